@@ -0,0 +1,74 @@
+// Account deletion. Deleted accounts are tombstoned right away and purged by a background task
+// once the retention period is over.
+
+use std::{sync::Arc, time::Duration};
+
+use mongodb::bson::{self, oid::ObjectId};
+
+use crate::{
+    audit::Audit,
+    config::AccountsConfig,
+    error::CustomAPIError,
+    middleware::auth,
+    models::{AuditAction, AuditTarget},
+    store::{AccountDeletion, Store},
+};
+
+/// Deletes the account of a user and records it in the audit log
+pub async fn delete_account(
+    db: &dyn Store,
+    audit: &Audit,
+    user_id: ObjectId,
+) -> Result<AccountDeletion, CustomAPIError> {
+    let deletion = db
+        .delete_user(user_id, db.get_current_time()?)
+        .await?
+        .ok_or_else(|| CustomAPIError::NotFound("User not found!".to_string()))?;
+
+    auth::forget_user_tokens(user_id);
+
+    audit
+        .record(
+            db,
+            AuditAction::AccountDeleted,
+            AuditTarget::new("user", user_id),
+            None,
+            Some(serde_json::json!(deletion)),
+        )
+        .await;
+
+    Ok(deletion)
+}
+
+/// Purges the accounts deleted more than `retention_days` before `now`
+pub async fn purge_deleted_accounts(
+    db: &dyn Store,
+    retention_days: u64,
+    now: bson::DateTime,
+) -> Result<u64, CustomAPIError> {
+    let retention_millis = i64::try_from(retention_days.saturating_mul(24 * 60 * 60 * 1000))
+        .unwrap_or(i64::MAX);
+    let cutoff = bson::DateTime::from_millis(now.timestamp_millis().saturating_sub(retention_millis));
+
+    db.purge_deleted_users(cutoff).await
+}
+
+/// Runs the purge every `purge_interval_secs` for as long as the server runs
+pub fn spawn_purge_task(db: Arc<dyn Store>, config: AccountsConfig) {
+    actix_web::rt::spawn(async move {
+        let mut interval =
+            actix_web::rt::time::interval(Duration::from_secs(config.purge_interval_secs));
+
+        loop {
+            interval.tick().await;
+
+            match purge_deleted_accounts(db.as_ref(), config.retention_days, bson::DateTime::now())
+                .await
+            {
+                Ok(0) => {}
+                Ok(purged) => log::info!("purged {} deleted accounts", purged),
+                Err(e) => log::error!("failed to purge deleted accounts: {}", e),
+            }
+        }
+    });
+}
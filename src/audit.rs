@@ -0,0 +1,99 @@
+// The audit trail of privileged actions. Handlers take an `Audit` argument and record what they
+// changed, entries are kept in the `audit_logs` collection.
+
+use std::future::{ready, Ready};
+
+use actix_web::{dev::Payload, web, Error, FromRequest, HttpMessage, HttpRequest};
+use mongodb::bson::{self, oid::ObjectId};
+use serde_json::Value;
+
+use crate::{
+    error::CustomAPIError,
+    middleware::{auth::Caller, client_ip, request_id::RequestId},
+    models::{AuditAction, AuditActor, AuditLog, AuditTarget},
+    store::Store,
+    AppState,
+};
+
+/// The actor, address and request id audit log entries are recorded with
+#[derive(Clone, Debug)]
+pub struct Audit {
+    actor: AuditActor,
+    ip: Option<String>,
+    request_id: Option<String>,
+}
+
+impl Audit {
+    pub fn new(caller: &Caller, ip: Option<String>, request_id: Option<String>) -> Self {
+        let actor = match caller {
+            Caller::Internal { key_name } => AuditActor {
+                userId: None,
+                key_name: Some(key_name.clone()),
+            },
+            Caller::User { user_id, .. } => AuditActor {
+                userId: Some(*user_id),
+                key_name: None,
+            },
+        };
+
+        Self {
+            actor,
+            ip,
+            request_id,
+        }
+    }
+
+    /// Appends an entry to the audit log.
+    ///
+    /// The action already happened, so a failed write is logged instead of failing the request.
+    pub async fn record(
+        &self,
+        db: &dyn Store,
+        action: AuditAction,
+        target: AuditTarget,
+        before: Option<Value>,
+        after: Option<Value>,
+    ) {
+        let entry = AuditLog {
+            _id: ObjectId::new(),
+            created_at: bson::DateTime::now(),
+            actor: self.actor.clone(),
+            action,
+            target,
+            before,
+            after,
+            ip: self.ip.clone(),
+            request_id: self.request_id.clone(),
+        };
+
+        if let Err(e) = db.create_audit_log(entry.clone()).await {
+            log::error!(target: "audit", "failed to store audit log entry {:?}: {}", entry, e);
+        }
+    }
+}
+
+impl FromRequest for Audit {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let caller = match req.extensions().get::<Caller>() {
+            Some(caller) => caller.clone(),
+            None => {
+                return ready(Err(CustomAPIError::Unauthorized(
+                    "Unauthorized Request!".to_string(),
+                )
+                .into()))
+            }
+        };
+
+        // Forwarded addresses are only taken from trusted proxies, clients could send anything.
+        let ip = req
+            .app_data::<web::Data<AppState>>()
+            .and_then(|data| client_ip(req, &data.config))
+            .map(|ip| ip.to_string());
+        let request_id = req.extensions().get::<RequestId>().map(|id| id.0.clone());
+
+        ready(Ok(Audit::new(&caller, ip, request_id)))
+    }
+}
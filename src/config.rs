@@ -0,0 +1,815 @@
+// Layered configuration for the API. Values are resolved in this order, later layers win:
+//
+// 1. Built-in defaults
+// 2. An optional TOML file (`--config`, `CONFIG_FILE`, `./config.<environment>.toml` or `./config.toml`)
+// 3. The `.env` file
+// 4. Process environment variables
+// 5. CLI flags
+//
+// The config is loaded once at startup and shared through the AppState.
+
+use std::{collections::HashMap, net::IpAddr, path::PathBuf, str::FromStr};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+
+use crate::{db::DB_NAME, models::UserRole};
+
+pub const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub mongodb_uri: String,
+    // The super-key is the direct bypass key for the API. Used for internal API's
+    pub super_key: String,
+    pub address: String,
+    pub port: u16,
+    /// The name of the deployment, e.g. development, staging or production
+    pub environment: String,
+    pub storage: StorageBackend,
+    pub database: DatabaseConfig,
+    pub inference: InferenceConfig,
+    pub jobs: JobsConfig,
+    pub rate_limit: RateLimitConfig,
+    /// Named keys for internal services, on top of the super key
+    pub internal_keys: Vec<InternalKey>,
+    pub jwt: JwtConfig,
+    pub accounts: AccountsConfig,
+    /// Addresses or CIDR ranges of reverse proxies. `X-Forwarded-For` is only read from these,
+    /// any other client could put an address of its choice in it.
+    pub trusted_proxies: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mongodb_uri: String::from("mongodb://localhost:27017"),
+            super_key: String::new(),
+            address: String::from("127.0.0.1"),
+            port: 8080,
+            environment: String::from("development"),
+            storage: StorageBackend::MongoDB,
+            database: DatabaseConfig::default(),
+            inference: InferenceConfig::default(),
+            jobs: JobsConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            internal_keys: Vec::new(),
+            jwt: JwtConfig::default(),
+            accounts: AccountsConfig::default(),
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+/// The backend the API stores its data in
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    #[value(name = "mongodb")]
+    MongoDB,
+    /// Keeps everything in memory, meant for tests and local development
+    Memory,
+}
+
+impl FromStr for StorageBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "mongodb" => Ok(StorageBackend::MongoDB),
+            "memory" => Ok(StorageBackend::Memory),
+            _ => Err(anyhow!("unknown storage backend {:?}", value)),
+        }
+    }
+}
+
+/// Where the API stores its data.
+///
+/// Environments can share a cluster by using a different database name or collection prefix.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub name: String,
+    /// Prepended to every collection name, e.g. `staging_`
+    pub collection_prefix: String,
+    /// Renames collections, keyed by their default name, e.g. `users = "accounts_users"`
+    pub collections: HashMap<String, String>,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            name: String::from(DB_NAME),
+            collection_prefix: String::new(),
+            collections: HashMap::new(),
+        }
+    }
+}
+
+/// Limits for the machine learning workers.
+///
+/// Inference is CPU bound, so it runs on its own threads instead of the HTTP workers.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InferenceConfig {
+    /// Number of threads running inference, defaults to the number of CPUs
+    pub workers: usize,
+    /// Requests waiting for a worker, anything beyond this is rejected with a 503
+    pub queue_size: usize,
+    /// How long a request waits for its result, including the time spent in the queue
+    pub timeout_secs: u64,
+    /// Answers translations with the mock engine, which still charges credits. Builds without
+    /// the `engine` feature refuse to start unless this is set.
+    pub mock: bool,
+}
+
+impl Default for InferenceConfig {
+    fn default() -> Self {
+        Self {
+            workers: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            queue_size: 64,
+            timeout_secs: 30,
+            mock: false,
+        }
+    }
+}
+
+/// Limits for jobs queued through `/api/v1/jobs`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    /// How long a job may wait and run before it fails
+    pub timeout_secs: u64,
+    /// How long finished jobs and their results are kept
+    pub ttl_secs: u64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 600,
+            ttl_secs: 3600,
+        }
+    }
+}
+
+/// How long deleted accounts are kept before they are purged
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountsConfig {
+    /// Days a deleted account is kept tombstoned, e.g. to undo a mistaken deletion
+    pub retention_days: u64,
+    /// How often the purge runs
+    pub purge_interval_secs: u64,
+}
+
+impl Default for AccountsConfig {
+    fn default() -> Self {
+        Self {
+            retention_days: 30,
+            purge_interval_secs: 3600,
+        }
+    }
+}
+
+/// A token bucket: `burst` requests at once, refilled at `per_minute`
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+/// Request rate limits, applied per client IP and per API token
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// Shared by every request from the same IP
+    pub ip: RateLimit,
+    /// Applied to every API token, unless one of its user's roles has its own limit
+    pub token: RateLimit,
+    /// Limits for tokens of users with the role, keyed by role, e.g. `ADMIN`. The most generous
+    /// limit of a user's roles is used.
+    pub roles: HashMap<UserRole, RateLimit>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ip: RateLimit {
+                burst: 120,
+                per_minute: 600,
+            },
+            token: RateLimit {
+                burst: 60,
+                per_minute: 300,
+            },
+            roles: HashMap::new(),
+        }
+    }
+}
+
+/// What an internal key or access token may be used for
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyScope {
+    /// Every route
+    All,
+    /// Translations and jobs
+    Translate,
+    /// Creating api tokens
+    Tokens,
+    /// Payments, credits and quotas
+    Billing,
+    /// System and user reports
+    Reports,
+}
+
+impl KeyScope {
+    /// Checks if the scopes include this one, `all` includes every scope
+    pub fn granted_by(self, scopes: &[KeyScope]) -> bool {
+        scopes.contains(&KeyScope::All) || scopes.contains(&self)
+    }
+}
+
+/// Signing keys for the short-lived access tokens api keys can be exchanged for
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtConfig {
+    /// New tokens are signed with the first key, the others only verify tokens signed before a
+    /// rotation. Access tokens are disabled without keys.
+    pub keys: Vec<JwtKey>,
+    /// How long an access token is valid
+    pub ttl_secs: u64,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        Self {
+            keys: Vec::new(),
+            ttl_secs: 900,
+        }
+    }
+}
+
+/// A HMAC key, tokens name the key they were signed with in their `kid` header
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtKey {
+    pub kid: String,
+    pub secret: String,
+}
+
+/// A key for an internal service, sent in the Authorization header like an api token
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InternalKey {
+    /// Identifies the service in the audit log
+    pub name: String,
+    pub key: String,
+    pub scopes: Vec<KeyScope>,
+    /// Addresses or CIDR ranges the key may be used from, e.g. `10.0.0.0/8`. Any address if empty.
+    #[serde(default)]
+    pub allowed_ips: Vec<String>,
+}
+
+impl InternalKey {
+    /// The legacy super key, allowed everywhere from anywhere
+    pub fn super_key(key: &str) -> Self {
+        Self {
+            name: String::from("super_key"),
+            key: key.to_string(),
+            scopes: vec![KeyScope::All],
+            allowed_ips: Vec::new(),
+        }
+    }
+
+    pub fn has_scope(&self, scope: KeyScope) -> bool {
+        scope.granted_by(&self.scopes)
+    }
+
+    /// Checks the address against the allowlist
+    pub fn allows_ip(&self, ip: IpAddr) -> bool {
+        self.allowed_ips.is_empty()
+            || self
+                .allowed_ips
+                .iter()
+                .any(|range| parse_ip_range(range).is_some_and(|range| range.contains(ip)))
+    }
+}
+
+impl Config {
+    /// Checks if the address belongs to one of the trusted proxies
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|range| parse_ip_range(range).is_some_and(|range| range.contains(ip)))
+    }
+}
+
+/// An address with a prefix length, a single address has the full length
+struct IpRange {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl IpRange {
+    fn contains(&self, ip: IpAddr) -> bool {
+        let (network, ip, bits) = match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                (u128::from(u32::from(network)), u128::from(u32::from(ip)), 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+            _ => return false,
+        };
+
+        let shift = bits - self.prefix;
+        shift >= bits || network >> shift == ip >> shift
+    }
+}
+
+/// Parses `10.0.0.1`, `10.0.0.0/8` or the IPv6 equivalents
+fn parse_ip_range(range: &str) -> Option<IpRange> {
+    let (network, prefix) = match range.split_once('/') {
+        Some((network, prefix)) => (network, Some(prefix)),
+        None => (range, None),
+    };
+
+    let network: IpAddr = network.trim().parse().ok()?;
+    let bits = if network.is_ipv4() { 32 } else { 128 };
+
+    let prefix = match prefix {
+        Some(prefix) => prefix.trim().parse().ok().filter(|prefix| *prefix <= bits)?,
+        None => bits,
+    };
+
+    Some(IpRange { network, prefix })
+}
+
+/// Command line flags, these override every other source
+#[derive(Debug, Default, Parser)]
+#[command(name = "neura-labs-api", about = "The Neura Labs API server")]
+pub struct Cli {
+    /// Path to a TOML config file
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// The MongoDB connection string
+    #[arg(long)]
+    pub mongodb_uri: Option<String>,
+
+    /// The address to bind the server to
+    #[arg(long)]
+    pub address: Option<String>,
+
+    /// The port to bind the server to
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// The deployment environment, selects `config.<env>.toml` if no config file is given
+    #[arg(long = "env")]
+    pub environment: Option<String>,
+
+    /// The storage backend
+    #[arg(long, value_enum)]
+    pub storage: Option<StorageBackend>,
+
+    /// The MongoDB database name
+    #[arg(long)]
+    pub database_name: Option<String>,
+
+    /// Prepended to every collection name
+    #[arg(long)]
+    pub collection_prefix: Option<String>,
+}
+
+/// Parses an environment variable, naming the variable in the error
+fn parse_env<T: FromStr>(env: &HashMap<String, String>, name: &str) -> anyhow::Result<Option<T>> {
+    match env.get(name) {
+        Some(value) => match value.trim().parse::<T>() {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => Err(anyhow!("{} has an invalid value: {:?}", name, value)),
+        },
+        None => Ok(None),
+    }
+}
+
+impl Config {
+    /// Loads the config from every source
+    pub fn load() -> anyhow::Result<Self> {
+        let cli = Cli::parse();
+
+        // Process environment variables win over the .env file.
+        let mut env: HashMap<String, String> = match env_file_reader::read_file(".env") {
+            Ok(values) => values.into_iter().collect(),
+            Err(_) => HashMap::new(),
+        };
+        env.extend(std::env::vars());
+
+        let path = cli
+            .config
+            .clone()
+            .or_else(|| env.get("CONFIG_FILE").map(PathBuf::from));
+
+        let environment = cli
+            .environment
+            .clone()
+            .or_else(|| env.get("APP_ENV").cloned());
+
+        let file = match &path {
+            Some(path) => Some(
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read config file {}", path.display()))?,
+            ),
+            None => environment
+                .and_then(|environment| {
+                    std::fs::read_to_string(format!("config.{}.toml", environment)).ok()
+                })
+                .or_else(|| std::fs::read_to_string(DEFAULT_CONFIG_FILE).ok()),
+        };
+
+        Self::from_sources(file.as_deref(), &env, &cli)
+    }
+
+    /// Merges the given sources on top of the defaults and validates the result
+    pub fn from_sources(
+        file: Option<&str>,
+        env: &HashMap<String, String>,
+        cli: &Cli,
+    ) -> anyhow::Result<Self> {
+        let mut config = match file {
+            Some(file) => toml::from_str::<Config>(file).context("Invalid config file")?,
+            None => Config::default(),
+        };
+
+        if let Some(mongodb_uri) = parse_env(env, "MONGODB_URI")? {
+            config.mongodb_uri = mongodb_uri;
+        }
+        if let Some(super_key) = parse_env(env, "SUPER_KEY")? {
+            config.super_key = super_key;
+        }
+        if let Some(address) = parse_env(env, "ADDRESS")? {
+            config.address = address;
+        }
+        if let Some(port) = parse_env(env, "PORT")? {
+            config.port = port;
+        }
+        if let Some(environment) = parse_env(env, "APP_ENV")? {
+            config.environment = environment;
+        }
+        if let Some(storage) = parse_env(env, "STORAGE")? {
+            config.storage = storage;
+        }
+        if let Some(name) = parse_env(env, "DATABASE_NAME")? {
+            config.database.name = name;
+        }
+        if let Some(prefix) = env.get("COLLECTION_PREFIX") {
+            config.database.collection_prefix = prefix.clone();
+        }
+        if let Some(workers) = parse_env(env, "INFERENCE_WORKERS")? {
+            config.inference.workers = workers;
+        }
+        if let Some(queue_size) = parse_env(env, "INFERENCE_QUEUE_SIZE")? {
+            config.inference.queue_size = queue_size;
+        }
+        if let Some(timeout_secs) = parse_env(env, "INFERENCE_TIMEOUT_SECS")? {
+            config.inference.timeout_secs = timeout_secs;
+        }
+        if let Some(mock) = parse_env(env, "INFERENCE_MOCK")? {
+            config.inference.mock = mock;
+        }
+        if let Some(timeout_secs) = parse_env(env, "JOB_TIMEOUT_SECS")? {
+            config.jobs.timeout_secs = timeout_secs;
+        }
+        if let Some(ttl_secs) = parse_env(env, "JOB_TTL_SECS")? {
+            config.jobs.ttl_secs = ttl_secs;
+        }
+        if let Some(enabled) = parse_env(env, "RATE_LIMIT_ENABLED")? {
+            config.rate_limit.enabled = enabled;
+        }
+        if let Some(ttl_secs) = parse_env(env, "JWT_TTL_SECS")? {
+            config.jwt.ttl_secs = ttl_secs;
+        }
+        if let Some(retention_days) = parse_env(env, "ACCOUNT_RETENTION_DAYS")? {
+            config.accounts.retention_days = retention_days;
+        }
+
+        if let Some(mongodb_uri) = &cli.mongodb_uri {
+            config.mongodb_uri = mongodb_uri.clone();
+        }
+        if let Some(address) = &cli.address {
+            config.address = address.clone();
+        }
+        if let Some(port) = cli.port {
+            config.port = port;
+        }
+        if let Some(environment) = &cli.environment {
+            config.environment = environment.clone();
+        }
+        if let Some(storage) = cli.storage {
+            config.storage = storage;
+        }
+        if let Some(name) = &cli.database_name {
+            config.database.name = name.clone();
+        }
+        if let Some(prefix) = &cli.collection_prefix {
+            config.database.collection_prefix = prefix.clone();
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.super_key.trim().is_empty() && self.internal_keys.is_empty() {
+            return Err(anyhow!(
+                "super_key is required unless internal_keys are configured, \
+                 set SUPER_KEY or super_key in the config file"
+            ));
+        }
+
+        for (index, key) in self.internal_keys.iter().enumerate() {
+            if key.name.trim().is_empty() {
+                return Err(anyhow!("internal_keys[{}] needs a name", index));
+            }
+
+            // Short keys could be guessed, the generated api tokens are 32 random bytes.
+            if key.key.len() < 32 {
+                return Err(anyhow!(
+                    "internal key {:?} has to be at least 32 characters long",
+                    key.name
+                ));
+            }
+
+            if self.internal_keys[..index]
+                .iter()
+                .any(|other| other.name == key.name || other.key == key.key)
+            {
+                return Err(anyhow!(
+                    "internal key {:?} is configured twice, names and keys have to be unique",
+                    key.name
+                ));
+            }
+
+            if let Some(range) = key
+                .allowed_ips
+                .iter()
+                .find(|range| parse_ip_range(range).is_none())
+            {
+                return Err(anyhow!(
+                    "internal key {:?} has an invalid allowed_ips entry {:?}",
+                    key.name,
+                    range
+                ));
+            }
+        }
+
+        if let Some(range) = self
+            .trusted_proxies
+            .iter()
+            .find(|range| parse_ip_range(range).is_none())
+        {
+            return Err(anyhow!("trusted_proxies has an invalid entry {:?}", range));
+        }
+
+        if !self.mongodb_uri.starts_with("mongodb://")
+            && !self.mongodb_uri.starts_with("mongodb+srv://")
+        {
+            return Err(anyhow!(
+                "mongodb_uri must start with mongodb:// or mongodb+srv://"
+            ));
+        }
+
+        if self.address.trim().is_empty() {
+            return Err(anyhow!("address can not be empty"));
+        }
+
+        // MongoDB does not allow these characters in database names.
+        if self.database.name.is_empty()
+            || self.database.name.contains(['/', '\\', '.', ' ', '"', '$'])
+        {
+            return Err(anyhow!(
+                "database.name {:?} is not a valid MongoDB database name",
+                self.database.name
+            ));
+        }
+
+        if self.database.collection_prefix.contains('$') {
+            return Err(anyhow!("database.collection_prefix can not contain '$'"));
+        }
+
+        if self.inference.workers == 0 {
+            return Err(anyhow!("inference.workers has to be at least 1"));
+        }
+
+        if self.inference.timeout_secs == 0 {
+            return Err(anyhow!("inference.timeout_secs has to be at least 1"));
+        }
+
+        if self.jobs.timeout_secs == 0 {
+            return Err(anyhow!("jobs.timeout_secs has to be at least 1"));
+        }
+
+        if self.accounts.purge_interval_secs == 0 {
+            return Err(anyhow!("accounts.purge_interval_secs has to be at least 1"));
+        }
+
+        if self.jwt.ttl_secs == 0 {
+            return Err(anyhow!("jwt.ttl_secs has to be at least 1"));
+        }
+
+        for (index, key) in self.jwt.keys.iter().enumerate() {
+            if key.kid.trim().is_empty() {
+                return Err(anyhow!("jwt.keys[{}] needs a kid", index));
+            }
+
+            // HS256 keys should be at least as long as the hash.
+            if key.secret.len() < 32 {
+                return Err(anyhow!(
+                    "jwt key {:?} has to be at least 32 characters long",
+                    key.kid
+                ));
+            }
+
+            if self.jwt.keys[..index].iter().any(|other| other.kid == key.kid) {
+                return Err(anyhow!("jwt key {:?} is configured twice", key.kid));
+            }
+        }
+
+        let limits = [
+            (String::from("ip"), &self.rate_limit.ip),
+            (String::from("token"), &self.rate_limit.token),
+        ]
+        .into_iter()
+        .chain(
+            self.rate_limit
+                .roles
+                .iter()
+                .map(|(role, limit)| (format!("roles.{:?}", role), limit)),
+        );
+
+        for (name, limit) in limits {
+            if limit.burst == 0 || limit.per_minute == 0 {
+                return Err(anyhow!(
+                    "rate_limit.{} needs a burst and per_minute of at least 1",
+                    name
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(values: &[(&str, &str)]) -> HashMap<String, String> {
+        values
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let file = "super_key = \"file\"\nport = 3000\naddress = \"0.0.0.0\"";
+        let cli = Cli {
+            port: Some(4000),
+            ..Cli::default()
+        };
+
+        let config = Config::from_sources(Some(file), &env(&[("PORT", "5000")]), &cli).unwrap();
+
+        assert_eq!(config.super_key, "file");
+        assert_eq!(config.address, "0.0.0.0");
+        assert_eq!(config.port, 4000);
+        assert_eq!(config.mongodb_uri, "mongodb://localhost:27017");
+    }
+
+    #[test]
+    fn rejects_malformed_port() {
+        let env = env(&[("SUPER_KEY", "key"), ("PORT", "eighty")]);
+
+        let err = Config::from_sources(None, &env, &Cli::default()).unwrap_err();
+
+        assert!(err.to_string().contains("PORT"));
+    }
+
+    #[test]
+    fn requires_super_key() {
+        let err = Config::from_sources(None, &HashMap::new(), &Cli::default()).unwrap_err();
+
+        assert!(err.to_string().contains("super_key"));
+    }
+
+    #[test]
+    fn reads_database_settings() {
+        let file = "super_key = \"key\"\n[database]\nname = \"staging\"\n[database.collections]\nusers = \"members\"";
+        let env = env(&[("COLLECTION_PREFIX", "ci_")]);
+
+        let config = Config::from_sources(Some(file), &env, &Cli::default()).unwrap();
+
+        assert_eq!(config.database.name, "staging");
+        assert_eq!(config.database.collection_prefix, "ci_");
+        assert_eq!(config.database.collections["users"], "members");
+    }
+
+    #[test]
+    fn rejects_invalid_database_name() {
+        let env = env(&[("SUPER_KEY", "key"), ("DATABASE_NAME", "neura.labs")]);
+
+        assert!(Config::from_sources(None, &env, &Cli::default()).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_file_keys() {
+        let file = "super_key = \"key\"\nprot = 3000";
+
+        assert!(Config::from_sources(Some(file), &HashMap::new(), &Cli::default()).is_err());
+    }
+
+    #[test]
+    fn selects_storage_backend() {
+        let file = "super_key = \"key\"\nstorage = \"memory\"";
+
+        let config = Config::from_sources(Some(file), &HashMap::new(), &Cli::default()).unwrap();
+        assert_eq!(config.storage, StorageBackend::Memory);
+
+        let env = env(&[("SUPER_KEY", "key"), ("STORAGE", "postgres")]);
+        assert!(Config::from_sources(None, &env, &Cli::default()).is_err());
+    }
+
+    #[test]
+    fn reads_inference_limits() {
+        let file = "super_key = \"key\"\n[inference]\nworkers = 2\nqueue_size = 8";
+        let vars = env(&[("INFERENCE_TIMEOUT_SECS", "5"), ("INFERENCE_MOCK", "true")]);
+
+        let config = Config::from_sources(Some(file), &vars, &Cli::default()).unwrap();
+
+        assert_eq!(config.inference.workers, 2);
+        assert_eq!(config.inference.queue_size, 8);
+        assert_eq!(config.inference.timeout_secs, 5);
+        assert!(config.inference.mock);
+
+        let env = env(&[("SUPER_KEY", "key"), ("INFERENCE_WORKERS", "0")]);
+        assert!(Config::from_sources(None, &env, &Cli::default()).is_err());
+    }
+
+    #[test]
+    fn reads_internal_keys() {
+        let file = r#"
+            [[internal_keys]]
+            name = "billing"
+            key = "0123456789abcdef0123456789abcdef"
+            scopes = ["billing"]
+            allowed_ips = ["10.0.0.0/8", "::1"]
+        "#;
+
+        let config = Config::from_sources(Some(file), &HashMap::new(), &Cli::default()).unwrap();
+        let key = &config.internal_keys[0];
+
+        assert!(key.has_scope(KeyScope::Billing));
+        assert!(!key.has_scope(KeyScope::Translate));
+        assert!(key.allows_ip("10.1.2.3".parse().unwrap()));
+        assert!(key.allows_ip("::1".parse().unwrap()));
+        assert!(!key.allows_ip("11.0.0.1".parse().unwrap()));
+        assert!(InternalKey::super_key("key").allows_ip("11.0.0.1".parse().unwrap()));
+
+        let short = "[[internal_keys]]\nname = \"a\"\nkey = \"short\"\nscopes = []";
+        let err = Config::from_sources(Some(short), &HashMap::new(), &Cli::default()).unwrap_err();
+        assert!(err.to_string().contains("32 characters"));
+
+        let invalid = file.replace("10.0.0.0/8", "10.0.0.0/33");
+        assert!(Config::from_sources(Some(&invalid), &HashMap::new(), &Cli::default()).is_err());
+    }
+
+    #[test]
+    fn reads_rate_limits_per_role() {
+        let file = "super_key = \"key\"\n[rate_limit.roles.ADMIN]\nburst = 500\nper_minute = 1000";
+
+        let config = Config::from_sources(Some(file), &HashMap::new(), &Cli::default()).unwrap();
+
+        assert!(config.rate_limit.enabled);
+        assert_eq!(
+            config.rate_limit.roles[&UserRole::ADMIN],
+            RateLimit {
+                burst: 500,
+                per_minute: 1000
+            }
+        );
+
+        let file = "super_key = \"key\"\n[rate_limit.roles.OWNER]\nburst = 1\nper_minute = 1";
+        assert!(Config::from_sources(Some(file), &HashMap::new(), &Cli::default()).is_err());
+
+        let file = "super_key = \"key\"\n[rate_limit.ip]\nburst = 0\nper_minute = 1";
+        let err = Config::from_sources(Some(file), &HashMap::new(), &Cli::default()).unwrap_err();
+        assert!(err.to_string().contains("rate_limit.ip"));
+    }
+}
@@ -1,20 +1,49 @@
 extern crate anyhow;
 
-use crate::models::{Credits, ReportStatus, Statistics, SystemReport, Tokens, Usage, UserReport};
-use chrono::{DateTime, Utc};
+use crate::{
+    error::CustomAPIError,
+    metrics::{self, CREDIT_DEDUCTIONS_TOTAL},
+    models::{
+        AuditLog, Credits, Payment, Quota, QuotaUsage, ReportEvent, ReportStatus, Session,
+        Statistics, SystemReport, Tokens, Usage, User, UserReport,
+    },
+    store::{
+        report_status_conflict, AccountDeletion, AuditFilter, Charge, ProfileChanges,
+        ReportChanges, ReportFilter, Store, ACCOUNT_DELETED_REASON,
+    },
+};
+use crate::config::DatabaseConfig;
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use std::collections::HashMap;
 use mongodb::{
-    bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document, self},
-    options::ReplaceOptions,
+    bson::{doc, oid::ObjectId, Document, self},
+    options::{
+        FindOneAndUpdateOptions, FindOptions, IndexOptions, ReturnDocument, UpdateOptions,
+    },
+    IndexModel,
     Database, {Client, Collection},
 };
 
+/// The default database name, used unless the config overrides it
 pub const DB_NAME: &str = "neuralabsai";
 
+/// The user id used for reports filed by the API itself
+pub const SYSTEM_USER_ID: ObjectId = ObjectId::from_bytes([0; 12]);
+
+/// The statistics of users without telemetry are added up under this id
+pub const ANONYMOUS_USER_ID: ObjectId =
+    ObjectId::from_bytes([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
 #[derive(Clone, Debug)]
 pub struct MongoDB {
     pub db_name: String,
     pub client: Client,
     pub db: Database,
+    /// Prepended to every collection name
+    pub collection_prefix: String,
+    /// Replaces the default name of a collection, keyed by the default name
+    pub collection_names: HashMap<String, String>,
 }
 
 pub enum CollectionNames {
@@ -27,296 +56,1037 @@ pub enum CollectionNames {
     Statistics,
     SystemReport,
     UserReport,
+    AuditLog,
     Custom(String),
 }
 
+/// The quota and usage of a user or token, the rest of the document is not needed to charge it
+#[derive(serde::Deserialize)]
+struct QuotaDocument {
+    #[serde(default)]
+    quota: Quota,
+    #[serde(default)]
+    quota_usage: QuotaUsage,
+    telemetry: Option<bool>,
+}
+
+/// Matches the document if one more charged request fits in its quota.
+///
+/// Counters of a past day or month count as zero, they start over with the next request.
+fn quota_room_filter(id: ObjectId, day: &str, month: &str) -> Document {
+    let room = |limit: &str, counter: &str, period: &str, current: &str| {
+        doc! {"$or": [
+            {"$eq": [{"$ifNull": [limit, null]}, null]},
+            {"$ne": [period, current]},
+            {"$lt": [{"$ifNull": [counter, 0]}, limit]},
+        ]}
+    };
+
+    doc! {
+        "_id": id,
+        "$expr": {"$and": [
+            room("$quota.requests_per_day", "$quota_usage.requests", "$quota_usage.day", day),
+            room("$quota.credits_per_month", "$quota_usage.credits", "$quota_usage.month", month),
+        ]},
+    }
+}
+
+/// Adds `step` to the quota usage, starting the counters over if their day or month has passed.
+///
+/// A negative step undoes a charge, counters that already started over are left alone.
+fn quota_usage_update(day: &str, month: &str, step: i32) -> Vec<Document> {
+    let count = |counter: &str, period: &str, current: &str| {
+        let counter = doc! {"$ifNull": [counter, 0]};
+        let started_over = if step > 0 { bson::Bson::from(step) } else { counter.clone().into() };
+
+        doc! {"$cond": [{"$eq": [period, current]}, {"$add": [counter, step]}, started_over]}
+    };
+    let period = |period: &str, current: &str| {
+        if step > 0 {
+            bson::Bson::from(current)
+        } else {
+            bson::Bson::from(period)
+        }
+    };
+
+    vec![doc! {"$set": {
+        "quota_usage.requests": count("$quota_usage.requests", "$quota_usage.day", day),
+        "quota_usage.credits": count("$quota_usage.credits", "$quota_usage.month", month),
+        "quota_usage.day": period("$quota_usage.day", day),
+        "quota_usage.month": period("$quota_usage.month", month),
+    }}]
+}
+
+/// Builds the `$inc` of the usage counters, counters the usage does not have are left alone
+fn usage_increments(usage: &Usage) -> Result<Document, CustomAPIError> {
+    let mut increments = Document::new();
+
+    for (counter, count) in bson::to_document(usage)? {
+        if let bson::Bson::Int32(count) = count {
+            increments.insert(format!("usage.{}", counter), count);
+        }
+    }
+
+    Ok(increments)
+}
+
+/// Builds the query for an audit log listing
+fn audit_filter(filter: &AuditFilter) -> Result<Document, CustomAPIError> {
+    let mut query = Document::new();
+
+    if let Some(actor_id) = filter.actor_id {
+        query.insert("actor.userId", actor_id);
+    }
+    if let Some(key_name) = &filter.key_name {
+        query.insert("actor.key_name", key_name);
+    }
+    if let Some(action) = filter.action {
+        query.insert("action", bson::to_bson(&action)?);
+    }
+    if let Some(kind) = &filter.target_kind {
+        query.insert("target.kind", kind);
+    }
+    if let Some(id) = &filter.target_id {
+        query.insert("target.id", id);
+    }
+
+    let mut created_at = Document::new();
+    if let Some(from) = filter.from {
+        created_at.insert("$gte", from);
+    }
+    if let Some(to) = filter.to {
+        created_at.insert("$lt", to);
+    }
+    if !created_at.is_empty() {
+        query.insert("created_at", created_at);
+    }
+
+    Ok(query)
+}
+
+/// Builds the query for a report listing, `owner_field` names the user the reports belong to
+fn report_filter(filter: ReportFilter, owner_field: &str) -> Result<Document, CustomAPIError> {
+    let mut query = Document::new();
+
+    if let Some(status) = filter.status {
+        query.insert("status", bson::to_bson(&status)?);
+    }
+
+    if let Some(owner_id) = filter.owner_id {
+        query.insert(owner_field, owner_id);
+    }
+
+    Ok(query)
+}
+
+/// Builds the update document for a report change.
+///
+/// Empty `$set` documents are rejected by older MongoDB versions so they are left out.
+fn report_update(changes: ReportChanges, event: Option<ReportEvent>) -> Result<Document, CustomAPIError> {
+    let mut set = Document::new();
+
+    if let Some(title) = changes.title {
+        set.insert("title", title);
+    }
+    if let Some(description) = changes.description {
+        set.insert("description", description);
+    }
+    if let Some(status) = changes.status {
+        set.insert("status", bson::to_bson(&status)?);
+    }
+    if let Some(assigned_to_id) = changes.assigned_to_id {
+        set.insert("assignedToId", assigned_to_id);
+    }
+
+    let mut update = Document::new();
+
+    if !set.is_empty() {
+        update.insert("$set", set);
+    }
+
+    if let Some(event) = event {
+        update.insert("$push", doc! {"timeline": bson::to_bson(&event)?});
+    }
+
+    Ok(update)
+}
+
+/// Matches the report, and only while it still has the status the changes were checked against
+fn report_update_filter(id: ObjectId, changes: &ReportChanges) -> Result<Document, CustomAPIError> {
+    let mut filter = doc! {"_id": id};
+
+    if let Some(status) = changes.from_status {
+        filter.insert("status", bson::to_bson(&status)?);
+    }
+
+    Ok(filter)
+}
+
+/// Builds the update document for a profile change, cleared fields are set to null
+fn profile_update(changes: ProfileChanges) -> Document {
+    let mut set = Document::new();
+
+    for (field, value) in [
+        ("name", changes.name),
+        ("username", changes.username),
+        ("bio", changes.bio),
+    ] {
+        if let Some(value) = value {
+            set.insert(field, value);
+        }
+    }
+    if let Some(image) = changes.image {
+        set.insert("image", image);
+    }
+    if let Some(telemetry) = changes.telemetry {
+        set.insert("telemetry", telemetry);
+    }
+
+    doc! {"$set": set}
+}
+
 impl MongoDB {
     /// Initializes a new MongoDB instance
-    pub async fn new(auth_url: &String) -> anyhow::Result<Self> {
+    pub async fn new(auth_url: &String, database: &DatabaseConfig) -> anyhow::Result<Self> {
         let client = match Client::with_uri_str(auth_url).await {
             Ok(client) => client,
             Err(e) => return Err(anyhow::Error::new(e)),
         };
 
-        let db = client.database(DB_NAME);
+        let db = client.database(&database.name);
 
         println!("MongoDB Initialized");
 
         Ok(Self {
-            db_name: DB_NAME.to_string(),
+            db_name: database.name.clone(),
             client,
             db,
+            collection_prefix: database.collection_prefix.clone(),
+            collection_names: database.collections.clone(),
         })
     }
 
-    /// Checks if the MongoDB instance is alive
+    /// Counts one charged request against the quota of a user or token.
     ///
-    /// Returns 1 if the instance is alive and 0 if it is not
-    pub async fn ping(&self) -> anyhow::Result<Document> {
-        match self.db.run_command(doc! {"ping": 1}, None).await {
-            Ok(result) => Ok(result),
-            Err(e) => Err(anyhow::Error::new(e)),
+    /// The check and the increment are a single update, so concurrent requests can not push the
+    /// usage past the quota. Returns the document, none if there is none with the id.
+    async fn charge_quota(
+        &self,
+        collection_name: CollectionNames,
+        scope: &'static str,
+        id: ObjectId,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Option<QuotaDocument>, CustomAPIError> {
+        let collection = self.get_collection::<QuotaDocument>(collection_name);
+        let day = now.format("%Y-%m-%d").to_string();
+        let month = now.format("%Y-%m").to_string();
+
+        let charged = collection
+            .find_one_and_update(
+                quota_room_filter(id, &day, &month),
+                quota_usage_update(&day, &month, 1),
+                None,
+            )
+            .await?;
+
+        match charged {
+            Some(document) => Ok(Some(document)),
+            // Nothing matched, either there is no such document or its quota is used up.
+            None => match collection.find_one(doc! {"_id": id}, None).await? {
+                Some(document) => {
+                    let quota = document
+                        .quota_usage
+                        .current(now)
+                        .exceeded(&document.quota)
+                        .unwrap_or("requests_per_day");
+
+                    Err(CustomAPIError::QuotaExceeded { scope, quota })
+                }
+                None => Ok(None),
+            },
         }
     }
 
+    /// Undoes `charge_quota`, used when a later step of the charge is rejected
+    async fn refund_quota(
+        &self,
+        collection_name: CollectionNames,
+        id: ObjectId,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), CustomAPIError> {
+        let collection = self.get_collection::<Document>(collection_name);
+        let day = now.format("%Y-%m-%d").to_string();
+        let month = now.format("%Y-%m").to_string();
+
+        collection
+            .update_one(doc! {"_id": id}, quota_usage_update(&day, &month, -1), None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Creates the indexes the queries rely on
+    pub async fn create_indexes(&self) -> Result<(), CustomAPIError> {
+        let collection = self.get_collection::<SystemReport>(CollectionNames::SystemReport);
+
+        // Only one report per server error is open at a time.
+        let options = IndexOptions::builder()
+            .name(String::from("open_fingerprint"))
+            .unique(true)
+            .partial_filter_expression(doc! {
+                "fingerprint": {"$exists": true},
+                "status": bson::to_bson(&ReportStatus::InProgress)?,
+            })
+            .build();
+        let index = IndexModel::builder()
+            .keys(doc! {"fingerprint": 1})
+            .options(options)
+            .build();
+
+        collection.create_index(index, None).await?;
+
+        Ok(())
+    }
+
     /// Returns a MongoDB collection
     ///
     /// This is a helper function that also is typed
     pub fn get_collection<T>(&self, collection_name: CollectionNames) -> Collection<T> {
-        match collection_name {
-            CollectionNames::User => self.db.collection("users"),
-            CollectionNames::Account => self.db.collection("accounts"),
-            CollectionNames::Session => self.db.collection("sessions"),
-            CollectionNames::Tokens => self.db.collection("tokens"),
-            CollectionNames::SystemReport => self.db.collection("system_reports"),
-            CollectionNames::UserReport => self.db.collection("user_reports"),
-            CollectionNames::Statistics => self.db.collection("statistics"),
-            CollectionNames::Payment => self.db.collection("payments"),
-            CollectionNames::Credits => self.db.collection("credits"),
-            CollectionNames::Custom(name) => self.db.collection(&name),
+        self.db.collection(&self.collection_name(collection_name))
+    }
+
+    /// Resolves the configured name of a collection, including the prefix
+    pub fn collection_name(&self, collection_name: CollectionNames) -> String {
+        let name = match collection_name {
+            CollectionNames::User => "users".to_string(),
+            CollectionNames::Account => "accounts".to_string(),
+            CollectionNames::Session => "sessions".to_string(),
+            CollectionNames::Tokens => "tokens".to_string(),
+            CollectionNames::SystemReport => "system_reports".to_string(),
+            CollectionNames::UserReport => "user_reports".to_string(),
+            CollectionNames::Statistics => "statistics".to_string(),
+            CollectionNames::Payment => "payments".to_string(),
+            CollectionNames::Credits => "credits".to_string(),
+            CollectionNames::AuditLog => "audit_logs".to_string(),
+            CollectionNames::Custom(name) => name,
+        };
+
+        let name = self.collection_names.get(&name).cloned().unwrap_or(name);
+
+        format!("{}{}", self.collection_prefix, name)
+    }
+
+    // todo - on api startup, cache all current tokens in memory for faster access
+    // todo - any new tokens will be added to the cache. This is to avoid querying the database for every request
+    /// Checks if a api token exists in the database
+    ///
+    /// This is used to check if a token is valid. If it is, then the user is authenticated on the API.
+    pub async fn has_api_key(&self, token: String) -> Result<bool, CustomAPIError> {
+        let _timer = metrics::time_db_operation("has_api_key");
+
+        let collection = self.get_collection::<Tokens>(CollectionNames::Tokens);
+
+        let filter = doc! {"token": token};
+
+        match collection.find_one(filter, None).await {
+            Ok(result) => match result {
+                Some(_) => Ok(true),
+                None => Ok(false),
+            },
+            Err(e) => Err(e.into()),
         }
     }
 
-    pub async fn create_system_report(
-        &self,
-        title: String,
-        user_id: ObjectId,
-        description: String,
-    ) -> anyhow::Result<()> {
-        let collection = self.get_collection::<SystemReport>(CollectionNames::SystemReport);
+    /// Returns the API key for a given token
+    pub async fn get_api_key(&self, token: &str) -> Result<Option<String>, CustomAPIError> {
+        let _timer = metrics::time_db_operation("get_api_key");
 
-        let report = SystemReport {
-            _id: ObjectId::new(),
-            title,
-            description,
-            status: ReportStatus::InProgress,
-            created_at: self.get_current_time().unwrap(),
-            userId: user_id,
-        };
+        let collection = self.get_collection::<Tokens>(CollectionNames::Tokens);
 
-        match collection.insert_one(report, None).await {
+        let filter = doc! {"token": token};
+
+        match collection.find_one(filter, None).await {
+            Ok(result) => match result {
+                Some(data) => Ok(Some(data.token)),
+                None => Ok(None),
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl Store for MongoDB {
+    /// Checks if the MongoDB instance is alive
+    async fn ping(&self) -> Result<(), CustomAPIError> {
+        let _timer = metrics::time_db_operation("ping");
+
+        match self.db.run_command(doc! {"ping": 1}, None).await {
             Ok(_) => Ok(()),
-            Err(e) => Err(anyhow::Error::new(e)),
+            Err(e) => Err(e.into()),
         }
     }
 
-    pub async fn create_user_report(
-        &self,
-        title: String,
-        description: String,
-        assigned_to_id: ObjectId,
-    ) -> anyhow::Result<()> {
-        let collection = self.get_collection::<UserReport>(CollectionNames::UserReport);
+    /// Returns the stored token document for a given api token
+    async fn get_api_token(&self, token: &str) -> Result<Option<Tokens>, CustomAPIError> {
+        let _timer = metrics::time_db_operation("get_api_token");
 
-        let report = UserReport {
-            _id: ObjectId::new(),
-            title,
-            description,
-            status: ReportStatus::InProgress,
-            created_at: self.get_current_time().unwrap(),
-            assignedToId: assigned_to_id,
-        };
+        let collection = self.get_collection::<Tokens>(CollectionNames::Tokens);
 
-        match collection.insert_one(report, None).await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(anyhow::Error::new(e)),
+        let filter = doc! {"token": token};
+
+        match collection.find_one(filter, None).await {
+            Ok(result) => Ok(result),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_api_token_by_id(&self, id: ObjectId) -> Result<Option<Tokens>, CustomAPIError> {
+        let _timer = metrics::time_db_operation("get_api_token_by_id");
+
+        let collection = self.get_collection::<Tokens>(CollectionNames::Tokens);
+
+        Ok(collection.find_one(doc! {"_id": id}, None).await?)
+    }
+
+    async fn create_api_token(&self, token: Tokens) -> Result<(), CustomAPIError> {
+        let _timer = metrics::time_db_operation("create_api_token");
+
+        let collection = self.get_collection::<Tokens>(CollectionNames::Tokens);
+
+        collection.insert_one(token, None).await?;
+
+        Ok(())
+    }
+
+    /// Finds a dashboard session by its token
+    async fn get_session(&self, session_token: &str) -> Result<Option<Session>, CustomAPIError> {
+        let _timer = metrics::time_db_operation("get_session");
+
+        let collection = self.get_collection::<Session>(CollectionNames::Session);
+
+        match collection.find_one(doc! {"sessionToken": session_token}, None).await {
+            Ok(result) => Ok(result),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_user(&self, user_id: ObjectId) -> Result<Option<User>, CustomAPIError> {
+        let _timer = metrics::time_db_operation("get_user");
+
+        let collection = self.get_collection::<User>(CollectionNames::User);
+
+        match collection.find_one(doc! {"_id": user_id}, None).await {
+            Ok(result) => Ok(result),
+            Err(e) => Err(e.into()),
         }
     }
 
+    async fn get_credits(&self, user_id: ObjectId) -> Result<Option<Credits>, CustomAPIError> {
+        let _timer = metrics::time_db_operation("get_credits");
+
+        let collection = self.get_collection::<Credits>(CollectionNames::Credits);
+
+        Ok(collection.find_one(doc! {"userId": user_id}, None).await?)
+    }
+
+    async fn add_credits(&self, user_id: ObjectId, amount: i32) -> Result<(), CustomAPIError> {
+        let _timer = metrics::time_db_operation("add_credits");
+
+        let collection = self.get_collection::<Credits>(CollectionNames::Credits);
+
+        // One update, so deductions made at the same time are not overwritten.
+        collection
+            .update_one(
+                doc! {"userId": user_id},
+                doc! {
+                    "$inc": {"current_amount": amount},
+                    "$setOnInsert": {"used_amount": 0},
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
     /// Updates user credit information.
     ///
     /// This function is called when a user makes a request to the API and the request is successful.
-    ///
-    /// todo - Add the create_statistics_report function to this function. First we need to validate the current data and day.
-    pub async fn process_credit_usage(&self, user_id: ObjectId) -> anyhow::Result<bool> {
-        let collection = self.get_collection::<Credits>(CollectionNames::Credits);
+    /// The quotas and credits are each checked and updated in one conditional update, parts that
+    /// were already counted are undone if a later one is rejected.
+    async fn process_credit_usage(&self, charge: Charge) -> Result<bool, CustomAPIError> {
+        let _timer = metrics::time_db_operation("process_credit_usage");
+
+        let user_id = charge.user_id;
+        let now = chrono::Utc::now();
 
-        // Find an existing credits for the user.
-        let filter = doc! {"userId": user_id};
+        let user = self
+            .charge_quota(CollectionNames::User, "user", user_id, now)
+            .await?;
+        let telemetry = user.as_ref().is_none_or(|user| user.telemetry.unwrap_or(true));
 
-        let mut credits = match collection.find_one(filter.clone(), None).await? {
-            Some(credits) => credits,
-            None => return Ok(false), // Return false if credits document not found.
+        let token = match charge.token_id {
+            Some(token_id) => {
+                match self
+                    .charge_quota(CollectionNames::Tokens, "token", token_id, now)
+                    .await
+                {
+                    Ok(token) => token.map(|_| token_id),
+                    Err(e) => {
+                        if user.is_some() {
+                            self.refund_quota(CollectionNames::User, user_id, now).await?;
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            None => None,
         };
 
-        // Check if user has enough credits.
-        if credits.current_amount.unwrap_or(0) <= 0 {
+        // Subtract one from current_amount and add one to used_amount, unless nothing is left.
+        let collection = self.get_collection::<Credits>(CollectionNames::Credits);
+        let update = vec![doc! {"$set": {
+            "current_amount": {"$subtract": ["$current_amount", 1]},
+            "used_amount": {"$add": [{"$ifNull": ["$used_amount", 0]}, 1]},
+        }}];
+        let result = collection
+            .update_one(doc! {"userId": user_id, "current_amount": {"$gt": 0}}, update, None)
+            .await?;
+
+        if result.matched_count == 0 {
+            if let Some(token_id) = token {
+                self.refund_quota(CollectionNames::Tokens, token_id, now).await?;
+            }
+            if user.is_some() {
+                self.refund_quota(CollectionNames::User, user_id, now).await?;
+            }
             return Ok(false);
         }
 
-        // Subtract one from current_amount and add one to used_amount.
-        credits.current_amount = credits.current_amount.map(|amount| amount - 1);
-        credits.used_amount = credits.used_amount.map(|amount| amount + 1);
+        CREDIT_DEDUCTIONS_TOTAL.inc();
 
-        // Save the credits to the database.
-        collection
-            .replace_one(filter, credits.clone(), None)
+        // Users without telemetry keep only their credits and quota usage, which billing needs.
+        // Their calls are counted in the anonymous report.
+        if telemetry {
+            self.create_statistics_report(user_id, Some(Usage::call(now, true)))
+                .await?;
+        } else {
+            self.create_statistics_report(ANONYMOUS_USER_ID, Some(Usage::call(now, false)))
+                .await?;
+        }
+
+        Ok(true)
+    }
+
+    async fn get_user_by_username(&self, username: &str) -> Result<Option<User>, CustomAPIError> {
+        let _timer = metrics::time_db_operation("get_user_by_username");
+
+        let collection = self.get_collection::<User>(CollectionNames::User);
+
+        match collection.find_one(doc! {"username": username}, None).await {
+            Ok(result) => Ok(result),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn update_user_profile(
+        &self,
+        user_id: ObjectId,
+        changes: ProfileChanges,
+    ) -> Result<Option<User>, CustomAPIError> {
+        let _timer = metrics::time_db_operation("update_user_profile");
+
+        let collection = self.get_collection::<User>(CollectionNames::User);
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        let filter = doc! {"_id": user_id, "tomestoned": false};
+
+        match collection
+            .find_one_and_update(filter, profile_update(changes), options)
+            .await
+        {
+            Ok(result) => Ok(result),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn set_user_quota(&self, user_id: ObjectId, quota: Quota) -> Result<bool, CustomAPIError> {
+        let _timer = metrics::time_db_operation("set_user_quota");
+
+        let collection = self.get_collection::<User>(CollectionNames::User);
+
+        let update = doc! {"$set": {"quota": bson::to_bson(&quota)?}};
+        let result = collection.update_one(doc! {"_id": user_id}, update, None).await?;
+
+        Ok(result.matched_count > 0)
+    }
+
+    async fn set_token_quota(&self, token_id: ObjectId, quota: Quota) -> Result<bool, CustomAPIError> {
+        let _timer = metrics::time_db_operation("set_token_quota");
+
+        let collection = self.get_collection::<Tokens>(CollectionNames::Tokens);
+
+        let update = doc! {"$set": {"quota": bson::to_bson(&quota)?}};
+        let result = collection.update_one(doc! {"_id": token_id}, update, None).await?;
+
+        Ok(result.matched_count > 0)
+    }
+
+    async fn create_audit_log(&self, entry: AuditLog) -> Result<(), CustomAPIError> {
+        let _timer = metrics::time_db_operation("create_audit_log");
+
+        let collection = self.get_collection::<AuditLog>(CollectionNames::AuditLog);
+
+        collection.insert_one(entry, None).await?;
+
+        Ok(())
+    }
+
+    async fn get_audit_logs(&self, filter: AuditFilter) -> Result<Vec<AuditLog>, CustomAPIError> {
+        let _timer = metrics::time_db_operation("get_audit_logs");
+
+        let collection = self.get_collection::<AuditLog>(CollectionNames::AuditLog);
+        let options = FindOptions::builder()
+            .sort(doc! {"created_at": -1})
+            .limit(filter.limit)
+            .build();
+
+        let cursor = collection.find(audit_filter(&filter)?, options).await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn delete_user(
+        &self,
+        user_id: ObjectId,
+        deleted_at: bson::DateTime,
+    ) -> Result<Option<AccountDeletion>, CustomAPIError> {
+        let _timer = metrics::time_db_operation("delete_user");
+
+        // Already deleted users go through the steps again, so a deletion that failed halfway
+        // is finished by retrying it. The time of the first deletion is kept.
+        let user = self
+            .get_collection::<User>(CollectionNames::User)
+            .find_one_and_update(
+                doc! {"_id": user_id},
+                vec![doc! {"$set": {
+                    "tomestoned": true,
+                    "deleted_at": {"$ifNull": ["$deleted_at", deleted_at]},
+                }}],
+                None,
+            )
             .await?;
+        if user.is_none() {
+            return Ok(None);
+        }
 
-        let u = Usage {
-            api_calls: Some(1),
-            api_calls_monday: None,
-            api_calls_tuesday: None,
-            api_calls_wednesday: None,
-            api_calls_thursday: None,
-            api_calls_friday: None,
-            api_calls_saturday: None,
-            api_calls_sunday: None,
-            api_calls_success: None,
-            api_calls_fail: None,
-        };
+        let tokens = self
+            .get_collection::<Tokens>(CollectionNames::Tokens)
+            .update_many(
+                doc! {"userId": user_id, "tomestoned": false},
+                doc! {"$set": {"tomestoned": true, "updated_at": deleted_at}},
+                None,
+            )
+            .await?;
 
-        // Create a new statistics report for the user.
-        self.create_statistics_report(user_id, Some(u)).await?;
+        let payments = self
+            .get_collection::<Payment>(CollectionNames::Payment)
+            .update_many(
+                doc! {"userId": user_id, "active": true},
+                doc! {"$set": {
+                    "active": false,
+                    "subscription_cancelled": true,
+                    "subscription_cancelled_date": deleted_at,
+                    "subscription_cancelled_reason": ACCOUNT_DELETED_REASON,
+                }},
+                None,
+            )
+            .await?;
 
-        Ok(true)
+        // The usage stays in the global numbers, but can no longer be tied to the user.
+        let statistics = self
+            .get_collection::<Statistics>(CollectionNames::Statistics)
+            .update_many(
+                doc! {"userId": user_id},
+                doc! {"$set": {"userId": ObjectId::new()}},
+                None,
+            )
+            .await?;
+
+        self.get_collection::<Document>(CollectionNames::Session)
+            .delete_many(doc! {"userId": user_id}, None)
+            .await?;
+
+        Ok(Some(AccountDeletion {
+            tokens_revoked: tokens.modified_count,
+            payments_cancelled: payments.modified_count,
+            statistics_anonymised: statistics.modified_count,
+        }))
+    }
+
+    async fn purge_deleted_users(&self, deleted_before: bson::DateTime) -> Result<u64, CustomAPIError> {
+        let _timer = metrics::time_db_operation("purge_deleted_users");
+
+        let users = self.get_collection::<User>(CollectionNames::User);
+        let filter = doc! {"tomestoned": true, "deleted_at": {"$lt": deleted_before}};
+
+        let ids: Vec<ObjectId> = users
+            .find(filter, None)
+            .await?
+            .try_collect::<Vec<User>>()
+            .await?
+            .into_iter()
+            .map(|user| user._id)
+            .collect();
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        let owned = doc! {"userId": {"$in": &ids}};
+        for collection in [
+            CollectionNames::Tokens,
+            CollectionNames::Credits,
+            CollectionNames::Payment,
+            CollectionNames::Session,
+            CollectionNames::Account,
+        ] {
+            self.get_collection::<Document>(collection)
+                .delete_many(owned.clone(), None)
+                .await?;
+        }
+
+        let result = users.delete_many(doc! {"_id": {"$in": &ids}}, None).await?;
+
+        Ok(result.deleted_count)
+    }
+
+    async fn create_payment(&self, payment: Payment) -> Result<(), CustomAPIError> {
+        let _timer = metrics::time_db_operation("create_payment");
+
+        let collection = self.get_collection::<Payment>(CollectionNames::Payment);
+
+        collection.insert_one(payment, None).await?;
+
+        Ok(())
     }
 
     // Creates a new stats report for the api.
-    pub async fn create_statistics_report(
+    async fn create_statistics_report(
         &self,
         user_id: ObjectId,
         usage: Option<Usage>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), CustomAPIError> {
+        let _timer = metrics::time_db_operation("create_statistics_report");
+
         let collection = self.get_collection::<Statistics>(CollectionNames::Statistics);
 
         // Get the current date and time.
-        let now = self.get_current_time().unwrap();
-
-        // Find an existing statistics report for the user.
-        let filter = doc! {"userId": user_id};
-        let existing_report = collection.find_one(filter.clone(), None).await?;
-
-        // Create a new statistics report or update the existing one.
-        let report = match existing_report {
-            Some(mut report) => {
-                // Update the usage data if provided.
-                if let Some(new_usage) = usage {
-                    // Update the usage data if provided.
-                    let mut old_usage = report.usage.take().unwrap();
-
-                    // Increment the usage data if it exists, or set it to the new value if it doesn't.
-                    old_usage.api_calls = old_usage
-                        .api_calls
-                        .map(|x| x + new_usage.api_calls.unwrap_or_default())
-                        .or(new_usage.api_calls);
-                    old_usage.api_calls_monday = old_usage
-                        .api_calls_monday
-                        .map(|x| x + new_usage.api_calls_monday.unwrap_or_default())
-                        .or(new_usage.api_calls_monday);
-                    old_usage.api_calls_tuesday = old_usage
-                        .api_calls_tuesday
-                        .map(|x| x + new_usage.api_calls_tuesday.unwrap_or_default())
-                        .or(new_usage.api_calls_tuesday);
-                    old_usage.api_calls_wednesday = old_usage
-                        .api_calls_wednesday
-                        .map(|x| x + new_usage.api_calls_wednesday.unwrap_or_default())
-                        .or(new_usage.api_calls_wednesday);
-                    old_usage.api_calls_thursday = old_usage
-                        .api_calls_thursday
-                        .map(|x| x + new_usage.api_calls_thursday.unwrap_or_default())
-                        .or(new_usage.api_calls_thursday);
-                    old_usage.api_calls_friday = old_usage
-                        .api_calls_friday
-                        .map(|x| x + new_usage.api_calls_friday.unwrap_or_default())
-                        .or(new_usage.api_calls_friday);
-                    old_usage.api_calls_saturday = old_usage
-                        .api_calls_saturday
-                        .map(|x| x + new_usage.api_calls_saturday.unwrap_or_default())
-                        .or(new_usage.api_calls_saturday);
-                    old_usage.api_calls_sunday = old_usage
-                        .api_calls_sunday
-                        .map(|x| x + new_usage.api_calls_sunday.unwrap_or_default())
-                        .or(new_usage.api_calls_sunday);
-                    old_usage.api_calls_success = old_usage
-                        .api_calls_success
-                        .map(|x| x + new_usage.api_calls_success.unwrap_or_default())
-                        .or(new_usage.api_calls_success);
-                    old_usage.api_calls_fail = old_usage
-                        .api_calls_fail
-                        .map(|x| x + new_usage.api_calls_fail.unwrap_or_default())
-                        .or(new_usage.api_calls_fail);
-
-                    // Update the usage field of the report struct.
-                    report.usage = Some(old_usage);
-                }
-
-                // Update the updated_at field.
-                report.updated_at = Some(now);
+        let now = self.get_current_time()?;
 
-                report
-            }
-            None => {
-                // Create a new statistics report.
-                Statistics {
-                    _id: ObjectId::new(),
-                    created_at: now,
-                    updated_at: None,
-                    usage,
-                    userId: user_id,
-                }
-            }
+        // Every opted-out user shares the anonymous report, so the counters are incremented in
+        // place instead of reading and replacing the report.
+        let mut update = doc! {
+            "$set": {"updated_at": now},
+            "$setOnInsert": {"_id": ObjectId::new(), "created_at": now},
         };
+        if let Some(usage) = usage {
+            let increments = usage_increments(&usage)?;
+            if !increments.is_empty() {
+                update.insert("$inc", increments);
+            }
+        }
 
-        // Save the statistics report to the database.
         collection
-            .replace_one(
-                filter,
-                report.clone(),
-                ReplaceOptions::builder().upsert(true).build(),
+            .update_one(
+                doc! {"userId": user_id},
+                update,
+                UpdateOptions::builder().upsert(true).build(),
             )
             .await?;
 
         Ok(())
     }
 
-    // todo - on api startup, cache all current tokens in memory for faster access
-    // todo - any new tokens will be added to the cache. This is to avoid querying the database for every request
-    /// Checks if a api token exists in the database
+    /// Counts the calls of every user, including the anonymous report
+    async fn get_total_api_calls(&self) -> Result<i64, CustomAPIError> {
+        let _timer = metrics::time_db_operation("get_total_api_calls");
+
+        let collection = self.get_collection::<Statistics>(CollectionNames::Statistics);
+        let pipeline = [doc! {
+            "$group": {"_id": null, "api_calls": {"$sum": "$usage.api_calls"}}
+        }];
+
+        let totals: Vec<Document> = collection
+            .aggregate(pipeline, None)
+            .await?
+            .try_collect()
+            .await?;
+
+        // $sum yields an int32 or an int64 depending on the size of the total.
+        Ok(match totals.first().and_then(|totals| totals.get("api_calls")) {
+            Some(bson::Bson::Int32(total)) => i64::from(*total),
+            Some(bson::Bson::Int64(total)) => *total,
+            _ => 0,
+        })
+    }
+
+    /// Files a new system report
+    async fn create_system_report(
+        &self,
+        title: String,
+        user_id: ObjectId,
+        description: String,
+    ) -> Result<SystemReport, CustomAPIError> {
+        let _timer = metrics::time_db_operation("create_system_report");
+
+        let collection = self.get_collection::<SystemReport>(CollectionNames::SystemReport);
+
+        let report = SystemReport {
+            _id: ObjectId::new(),
+            title,
+            description,
+            status: ReportStatus::InProgress,
+            created_at: self.get_current_time()?,
+            userId: user_id,
+            timeline: Vec::new(),
+            fingerprint: None,
+            occurrences: None,
+        };
+
+        match collection.insert_one(report.clone(), None).await {
+            Ok(_) => Ok(report),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Returns all system reports matching the filter, newest first
+    async fn get_system_reports(&self, filter: ReportFilter) -> Result<Vec<SystemReport>, CustomAPIError> {
+        let _timer = metrics::time_db_operation("get_system_reports");
+
+        let collection = self.get_collection::<SystemReport>(CollectionNames::SystemReport);
+        let options = FindOptions::builder().sort(doc! {"created_at": -1}).build();
+
+        let cursor = collection
+            .find(report_filter(filter, "userId")?, options).await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    /// Returns a single system report
+    async fn get_system_report(&self, id: ObjectId) -> Result<Option<SystemReport>, CustomAPIError> {
+        let _timer = metrics::time_db_operation("get_system_report");
+
+        let collection = self.get_collection::<SystemReport>(CollectionNames::SystemReport);
+
+        match collection.find_one(doc! {"_id": id}, None).await {
+            Ok(result) => Ok(result),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Applies the changes to a system report and returns the updated report
     ///
-    /// This is used to check if a token is valid. If it is, then the user is authenticated on the API.
-    pub async fn has_api_key(&self, token: String) -> anyhow::Result<bool> {
-        let collection = self.get_collection::<Tokens>(CollectionNames::Tokens);
+    /// The event, if any, is appended to the report timeline.
+    async fn update_system_report(
+        &self,
+        id: ObjectId,
+        changes: ReportChanges,
+        event: Option<ReportEvent>,
+    ) -> Result<Option<SystemReport>, CustomAPIError> {
+        let _timer = metrics::time_db_operation("update_system_report");
 
-        let filter = doc! {"token": token};
+        let collection = self.get_collection::<SystemReport>(CollectionNames::SystemReport);
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
 
-        match collection.find_one(filter, None).await {
-            Ok(result) => match result {
-                Some(_) => Ok(true),
-                None => Ok(false),
-            },
-            Err(e) => Err(anyhow::Error::new(e)),
+        let filter = report_update_filter(id, &changes)?;
+        let conditional = changes.from_status.is_some();
+
+        match collection
+            .find_one_and_update(filter, report_update(changes, event)?, options)
+            .await?
+        {
+            Some(report) => Ok(Some(report)),
+            // The report exists, but its status changed in the meantime.
+            None if conditional && collection.find_one(doc! {"_id": id}, None).await?.is_some() => {
+                Err(report_status_conflict())
+            }
+            None => Ok(None),
         }
     }
 
-    /// Returns the API key for a given token
-    pub async fn get_api_key(&self, token: &str) -> anyhow::Result<Option<String>> {
-        let collection = self.get_collection::<Tokens>(CollectionNames::Tokens);
+    /// Records occurrences of a grouped server error.
+    ///
+    /// The open report with the same fingerprint is updated, a new one is filed if there is none.
+    /// Both happen in one upsert, the unique index from `create_indexes` keeps concurrent upserts
+    /// from filing the report twice.
+    async fn record_server_error(
+        &self,
+        fingerprint: String,
+        title: String,
+        description: String,
+        occurrences: i64,
+    ) -> Result<(), CustomAPIError> {
+        let _timer = metrics::time_db_operation("record_server_error");
 
-        let filter = doc! {"token": token};
+        let collection = self.get_collection::<SystemReport>(CollectionNames::SystemReport);
 
-        match collection.find_one(filter, None).await {
-            Ok(result) => match result {
-                Some(data) => Ok(Some(data.token)),
-                None => Ok(None),
-            },
-            Err(e) => Err(anyhow::Error::new(e)),
+        let filter = doc! {
+            "fingerprint": &fingerprint,
+            "status": bson::to_bson(&ReportStatus::InProgress)?,
+        };
+
+        // The fields of the filter and the counter are filled in by the upsert itself.
+        let mut report = bson::to_document(&SystemReport {
+            _id: ObjectId::new(),
+            title,
+            description,
+            status: ReportStatus::InProgress,
+            created_at: self.get_current_time()?,
+            userId: SYSTEM_USER_ID,
+            timeline: Vec::new(),
+            fingerprint: None,
+            occurrences: None,
+        })?;
+        for field in ["fingerprint", "status", "occurrences"] {
+            report.remove(field);
         }
+
+        let update = doc! {"$inc": {"occurrences": occurrences}, "$setOnInsert": report};
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        collection.update_one(filter, update, options).await?;
+
+        Ok(())
+    }
+
+    /// Files a new user report
+    async fn create_user_report(
+        &self,
+        title: String,
+        description: String,
+        assigned_to_id: ObjectId,
+    ) -> Result<UserReport, CustomAPIError> {
+        let _timer = metrics::time_db_operation("create_user_report");
+
+        let collection = self.get_collection::<UserReport>(CollectionNames::UserReport);
+
+        let report = UserReport {
+            _id: ObjectId::new(),
+            title,
+            description,
+            status: ReportStatus::InProgress,
+            created_at: self.get_current_time()?,
+            assignedToId: assigned_to_id,
+            timeline: Vec::new(),
+        };
+
+        match collection.insert_one(report.clone(), None).await {
+            Ok(_) => Ok(report),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Returns all user reports matching the filter, newest first
+    async fn get_user_reports(&self, filter: ReportFilter) -> Result<Vec<UserReport>, CustomAPIError> {
+        let _timer = metrics::time_db_operation("get_user_reports");
+
+        let collection = self.get_collection::<UserReport>(CollectionNames::UserReport);
+        let options = FindOptions::builder().sort(doc! {"created_at": -1}).build();
+
+        let cursor = collection
+            .find(report_filter(filter, "assignedToId")?, options).await?;
+
+        Ok(cursor.try_collect().await?)
     }
 
-    /// Converts a string to an mongodb ObjectId
-    pub fn convert_to_object_id(&self, id: String) -> anyhow::Result<ObjectId> {
-        match ObjectId::parse_str(&id) {
-            Ok(object_id) => Ok(object_id),
-            Err(e) => Err(anyhow::Error::new(e)),
+    /// Returns a single user report
+    async fn get_user_report(&self, id: ObjectId) -> Result<Option<UserReport>, CustomAPIError> {
+        let _timer = metrics::time_db_operation("get_user_report");
+
+        let collection = self.get_collection::<UserReport>(CollectionNames::UserReport);
+
+        match collection.find_one(doc! {"_id": id}, None).await {
+            Ok(result) => Ok(result),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Applies the changes to a user report and returns the updated report
+    ///
+    /// The event, if any, is appended to the report timeline.
+    async fn update_user_report(
+        &self,
+        id: ObjectId,
+        changes: ReportChanges,
+        event: Option<ReportEvent>,
+    ) -> Result<Option<UserReport>, CustomAPIError> {
+        let _timer = metrics::time_db_operation("update_user_report");
+
+        let collection = self.get_collection::<UserReport>(CollectionNames::UserReport);
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        let filter = report_update_filter(id, &changes)?;
+        let conditional = changes.from_status.is_some();
+
+        match collection
+            .find_one_and_update(filter, report_update(changes, event)?, options)
+            .await?
+        {
+            Some(report) => Ok(Some(report)),
+            // The report exists, but its status changed in the meantime.
+            None if conditional && collection.find_one(doc! {"_id": id}, None).await?.is_some() => {
+                Err(report_status_conflict())
+            }
+            None => Ok(None),
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[actix_web::test]
+    async fn applies_collection_prefix_and_overrides() {
+        let mut database = DatabaseConfig {
+            collection_prefix: String::from("staging_"),
+            ..DatabaseConfig::default()
+        };
+        database
+            .collections
+            .insert(String::from("users"), String::from("members"));
+
+        let db = MongoDB::new(&String::from(test_utils::UNREACHABLE_URI), &database)
+            .await
+            .unwrap();
+
+        assert_eq!(db.collection_name(CollectionNames::User), "staging_members");
+        assert_eq!(db.collection_name(CollectionNames::Tokens), "staging_tokens");
+        assert_eq!(
+            db.collection_name(CollectionNames::Custom(String::from("jobs"))),
+            "staging_jobs"
+        );
+    }
+
+    #[test]
+    fn increments_only_the_counters_of_the_usage() {
+        let usage = Usage {
+            api_calls: Some(1),
+            api_calls_success: Some(1),
+            ..Usage::default()
+        };
 
-    /// Gets the current DateTime in UTC
-    /// This function converts the DateTime into MongoDB compatible format for storing in the database
-    pub fn get_current_time(&self) -> anyhow::Result<bson::DateTime> {
-        let now = Utc::now();
-        let bson_date_time = BsonDateTime::from_chrono(now);
-        Ok(bson_date_time)
+        assert_eq!(
+            usage_increments(&usage).unwrap(),
+            doc! {"usage.api_calls": 1, "usage.api_calls_success": 1}
+        );
     }
 }
@@ -1,34 +1,249 @@
 use actix_web::{
     error,
-    http::{header::ContentType, StatusCode},
+    http::{header, StatusCode},
     HttpResponse,
 };
-use derive_more::{Display, Error};
+use derive_more::Display;
+use serde::Serialize;
 
-#[derive(Debug, Display, Error)]
+use crate::middleware::request_id;
+
+/// The error type returned by every handler and database helper.
+///
+/// Errors are answered with a JSON envelope:
+///
+/// ```json
+/// {
+///   "error": {
+///     "code": "not_found",
+///     "message": "Report not found!",
+///     "request_id": "6458f3c2a7d1e2b4c9f01a3e",
+///     "details": null
+///   }
+/// }
+/// ```
+#[derive(Debug, Display)]
 pub enum CustomAPIError {
     #[display(fmt = "internal error")]
     InternalError,
 
-    #[display(fmt = "bad request")]
-    BadClientData,
+    #[display(fmt = "{}", _0)]
+    BadClientData(String),
 
     #[display(fmt = "timeout")]
     Timeout,
+
+    #[display(fmt = "{}", _0)]
+    Unauthorized(String),
+
+    #[display(fmt = "{}", "_0.message()")]
+    InvalidApiKey(ApiKeyError),
+
+    #[display(fmt = "{}", _0)]
+    Forbidden(String),
+
+    #[display(fmt = "{}", _0)]
+    NotFound(String),
+
+    #[display(fmt = "Insufficient credit amount!")]
+    InsufficientCredits,
+
+    #[display(fmt = "Too many requests, retry in {} seconds", retry_after)]
+    RateLimited { retry_after: u64 },
+
+    #[display(fmt = "The {} quota of this {} is used up", quota, scope)]
+    QuotaExceeded {
+        /// `user` or `token`
+        scope: &'static str,
+        /// `requests_per_day` or `credits_per_month`
+        quota: &'static str,
+    },
+
+    #[display(fmt = "{}", _0)]
+    Conflict(String),
+
+    #[display(fmt = "The server is busy, retry in {} seconds", retry_after)]
+    Unavailable { retry_after: u64 },
+
+    #[display(fmt = "{}", message)]
+    Validation { field: String, message: String },
+}
+
+/// Why the API key of a request was rejected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApiKeyError {
+    Missing,
+    Malformed,
+    Unknown,
+    Revoked,
+    Expired,
+}
+
+impl ApiKeyError {
+    /// A stable, machine readable name for the reason
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ApiKeyError::Missing => "missing",
+            ApiKeyError::Malformed => "malformed",
+            ApiKeyError::Unknown => "unknown",
+            ApiKeyError::Revoked => "revoked",
+            ApiKeyError::Expired => "expired",
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            ApiKeyError::Missing => "An API key is required!",
+            ApiKeyError::Malformed => "Malformed Authorization header!",
+            ApiKeyError::Unknown => "The API key is unknown!",
+            ApiKeyError::Revoked => "The API key was revoked!",
+            ApiKeyError::Expired => "The API key has expired!",
+        }
+    }
+
+    /// The `WWW-Authenticate` challenge, following RFC 6750
+    fn challenge(&self) -> String {
+        let error = match self {
+            // A request without credentials gets the bare challenge.
+            ApiKeyError::Missing => return String::from("Bearer realm=\"neura-labs-api\""),
+            ApiKeyError::Malformed => "invalid_request",
+            _ => "invalid_token",
+        };
+
+        format!(
+            "Bearer realm=\"neura-labs-api\", error=\"{}\", error_description=\"{}\"",
+            error,
+            self.message()
+        )
+    }
+}
+
+impl std::error::Error for CustomAPIError {}
+
+impl CustomAPIError {
+    /// Creates a validation error for a single request field
+    pub fn validation(field: &str, message: impl Into<String>) -> Self {
+        CustomAPIError::Validation {
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+
+    /// A stable, machine readable name for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            CustomAPIError::InternalError => "internal_error",
+            CustomAPIError::BadClientData(_) => "bad_request",
+            CustomAPIError::Timeout => "timeout",
+            CustomAPIError::Unauthorized(_) => "unauthorized",
+            CustomAPIError::Forbidden(_) => "forbidden",
+            CustomAPIError::NotFound(_) => "not_found",
+            CustomAPIError::InsufficientCredits => "insufficient_credits",
+            CustomAPIError::RateLimited { .. } => "rate_limited",
+            CustomAPIError::QuotaExceeded { .. } => "quota_exceeded",
+            CustomAPIError::InvalidApiKey(_) => "unauthorized",
+            CustomAPIError::Conflict(_) => "conflict",
+            CustomAPIError::Unavailable { .. } => "unavailable",
+            CustomAPIError::Validation { .. } => "validation_error",
+        }
+    }
+
+    fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            CustomAPIError::RateLimited { retry_after }
+            | CustomAPIError::Unavailable { retry_after } => {
+                Some(serde_json::json!({ "retry_after": retry_after }))
+            }
+            CustomAPIError::InvalidApiKey(e) => Some(serde_json::json!({ "reason": e.reason() })),
+            CustomAPIError::QuotaExceeded { scope, quota } => {
+                Some(serde_json::json!({ "scope": scope, "quota": quota }))
+            }
+            CustomAPIError::Validation { field, .. } => Some(serde_json::json!({ "field": field })),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorEnvelope {
+    error: ErrorBody,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    request_id: Option<String>,
+    details: Option<serde_json::Value>,
 }
 
 impl error::ResponseError for CustomAPIError {
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .insert_header(ContentType::html())
-            .body(self.to_string())
+        let mut res = HttpResponse::build(self.status_code());
+
+        if let CustomAPIError::RateLimited { retry_after }
+        | CustomAPIError::Unavailable { retry_after } = self
+        {
+            res.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+        }
+
+        if let CustomAPIError::InvalidApiKey(e) = self {
+            res.insert_header((header::WWW_AUTHENTICATE, e.challenge()));
+        }
+
+        res.json(ErrorEnvelope {
+            error: ErrorBody {
+                code: self.code(),
+                message: self.to_string(),
+                request_id: request_id::current(),
+                details: self.details(),
+            },
+        })
     }
 
     fn status_code(&self) -> StatusCode {
         match *self {
             CustomAPIError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
-            CustomAPIError::BadClientData => StatusCode::BAD_REQUEST,
+            CustomAPIError::BadClientData(_) => StatusCode::BAD_REQUEST,
             CustomAPIError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            CustomAPIError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            CustomAPIError::Forbidden(_) => StatusCode::FORBIDDEN,
+            CustomAPIError::NotFound(_) => StatusCode::NOT_FOUND,
+            CustomAPIError::InsufficientCredits => StatusCode::PAYMENT_REQUIRED,
+            CustomAPIError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            CustomAPIError::QuotaExceeded { .. } => StatusCode::TOO_MANY_REQUESTS,
+            CustomAPIError::InvalidApiKey(_) => StatusCode::UNAUTHORIZED,
+            CustomAPIError::Conflict(_) => StatusCode::CONFLICT,
+            CustomAPIError::Unavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            CustomAPIError::Validation { .. } => StatusCode::BAD_REQUEST,
         }
     }
-}
\ No newline at end of file
+}
+
+// Database and serialization failures are logged and reported to the client as internal errors,
+// the details are not meant for API users.
+impl From<mongodb::error::Error> for CustomAPIError {
+    fn from(e: mongodb::error::Error) -> Self {
+        log::error!("database error: {}", e);
+        CustomAPIError::InternalError
+    }
+}
+
+impl From<mongodb::bson::ser::Error> for CustomAPIError {
+    fn from(e: mongodb::bson::ser::Error) -> Self {
+        log::error!("serialization error: {}", e);
+        CustomAPIError::InternalError
+    }
+}
+
+impl From<anyhow::Error> for CustomAPIError {
+    fn from(e: anyhow::Error) -> Self {
+        log::error!("{}", e);
+        CustomAPIError::InternalError
+    }
+}
+
+/// Converts the built-in actix errors (bad json, path or query) into the API error envelope
+pub fn payload_error_handler(err: impl std::fmt::Display, field: &str) -> actix_web::Error {
+    CustomAPIError::validation(field, err.to_string()).into()
+}
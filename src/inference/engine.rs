@@ -0,0 +1,37 @@
+use neura_labs_engine::{
+    pipelines::translation::generate_translation, utils::convert_strings_to_strs,
+};
+
+use crate::inference::{InferenceEngine, Language};
+
+/// Runs translations on the Neura Labs engine
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NeuraLabsEngine;
+
+impl From<Language> for neura_labs_engine::Language {
+    fn from(language: Language) -> Self {
+        match language {
+            Language::English => neura_labs_engine::Language::English,
+            Language::Spanish => neura_labs_engine::Language::Spanish,
+            Language::French => neura_labs_engine::Language::French,
+            Language::German => neura_labs_engine::Language::German,
+            Language::Italian => neura_labs_engine::Language::Italian,
+            Language::Portuguese => neura_labs_engine::Language::Portuguese,
+            Language::Dutch => neura_labs_engine::Language::Dutch,
+            Language::Russian => neura_labs_engine::Language::Russian,
+            Language::ChineseMandarin => neura_labs_engine::Language::ChineseMandarin,
+            Language::Japanese => neura_labs_engine::Language::Japanese,
+        }
+    }
+}
+
+impl InferenceEngine for NeuraLabsEngine {
+    fn translate(
+        &self,
+        source: Language,
+        target: Language,
+        inputs: &[String],
+    ) -> anyhow::Result<Vec<String>> {
+        generate_translation(source.into(), target.into(), convert_strings_to_strs(inputs))
+    }
+}
@@ -0,0 +1,19 @@
+use crate::inference::{InferenceEngine, Language};
+
+/// Answers instantly with a predictable translation: the input prefixed with the target language.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MockEngine;
+
+impl InferenceEngine for MockEngine {
+    fn translate(
+        &self,
+        _source: Language,
+        target: Language,
+        inputs: &[String],
+    ) -> anyhow::Result<Vec<String>> {
+        Ok(inputs
+            .iter()
+            .map(|input| format!("[{:?}] {}", target, input))
+            .collect())
+    }
+}
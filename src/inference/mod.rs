@@ -0,0 +1,170 @@
+// Machine learning inference. Handlers only talk to the `InferenceEngine` trait, the Neura Labs
+// engine is used when the crate is built with the `engine` feature. The mock engine only runs when
+// `inference.mock` is set, so a build without the feature can not silently charge for mocked output.
+
+#[cfg(feature = "engine")]
+pub mod engine;
+pub mod mock;
+pub mod pool;
+
+use std::{str::FromStr, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::InferenceConfig;
+
+pub use mock::MockEngine;
+pub use pool::InferencePool;
+
+/// The languages the API can translate between
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum Language {
+    English,
+    Spanish,
+    French,
+    German,
+    Italian,
+    Portuguese,
+    Dutch,
+    Russian,
+    ChineseMandarin,
+    Japanese,
+}
+
+impl Language {
+    /// Every supported language, any of them can be translated to any other
+    pub const ALL: [Language; 10] = [
+        Language::English,
+        Language::Spanish,
+        Language::French,
+        Language::German,
+        Language::Italian,
+        Language::Portuguese,
+        Language::Dutch,
+        Language::Russian,
+        Language::ChineseMandarin,
+        Language::Japanese,
+    ];
+
+    /// The ISO 639-1 code of the language
+    pub fn code(&self) -> &'static str {
+        match self {
+            Language::English => "en",
+            Language::Spanish => "es",
+            Language::French => "fr",
+            Language::German => "de",
+            Language::Italian => "it",
+            Language::Portuguese => "pt",
+            Language::Dutch => "nl",
+            Language::Russian => "ru",
+            Language::ChineseMandarin => "zh",
+            Language::Japanese => "ja",
+        }
+    }
+
+    /// The English name of the language
+    pub fn name(&self) -> &'static str {
+        match self {
+            Language::ChineseMandarin => "Chinese (Mandarin)",
+            _ => self.variant(),
+        }
+    }
+
+    /// The name clients used before language codes were introduced
+    fn variant(&self) -> &'static str {
+        match self {
+            Language::English => "English",
+            Language::Spanish => "Spanish",
+            Language::French => "French",
+            Language::German => "German",
+            Language::Italian => "Italian",
+            Language::Portuguese => "Portuguese",
+            Language::Dutch => "Dutch",
+            Language::Russian => "Russian",
+            Language::ChineseMandarin => "ChineseMandarin",
+            Language::Japanese => "Japanese",
+        }
+    }
+}
+
+impl FromStr for Language {
+    type Err = anyhow::Error;
+
+    /// Accepts the language code as well as the variant name, ignoring case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Language::ALL
+            .into_iter()
+            .find(|language| {
+                language.code().eq_ignore_ascii_case(s) || language.variant().eq_ignore_ascii_case(s)
+            })
+            .ok_or_else(|| anyhow::anyhow!("Unsupported language '{}'", s))
+    }
+}
+
+/// Runs the machine learning models.
+///
+/// Inference is CPU bound and blocks, so it must not be called on the async runtime.
+pub trait InferenceEngine: Send + Sync + std::fmt::Debug {
+    /// Translates every input from the source to the target language, in order
+    fn translate(
+        &self,
+        source: Language,
+        target: Language,
+        inputs: &[String],
+    ) -> anyhow::Result<Vec<String>>;
+}
+
+/// The engine the server runs with
+#[cfg(feature = "engine")]
+pub fn default_engine(config: &InferenceConfig) -> anyhow::Result<Arc<dyn InferenceEngine>> {
+    if config.mock {
+        log::warn!("inference.mock is set, translations are mocked");
+        return Ok(Arc::new(MockEngine));
+    }
+
+    Ok(Arc::new(engine::NeuraLabsEngine))
+}
+
+/// The engine the server runs with
+#[cfg(not(feature = "engine"))]
+pub fn default_engine(config: &InferenceConfig) -> anyhow::Result<Arc<dyn InferenceEngine>> {
+    if !config.mock {
+        return Err(anyhow::anyhow!(
+            "built without the engine feature, set inference.mock or INFERENCE_MOCK to serve \
+             mocked translations"
+        ));
+    }
+
+    log::warn!("inference.mock is set, translations are mocked");
+    Ok(Arc::new(MockEngine))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_codes_and_names() {
+        assert_eq!("es".parse::<Language>().unwrap(), Language::Spanish);
+        assert_eq!("ZH".parse::<Language>().unwrap(), Language::ChineseMandarin);
+        assert_eq!("ChineseMandarin".parse::<Language>().unwrap(), Language::ChineseMandarin);
+        assert_eq!("english".parse::<Language>().unwrap(), Language::English);
+        assert!("Klingon".parse::<Language>().is_err());
+
+        for language in Language::ALL {
+            assert_eq!(language.code().parse::<Language>().unwrap(), language);
+        }
+    }
+
+    #[cfg(not(feature = "engine"))]
+    #[test]
+    fn mocks_translations_only_when_configured() {
+        let mut config = InferenceConfig::default();
+        assert!(default_engine(&config).is_err());
+
+        config.mock = true;
+        let engine = default_engine(&config).unwrap();
+        let inputs = [String::from("Hello")];
+        assert!(engine.translate(Language::English, Language::Spanish, &inputs).is_ok());
+    }
+}
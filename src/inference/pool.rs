@@ -0,0 +1,220 @@
+use std::{
+    future::Future,
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use tokio::sync::oneshot;
+
+use crate::{
+    config::InferenceConfig,
+    error::CustomAPIError,
+    inference::{InferenceEngine, Language},
+    metrics::{INFERENCE_QUEUE_DEPTH, INFERENCE_REJECTIONS_TOTAL},
+};
+
+/// How long clients are asked to wait when the queue is full
+const QUEUE_FULL_RETRY_AFTER: u64 = 5;
+
+type Job = Box<dyn FnOnce(&dyn InferenceEngine) + Send>;
+
+/// Runs inference on a fixed number of threads with a bounded queue in front of them.
+///
+/// Requests are rejected when the queue is full instead of piling up, and callers stop waiting
+/// after the configured timeout. A job that already started can not be interrupted, it finishes
+/// on its worker and the result is dropped.
+#[derive(Debug)]
+pub struct InferencePool {
+    sender: SyncSender<Job>,
+    timeout: Duration,
+}
+
+impl InferencePool {
+    /// Starts the worker threads. They stop once the pool is dropped and the queue is drained.
+    pub fn new(engine: Arc<dyn InferenceEngine>, config: &InferenceConfig) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(config.queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..config.workers {
+            let engine = engine.clone();
+            let receiver = receiver.clone();
+
+            thread::Builder::new()
+                .name(format!("inference-{}", i))
+                .spawn(move || worker(engine, receiver))
+                .expect("Failed to start an inference worker");
+        }
+
+        Self {
+            sender,
+            timeout: Duration::from_secs(config.timeout_secs),
+        }
+    }
+
+    /// Runs a job on one of the workers and waits for its result
+    pub async fn run<T, F>(&self, job: F) -> Result<T, CustomAPIError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn InferenceEngine) -> anyhow::Result<T> + Send + 'static,
+    {
+        self.submit(self.timeout, job)?.await
+    }
+
+    /// Queues a job and returns a future that waits up to `timeout` for its result.
+    ///
+    /// The job is queued right away, so a full queue is reported before anything is awaited.
+    pub fn submit<T, F>(
+        &self,
+        timeout: Duration,
+        job: F,
+    ) -> Result<impl Future<Output = Result<T, CustomAPIError>>, CustomAPIError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn InferenceEngine) -> anyhow::Result<T> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+
+        let job: Job = Box::new(move |engine| {
+            // The caller gave up waiting, the work would be thrown away.
+            if tx.is_closed() {
+                return;
+            }
+            let _ = tx.send(job(engine));
+        });
+
+        match self.sender.try_send(job) {
+            Ok(()) => INFERENCE_QUEUE_DEPTH.inc(),
+            Err(TrySendError::Full(_)) => {
+                INFERENCE_REJECTIONS_TOTAL.with_label_values(&["queue_full"]).inc();
+                return Err(CustomAPIError::Unavailable {
+                    retry_after: QUEUE_FULL_RETRY_AFTER,
+                });
+            }
+            Err(TrySendError::Disconnected(_)) => return Err(CustomAPIError::InternalError),
+        }
+
+        Ok(async move {
+            match tokio::time::timeout(timeout, rx).await {
+                Ok(Ok(result)) => Ok(result?),
+                // The worker panicked and dropped the sender.
+                Ok(Err(_)) => Err(CustomAPIError::InternalError),
+                Err(_) => {
+                    INFERENCE_REJECTIONS_TOTAL.with_label_values(&["timeout"]).inc();
+                    Err(CustomAPIError::Timeout)
+                }
+            }
+        })
+    }
+
+    /// Translates every input from the source to the target language, in order
+    pub async fn translate(
+        &self,
+        source: Language,
+        target: Language,
+        inputs: Vec<String>,
+    ) -> Result<Vec<String>, CustomAPIError> {
+        self.run(move |engine| engine.translate(source, target, &inputs))
+            .await
+    }
+}
+
+fn worker(engine: Arc<dyn InferenceEngine>, receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // The lock is only held while waiting for the next job, never while running one.
+        let job = match receiver
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .recv()
+        {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        INFERENCE_QUEUE_DEPTH.dec();
+
+        // A panicking model must not take the worker down with it.
+        let engine = engine.as_ref();
+        if std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| job(engine))).is_err() {
+            log::error!("inference job panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inference::MockEngine;
+    use std::sync::mpsc::Sender;
+
+    fn pool(workers: usize, queue_size: usize, timeout_secs: u64) -> InferencePool {
+        let config = InferenceConfig {
+            workers,
+            queue_size,
+            timeout_secs,
+            mock: true,
+        };
+
+        InferencePool::new(Arc::new(MockEngine), &config)
+    }
+
+    /// Queues a job directly, skipping the async side of the pool
+    fn enqueue(pool: &InferencePool, job: impl FnOnce() + Send + 'static) {
+        INFERENCE_QUEUE_DEPTH.inc();
+        pool.sender.try_send(Box::new(move |_| job())).unwrap();
+    }
+
+    /// Occupies a worker until the returned sender is used or dropped
+    fn block_worker(pool: &InferencePool) -> Sender<()> {
+        let (release, wait) = mpsc::channel::<()>();
+        let (started, has_started) = mpsc::channel::<()>();
+
+        enqueue(pool, move || {
+            started.send(()).unwrap();
+            let _ = wait.recv();
+        });
+
+        has_started
+            .recv_timeout(Duration::from_secs(5))
+            .expect("the worker did not start");
+
+        release
+    }
+
+    #[actix_web::test]
+    async fn translates_on_a_worker() {
+        let pool = pool(1, 1, 5);
+
+        let output = pool
+            .translate(Language::English, Language::French, vec!["Hi".into()])
+            .await
+            .unwrap();
+
+        assert_eq!(output, vec!["[French] Hi"]);
+    }
+
+    #[actix_web::test]
+    async fn rejects_jobs_beyond_the_queue() {
+        let pool = pool(1, 1, 5);
+        let _release = block_worker(&pool);
+
+        // Fills the only queue slot.
+        enqueue(&pool, || ());
+
+        let err = pool.run(|_| Ok(())).await.unwrap_err();
+        assert!(matches!(err, CustomAPIError::Unavailable { retry_after: 5 }));
+    }
+
+    #[actix_web::test]
+    async fn times_out_slow_jobs() {
+        let pool = pool(1, 1, 1);
+        let _release = block_worker(&pool);
+
+        let err = pool.run(|_| Ok(())).await.unwrap_err();
+
+        assert!(matches!(err, CustomAPIError::Timeout));
+    }
+}
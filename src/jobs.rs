@@ -0,0 +1,164 @@
+// Long running model requests. Jobs run on the inference pool of this instance and are kept in
+// memory, so a restart loses queued jobs and finished results alike.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+};
+
+use mongodb::bson::{self, oid::ObjectId};
+use serde::Serialize;
+
+use crate::error::CustomAPIError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+}
+
+/// Why a job failed, in the same shape as the error envelope
+#[derive(Clone, Debug, Serialize)]
+pub struct JobError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+// Field names follow the models, so jobs look like every other document the API returns.
+#[allow(non_snake_case)]
+#[derive(Clone, Debug, Serialize)]
+pub struct Job {
+    pub _id: ObjectId,
+    /// The user the job is charged to
+    pub userId: ObjectId,
+    pub status: JobStatus,
+    pub created_at: bson::DateTime,
+    pub finished_at: Option<bson::DateTime>,
+    pub result: Option<Vec<String>>,
+    pub error: Option<JobError>,
+}
+
+/// Keeps track of jobs until their results expire
+#[derive(Debug)]
+pub struct JobRegistry {
+    jobs: Mutex<HashMap<ObjectId, Job>>,
+    /// How long finished jobs are kept, in milliseconds
+    ttl_millis: i64,
+}
+
+impl JobRegistry {
+    pub fn new(ttl_secs: u64) -> Self {
+        Self {
+            jobs: Mutex::new(HashMap::new()),
+            ttl_millis: i64::try_from(ttl_secs.saturating_mul(1000)).unwrap_or(i64::MAX),
+        }
+    }
+
+    /// Registers a new queued job for the user
+    pub fn create(&self, user_id: ObjectId) -> Job {
+        let job = Job {
+            _id: ObjectId::new(),
+            userId: user_id,
+            status: JobStatus::Queued,
+            created_at: bson::DateTime::now(),
+            finished_at: None,
+            result: None,
+            error: None,
+        };
+
+        let mut jobs = self.jobs();
+        self.purge_expired(&mut jobs);
+        jobs.insert(job._id, job.clone());
+
+        job
+    }
+
+    /// Returns a job, unless its result has expired
+    pub fn get(&self, id: ObjectId) -> Option<Job> {
+        let mut jobs = self.jobs();
+        self.purge_expired(&mut jobs);
+        jobs.get(&id).cloned()
+    }
+
+    /// Forgets a job, used when it could not be queued
+    pub fn remove(&self, id: ObjectId) {
+        self.jobs().remove(&id);
+    }
+
+    /// Marks a job as picked up by a worker
+    pub fn start(&self, id: ObjectId) {
+        if let Some(job) = self.jobs().get_mut(&id) {
+            job.status = JobStatus::Running;
+        }
+    }
+
+    pub fn complete(&self, id: ObjectId, result: Vec<String>) {
+        self.finish(id, |job| {
+            job.status = JobStatus::Completed;
+            job.result = Some(result);
+        });
+    }
+
+    pub fn fail(&self, id: ObjectId, error: &CustomAPIError) {
+        self.finish(id, |job| {
+            job.status = JobStatus::Failed;
+            job.error = Some(JobError {
+                code: error.code(),
+                message: error.to_string(),
+            });
+        });
+    }
+
+    fn finish(&self, id: ObjectId, update: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.jobs().get_mut(&id) {
+            update(job);
+            job.finished_at = Some(bson::DateTime::now());
+        }
+    }
+
+    /// Drops finished jobs older than the ttl, queued and running jobs are always kept
+    fn purge_expired(&self, jobs: &mut HashMap<ObjectId, Job>) {
+        let now = bson::DateTime::now().timestamp_millis();
+
+        jobs.retain(|_, job| match job.finished_at {
+            Some(finished_at) => now - finished_at.timestamp_millis() < self.ttl_millis,
+            None => true,
+        });
+    }
+
+    /// Locks the jobs. They are only ever changed in place, so they are still usable after a panic.
+    fn jobs(&self) -> MutexGuard<'_, HashMap<ObjectId, Job>> {
+        self.jobs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_a_job_until_it_expires() {
+        let registry = JobRegistry::new(3600);
+        let job = registry.create(ObjectId::new());
+
+        registry.start(job._id);
+        assert_eq!(registry.get(job._id).unwrap().status, JobStatus::Running);
+
+        registry.complete(job._id, vec![String::from("Hola")]);
+        let finished = registry.get(job._id).unwrap();
+        assert_eq!(finished.status, JobStatus::Completed);
+        assert_eq!(finished.result, Some(vec![String::from("Hola")]));
+
+        let expired = JobRegistry::new(0);
+        let job = expired.create(ObjectId::new());
+        assert!(expired.get(job._id).is_some());
+
+        expired.fail(job._id, &CustomAPIError::Timeout);
+        assert!(expired.get(job._id).is_none());
+    }
+}
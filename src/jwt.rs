@@ -0,0 +1,154 @@
+// Short-lived access tokens. An api key can be exchanged for a JWT signed with HMAC-SHA256, which
+// the auth middleware verifies without looking up the api token. Only the deletion of the user is
+// checked, through a short-lived cache.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{JwtKey, KeyScope},
+    error::ApiKeyError,
+    models::UserRole,
+};
+
+/// The claims of an access token
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Claims {
+    /// The id of the user
+    pub sub: String,
+    /// The id of the api token the access token was issued for
+    pub tid: String,
+    pub roles: Vec<UserRole>,
+    pub scopes: Vec<KeyScope>,
+    /// Issued at, in seconds since the epoch
+    pub iat: i64,
+    /// Expires at, in seconds since the epoch
+    pub exp: i64,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Header {
+    alg: String,
+    typ: Option<String>,
+    kid: Option<String>,
+}
+
+/// Checks if a key has the shape of a JWT, api tokens and internal keys never contain dots
+pub fn is_jwt(token: &str) -> bool {
+    token.split('.').count() == 3
+}
+
+fn encode<T: Serialize>(value: &T) -> String {
+    // Serializing plain structs to JSON can not fail.
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(value).unwrap_or_default())
+}
+
+fn decode<T: for<'de> Deserialize<'de>>(part: &str) -> Option<T> {
+    let bytes = URL_SAFE_NO_PAD.decode(part).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+fn hmac_key(key: &JwtKey) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, key.secret.as_bytes())
+}
+
+/// Signs the claims with the key, naming it in the `kid` header
+pub fn sign(key: &JwtKey, claims: &Claims) -> String {
+    let header = Header {
+        alg: String::from("HS256"),
+        typ: Some(String::from("JWT")),
+        kid: Some(key.kid.clone()),
+    };
+
+    let message = format!("{}.{}", encode(&header), encode(claims));
+    let signature = hmac::sign(&hmac_key(key), message.as_bytes());
+
+    format!("{}.{}", message, URL_SAFE_NO_PAD.encode(signature.as_ref()))
+}
+
+/// Verifies the signature and expiry of a token.
+///
+/// The `kid` header picks the key, so tokens signed before a rotation stay valid as long as their
+/// key is still configured.
+pub fn verify(keys: &[JwtKey], token: &str, now: i64) -> Result<Claims, ApiKeyError> {
+    let mut parts = token.split('.');
+    let (header, claims, signature) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(header), Some(claims), Some(signature)) => (header, claims, signature),
+        _ => return Err(ApiKeyError::Malformed),
+    };
+
+    let decoded: Header = decode(header).ok_or(ApiKeyError::Malformed)?;
+    // Only our own algorithm is accepted, never `none` or whatever the token asks for.
+    if decoded.alg != "HS256" {
+        return Err(ApiKeyError::Malformed);
+    }
+
+    let key = keys
+        .iter()
+        .find(|key| decoded.kid.as_deref() == Some(key.kid.as_str()))
+        .ok_or(ApiKeyError::Unknown)?;
+
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| ApiKeyError::Malformed)?;
+    let message = &token[..header.len() + 1 + claims.len()];
+    hmac::verify(&hmac_key(key), message.as_bytes(), &signature)
+        .map_err(|_| ApiKeyError::Unknown)?;
+
+    let claims: Claims = decode(claims).ok_or(ApiKeyError::Malformed)?;
+    if claims.exp <= now {
+        return Err(ApiKeyError::Expired);
+    }
+
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(kid: &str) -> JwtKey {
+        JwtKey {
+            kid: kid.to_string(),
+            secret: format!("{}-0123456789abcdef0123456789abcdef", kid),
+        }
+    }
+
+    fn claims(exp: i64) -> Claims {
+        Claims {
+            sub: String::from("6458f3c2a7d1e2b4c9f01a3e"),
+            tid: String::from("6458f3c2a7d1e2b4c9f01a3f"),
+            roles: vec![UserRole::USER],
+            scopes: vec![KeyScope::Translate],
+            iat: 0,
+            exp,
+        }
+    }
+
+    #[test]
+    fn verifies_tokens_of_every_configured_key() {
+        let (old, new) = (key("old"), key("new"));
+        let token = sign(&old, &claims(100));
+
+        assert!(is_jwt(&token));
+        assert_eq!(verify(&[new.clone(), old], &token, 50), Ok(claims(100)));
+        assert_eq!(verify(&[new], &token, 50), Err(ApiKeyError::Unknown));
+    }
+
+    #[test]
+    fn rejects_expired_and_tampered_tokens() {
+        let keys = [key("main")];
+        let token = sign(&keys[0], &claims(100));
+
+        assert_eq!(verify(&keys, &token, 100), Err(ApiKeyError::Expired));
+
+        let (message, _) = token.rsplit_once('.').unwrap();
+        let forged = format!("{}.{}", message, URL_SAFE_NO_PAD.encode([0; 32]));
+        assert_eq!(verify(&keys, &forged, 50), Err(ApiKeyError::Unknown));
+
+        let (_, rest) = token.split_once('.').unwrap();
+        let unsigned = format!("{}.{}", encode(&serde_json::json!({ "alg": "none" })), rest);
+        assert_eq!(verify(&keys, &unsigned, 50), Err(ApiKeyError::Malformed));
+    }
+}
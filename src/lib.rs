@@ -0,0 +1,143 @@
+pub mod accounts;
+pub mod audit;
+pub mod config;
+pub mod db;
+pub mod error;
+pub mod inference;
+pub mod jobs;
+pub mod jwt;
+pub mod methods;
+pub mod metrics;
+pub mod middleware;
+pub mod models;
+pub mod store;
+#[cfg(test)]
+mod test_utils;
+
+extern crate anyhow;
+
+use std::sync::Arc;
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceFactory, ServiceRequest, ServiceResponse},
+    middleware::Logger,
+    web, App, Error,
+};
+
+use methods::{
+    delete::{delete_me, delete_user},
+    get::{
+        get_audit_logs, get_credits, get_global_statistics, get_job, get_languages, get_me,
+        get_metrics, get_system_report, get_system_report_timeline, get_system_reports, get_user,
+        get_user_report, get_user_report_timeline, get_user_reports, health_check, index,
+        not_found,
+    },
+    patch::{update_me, update_quota, update_system_report, update_user_report},
+    post::{
+        create_access_token, create_api_token, create_job, create_system_report,
+        create_system_report_comment, create_user_payment, create_user_report,
+        create_user_report_comment, translate, translate_stream,
+    },
+};
+
+#[derive(Clone, Debug)]
+pub struct AppState {
+    app_name: String,
+    config: config::Config,
+    db: Arc<dyn store::Store>,
+    inference: Arc<inference::InferencePool>,
+    jobs: Arc<jobs::JobRegistry>,
+    rate_limiter: Arc<dyn middleware::rate_limit::RateLimitBackend>,
+}
+
+impl AppState {
+    pub fn new(
+        config: config::Config,
+        db: Arc<dyn store::Store>,
+        engine: Arc<dyn inference::InferenceEngine>,
+    ) -> Self {
+        let inference = Arc::new(inference::InferencePool::new(engine, &config.inference));
+        let jobs = Arc::new(jobs::JobRegistry::new(config.jobs.ttl_secs));
+
+        Self {
+            app_name: String::from("Neura Labs API"),
+            config,
+            db,
+            inference,
+            jobs,
+            rate_limiter: Arc::new(middleware::rate_limit::MemoryRateLimiter::new()),
+        }
+    }
+}
+
+/// Builds the application with every middleware and route.
+///
+/// Shared by the server and the integration tests so both run the same app.
+pub fn build_app(
+    app_state: AppState,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = Error,
+        InitError = (),
+    >,
+> {
+    App::new()
+        .app_data(web::Data::new(app_state))
+        .app_data(web::JsonConfig::default().error_handler(|err, _req| {
+            error::payload_error_handler(err, "body")
+        }))
+        .app_data(web::PathConfig::default().error_handler(|err, _req| {
+            error::payload_error_handler(err, "path")
+        }))
+        .app_data(web::QueryConfig::default().error_handler(|err, _req| {
+            error::payload_error_handler(err, "query")
+        }))
+        .wrap(Logger::default())
+        .wrap(Logger::new("%a %{User-Agent}i"))
+        .wrap(middleware::rate_limit::RateLimiter::PerToken)
+        .wrap(middleware::auth::RequestHandler)
+        .wrap(middleware::rate_limit::RateLimiter::PerIp)
+        .wrap(middleware::error_reports::ErrorReporter)
+        .wrap(middleware::request_id::RequestIdHandler)
+        .wrap(middleware::metrics::RequestMetrics)
+        // get
+        .service(index)
+        .service(health_check)
+        .service(get_global_statistics)
+        .service(get_metrics)
+        .service(get_languages)
+        .service(get_system_reports)
+        .service(get_system_report)
+        .service(get_system_report_timeline)
+        .service(get_user_reports)
+        .service(get_user_report)
+        .service(get_user_report_timeline)
+        .service(get_job)
+        .service(get_credits)
+        .service(get_audit_logs)
+        .service(get_me)
+        .service(get_user)
+        // post
+        .service(translate)
+        .service(translate_stream)
+        .service(create_job)
+        .service(create_api_token)
+        .service(create_access_token)
+        .service(create_user_payment)
+        .service(create_system_report)
+        .service(create_user_report)
+        .service(create_system_report_comment)
+        .service(create_user_report_comment)
+        // patch
+        .service(update_system_report)
+        .service(update_user_report)
+        .service(update_quota)
+        .service(update_me)
+        .service(delete_me)
+        .service(delete_user)
+        .default_service(web::to(not_found))
+}
@@ -1,54 +1,66 @@
-mod db;
-mod error;
-mod methods;
-mod middleware;
-mod utils;
-mod models;
+use std::sync::Arc;
 
-extern crate anyhow;
-
-use actix_web::{middleware::Logger, web, App, HttpServer};
+use actix_web::HttpServer;
 use env_logger::Env;
 
-use methods::{
-    get::{health_check, index, get_global_statistics}, post::{create_api_token, create_user_payment},
+use neura_labs_api::{
+    accounts, build_app,
+    config::{Config, StorageBackend},
+    db::MongoDB,
+    inference,
+    middleware::error_reports,
+    store::{MemoryStore, Store},
+    AppState,
 };
 
-#[derive(Clone, Debug)]
-pub struct AppState {
-    app_name: String,
-    db: db::MongoDB,
-}
-
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let db = db::MongoDB::new(&utils::env().unwrap().mongodb_uri)
-        .await
-        .expect("Failed to initialize MongoDB");
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let engine = match inference::default_engine(&config.inference) {
+        Ok(engine) => engine,
+        Err(e) => {
+            eprintln!("Invalid configuration: {:#}", e);
+            std::process::exit(1);
+        }
+    };
 
     env_logger::init_from_env(Env::default().default_filter_or("info"));
 
-    HttpServer::new(move || {
-        let app_state = AppState {
-            app_name: String::from("Neura Labs API"),
-            db: db.clone(),
-        };
-
-        App::new()
-            .app_data(web::Data::new(app_state))
-            .wrap(Logger::default())
-            .wrap(Logger::new("%a %{User-Agent}i"))
-            .wrap(middleware::auth::RequestHandler)
-            // get
-            .service(index)
-            .service(health_check)
-            .service(get_global_statistics)
-            // post
-            // .service(translate)
-            .service(create_api_token)
-            .service(create_user_payment)
-    })
-    .bind((utils::env().unwrap().address, utils::env().unwrap().port))?
-    .run()
-    .await
-}
\ No newline at end of file
+    let db: Arc<dyn Store> = match config.storage {
+        StorageBackend::MongoDB => {
+            let db = MongoDB::new(&config.mongodb_uri, &config.database)
+                .await
+                .expect("Failed to initialize MongoDB");
+
+            if let Err(e) = db.create_indexes().await {
+                log::warn!("Failed to create the MongoDB indexes: {}", e);
+            }
+
+            Arc::new(db)
+        }
+        StorageBackend::Memory => Arc::new(MemoryStore::new()),
+    };
+
+    accounts::spawn_purge_task(db.clone(), config.accounts.clone());
+    error_reports::spawn_flush_task(db.clone());
+
+    let bind_address = (config.address.clone(), config.port);
+    let app_state = AppState::new(config, db.clone(), engine);
+
+    let result = HttpServer::new(move || build_app(app_state.clone()))
+        .bind(bind_address)?
+        .run()
+        .await;
+
+    // Occurrences collected since the last flush would be lost otherwise.
+    error_reports::flush_pending(db.as_ref()).await;
+
+    result
+}
@@ -0,0 +1,43 @@
+use crate::{
+    accounts::delete_account,
+    audit::Audit,
+    error::CustomAPIError,
+    methods::{is_admin, profile_owner},
+    middleware::auth::Caller,
+    AppState,
+};
+use actix_web::{delete, web, HttpResponse};
+
+/// Deletes the account of the user making the request
+#[delete("/api/v1/me")]
+pub async fn delete_me(
+    data: web::Data<AppState>,
+    caller: Caller,
+    audit: Audit,
+) -> Result<HttpResponse, CustomAPIError> {
+    let user_id = profile_owner(&caller)?;
+
+    let deletion = delete_account(data.db.as_ref(), &audit, user_id).await?;
+
+    Ok(HttpResponse::Ok().json(deletion))
+}
+
+/// Deletes the account of any user, requires the ADMIN role
+#[delete("/api/v1/users/{id}")]
+pub async fn delete_user(
+    data: web::Data<AppState>,
+    caller: Caller,
+    audit: Audit,
+    path: web::Path<String>,
+) -> Result<HttpResponse, CustomAPIError> {
+    if !is_admin(data.db.as_ref(), &caller).await? {
+        return Err(CustomAPIError::Forbidden(
+            "Only admins can delete other users!".to_string(),
+        ));
+    }
+
+    let id = data.db.convert_to_object_id(path.into_inner())?;
+    let deletion = delete_account(data.db.as_ref(), &audit, id).await?;
+
+    Ok(HttpResponse::Ok().json(deletion))
+}
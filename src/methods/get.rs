@@ -1,6 +1,16 @@
-use crate::AppState;
+use crate::{
+    error::CustomAPIError,
+    inference::Language,
+    methods::{
+        ensure_report_access, get_active_user, is_admin, is_moderator, profile_owner, UserProfile,
+    },
+    metrics,
+    middleware::auth::Caller,
+    models::{AuditAction, Quota, QuotaUsage, ReportStatus},
+    store::{AuditFilter, ReportFilter},
+    AppState,
+};
 use actix_web::{get, web, HttpResponse, Responder};
-use mongodb::bson::doc;
 use serde::{Deserialize, Serialize};
 
 #[get("/")]
@@ -17,11 +27,15 @@ struct HealthReport {
 
 #[get("/health")]
 pub async fn health_check(data: web::Data<AppState>) -> impl Responder {
-    let ping_result = data.db.ping().await.unwrap();
+    // An unreachable database is reported as unhealthy instead of failing the request.
+    let database = match data.db.ping().await {
+        Ok(()) => "1".to_string(),
+        Err(_) => "0".to_string(),
+    };
 
     let health_report = HealthReport {
         server: "OK".to_string(),
-        database: ping_result.get("ok").unwrap().to_string(),
+        database,
     };
 
     if health_report.database == "1" {
@@ -37,18 +51,22 @@ pub async fn health_check(data: web::Data<AppState>) -> impl Responder {
 #[derive(Serialize, Deserialize)]
 struct GlobalStatistics {
     customers: i32,
-    api_calls: i32,
+    api_calls: i64,
     github_stars: i32,
 }
 
 /// Returns the global statistics for the application
-// todo - fetch from the db
+///
+/// The api calls include the anonymous calls of users without telemetry.
+// todo - fetch the customers and stars from the db
 // todo - implement on startup cache that updates every 2 hours.
 // todo - this will fetch the latest stats to display globally.
 #[get("/api/v1/stats")]
-pub async fn get_global_statistics(_data: web::Data<AppState>) -> impl Responder {
+pub async fn get_global_statistics(
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, CustomAPIError> {
     let customers = 500;
-    let api_calls = 1000;
+    let api_calls = data.db.get_total_api_calls().await?;
     let github_stars = 100;
 
     let stats = GlobalStatistics {
@@ -57,5 +75,430 @@ pub async fn get_global_statistics(_data: web::Data<AppState>) -> impl Responder
         github_stars,
     };
 
-    HttpResponse::Ok().json(stats)
+    Ok(HttpResponse::Ok().json(stats))
+}
+
+/// Exposes the server metrics in the Prometheus text format, only to internal keys
+#[get("/metrics")]
+pub async fn get_metrics(caller: Caller) -> Result<HttpResponse, CustomAPIError> {
+    if !matches!(caller, Caller::Internal { .. }) {
+        return Err(CustomAPIError::Forbidden(
+            "Only internal keys can read the metrics!".to_string(),
+        ));
+    }
+
+    let body = metrics::gather()?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
+
+#[derive(Serialize)]
+struct SupportedLanguage {
+    code: &'static str,
+    name: &'static str,
+    /// The codes of the languages this language can be translated to
+    targets: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+struct LanguagesResponse {
+    data: Vec<SupportedLanguage>,
+}
+
+/// Lists the languages translations accept, with the languages each of them can be translated to
+#[get("/api/v1/languages")]
+pub async fn get_languages() -> impl Responder {
+    let data = Language::ALL
+        .into_iter()
+        .map(|language| SupportedLanguage {
+            code: language.code(),
+            name: language.name(),
+            targets: Language::ALL
+                .into_iter()
+                .filter(|target| *target != language)
+                .map(|target| target.code())
+                .collect(),
+        })
+        .collect();
+
+    HttpResponse::Ok().json(LanguagesResponse { data })
+}
+
+#[derive(Deserialize)]
+pub struct ReportQuery {
+    status: Option<ReportStatus>,
+}
+
+/// Builds the filter used to list reports.
+///
+/// Moderators see every report, other users only see the reports that belong to them.
+async fn report_filter(
+    data: &AppState,
+    caller: &Caller,
+    status: Option<ReportStatus>,
+) -> Result<ReportFilter, CustomAPIError> {
+    let mut filter = ReportFilter {
+        status,
+        owner_id: None,
+    };
+
+    if !is_moderator(data.db.as_ref(), caller).await? {
+        filter.owner_id = caller.user_id();
+    }
+
+    Ok(filter)
+}
+
+/// Lists system reports, optionally filtered by `?status=`
+#[get("/api/v1/reports/system")]
+pub async fn get_system_reports(
+    data: web::Data<AppState>,
+    caller: Caller,
+    query: web::Query<ReportQuery>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let filter = report_filter(&data, &caller, query.status).await?;
+    let reports = data.db.get_system_reports(filter).await?;
+
+    Ok(HttpResponse::Ok().json(reports))
+}
+
+/// Returns a single system report
+#[get("/api/v1/reports/system/{id}")]
+pub async fn get_system_report(
+    data: web::Data<AppState>,
+    caller: Caller,
+    path: web::Path<String>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let id = data.db.convert_to_object_id(path.into_inner())?;
+
+    let report = data
+        .db
+        .get_system_report(id)
+        .await?
+        .ok_or_else(|| CustomAPIError::NotFound("Report not found!".to_string()))?;
+
+    ensure_report_access(data.db.as_ref(), &caller, report.userId).await?;
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Returns the status changes and comments of a system report, oldest first
+#[get("/api/v1/reports/system/{id}/timeline")]
+pub async fn get_system_report_timeline(
+    data: web::Data<AppState>,
+    caller: Caller,
+    path: web::Path<String>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let id = data.db.convert_to_object_id(path.into_inner())?;
+
+    let report = data
+        .db
+        .get_system_report(id)
+        .await?
+        .ok_or_else(|| CustomAPIError::NotFound("Report not found!".to_string()))?;
+
+    ensure_report_access(data.db.as_ref(), &caller, report.userId).await?;
+
+    Ok(HttpResponse::Ok().json(report.timeline))
+}
+
+/// Lists user reports, optionally filtered by `?status=`
+#[get("/api/v1/reports/user")]
+pub async fn get_user_reports(
+    data: web::Data<AppState>,
+    caller: Caller,
+    query: web::Query<ReportQuery>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let filter = report_filter(&data, &caller, query.status).await?;
+    let reports = data.db.get_user_reports(filter).await?;
+
+    Ok(HttpResponse::Ok().json(reports))
+}
+
+/// Returns a single user report
+#[get("/api/v1/reports/user/{id}")]
+pub async fn get_user_report(
+    data: web::Data<AppState>,
+    caller: Caller,
+    path: web::Path<String>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let id = data.db.convert_to_object_id(path.into_inner())?;
+
+    let report = data
+        .db
+        .get_user_report(id)
+        .await?
+        .ok_or_else(|| CustomAPIError::NotFound("Report not found!".to_string()))?;
+
+    ensure_report_access(data.db.as_ref(), &caller, report.assignedToId).await?;
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Returns the status changes and comments of a user report, oldest first
+#[get("/api/v1/reports/user/{id}/timeline")]
+pub async fn get_user_report_timeline(
+    data: web::Data<AppState>,
+    caller: Caller,
+    path: web::Path<String>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let id = data.db.convert_to_object_id(path.into_inner())?;
+
+    let report = data
+        .db
+        .get_user_report(id)
+        .await?
+        .ok_or_else(|| CustomAPIError::NotFound("Report not found!".to_string()))?;
+
+    ensure_report_access(data.db.as_ref(), &caller, report.assignedToId).await?;
+
+    Ok(HttpResponse::Ok().json(report.timeline))
+}
+
+/// Returns the profile of the user making the request
+#[get("/api/v1/me")]
+pub async fn get_me(
+    data: web::Data<AppState>,
+    caller: Caller,
+) -> Result<HttpResponse, CustomAPIError> {
+    let user = get_active_user(data.db.as_ref(), profile_owner(&caller)?).await?;
+
+    Ok(HttpResponse::Ok().json(UserProfile::from(user)))
+}
+
+/// Returns the profile of any user, requires the ADMIN role
+#[get("/api/v1/users/{id}")]
+pub async fn get_user(
+    data: web::Data<AppState>,
+    caller: Caller,
+    path: web::Path<String>,
+) -> Result<HttpResponse, CustomAPIError> {
+    if !is_admin(data.db.as_ref(), &caller).await? {
+        return Err(CustomAPIError::Forbidden(
+            "Only admins can look up users!".to_string(),
+        ));
+    }
+
+    let id = data.db.convert_to_object_id(path.into_inner())?;
+    let user = get_active_user(data.db.as_ref(), id).await?;
+
+    Ok(HttpResponse::Ok().json(UserProfile::from(user)))
+}
+
+#[derive(Deserialize)]
+pub struct CreditsQuery {
+    /// The user to report on, required for internal requests
+    id: Option<String>,
+}
+
+/// The use of a single quota in its current period
+#[derive(Serialize)]
+struct QuotaReport {
+    /// Unset if there is no limit
+    limit: Option<i32>,
+    used: i32,
+}
+
+#[derive(Serialize)]
+struct QuotaReports {
+    requests_per_day: QuotaReport,
+    credits_per_month: QuotaReport,
+}
+
+impl QuotaReports {
+    fn new(quota: &Quota, usage: &QuotaUsage) -> Self {
+        let usage = usage.current(chrono::Utc::now());
+
+        Self {
+            requests_per_day: QuotaReport {
+                limit: quota.requests_per_day,
+                used: usage.requests,
+            },
+            credits_per_month: QuotaReport {
+                limit: quota.credits_per_month,
+                used: usage.credits,
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct UsageQuotas {
+    user: Option<QuotaReports>,
+    /// The quota of the token the request was made with
+    token: Option<QuotaReports>,
+}
+
+#[derive(Serialize)]
+struct CreditsResponse {
+    current_amount: i32,
+    used_amount: i32,
+    quotas: UsageQuotas,
+}
+
+/// Returns the credit balance of the caller and the use of their quotas
+///
+/// Internal requests name the user with the `id` query parameter.
+#[get("/api/v1/credits")]
+pub async fn get_credits(
+    data: web::Data<AppState>,
+    caller: Caller,
+    query: web::Query<CreditsQuery>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let user_id = match (caller.user_id(), query.into_inner().id) {
+        (Some(user_id), _) => user_id,
+        (None, Some(id)) => data.db.convert_to_object_id(id)?,
+        (None, None) => return Err(CustomAPIError::validation("id", "A user id is required!")),
+    };
+
+    let credits = data.db.get_credits(user_id).await?;
+    let user = data.db.get_user(user_id).await?;
+    let token = match caller.token_id() {
+        Some(token_id) => data.db.get_api_token_by_id(token_id).await?,
+        None => None,
+    };
+
+    Ok(HttpResponse::Ok().json(CreditsResponse {
+        current_amount: credits
+            .as_ref()
+            .and_then(|credits| credits.current_amount)
+            .unwrap_or(0),
+        used_amount: credits
+            .as_ref()
+            .and_then(|credits| credits.used_amount)
+            .unwrap_or(0),
+        quotas: UsageQuotas {
+            user: user.map(|user| QuotaReports::new(&user.quota, &user.quota_usage)),
+            token: token.map(|token| QuotaReports::new(&token.quota, &token.quota_usage)),
+        },
+    }))
+}
+
+/// Audit log entries returned when no limit is given
+const DEFAULT_AUDIT_LOG_LIMIT: i64 = 100;
+/// The most audit log entries returned at once
+const MAX_AUDIT_LOG_LIMIT: i64 = 1000;
+
+#[derive(Deserialize)]
+pub struct AuditLogQuery {
+    actor_id: Option<String>,
+    key_name: Option<String>,
+    action: Option<AuditAction>,
+    target_kind: Option<String>,
+    target_id: Option<String>,
+    /// RFC 3339 timestamp, only entries created at or after it
+    from: Option<String>,
+    /// RFC 3339 timestamp, only entries created before it
+    to: Option<String>,
+    limit: Option<i64>,
+}
+
+/// Parses an RFC 3339 timestamp from the query
+fn parse_timestamp(field: &str, value: &str) -> Result<mongodb::bson::DateTime, CustomAPIError> {
+    chrono::DateTime::parse_from_rfc3339(value)
+        .map(|time| mongodb::bson::DateTime::from_millis(time.timestamp_millis()))
+        .map_err(|_| {
+            CustomAPIError::validation(
+                field,
+                "Expected an RFC 3339 timestamp, e.g. 2023-05-14T00:00:00Z",
+            )
+        })
+}
+
+/// Lists the audit log, newest first. Only for admins and internal systems.
+///
+/// Every query parameter narrows the listing down, `limit` defaults to 100 and is capped at 1000.
+#[get("/api/v1/audit-logs")]
+pub async fn get_audit_logs(
+    data: web::Data<AppState>,
+    caller: Caller,
+    query: web::Query<AuditLogQuery>,
+) -> Result<HttpResponse, CustomAPIError> {
+    if !is_admin(data.db.as_ref(), &caller).await? {
+        return Err(CustomAPIError::Forbidden(
+            "Only admins can read the audit log!".to_string(),
+        ));
+    }
+
+    let query = query.into_inner();
+
+    let limit = query.limit.unwrap_or(DEFAULT_AUDIT_LOG_LIMIT);
+    if !(1..=MAX_AUDIT_LOG_LIMIT).contains(&limit) {
+        return Err(CustomAPIError::validation(
+            "limit",
+            format!("The limit has to be between 1 and {}!", MAX_AUDIT_LOG_LIMIT),
+        ));
+    }
+
+    let filter = AuditFilter {
+        actor_id: match query.actor_id {
+            Some(id) => Some(
+                data.db
+                    .convert_to_object_id(id)
+                    .map_err(|_| CustomAPIError::validation("actor_id", "Invalid user id!"))?,
+            ),
+            None => None,
+        },
+        key_name: query.key_name,
+        action: query.action,
+        target_kind: query.target_kind,
+        target_id: query.target_id,
+        from: query.from.map(|from| parse_timestamp("from", &from)).transpose()?,
+        to: query.to.map(|to| parse_timestamp("to", &to)).transpose()?,
+        limit,
+    };
+
+    Ok(HttpResponse::Ok().json(data.db.get_audit_logs(filter).await?))
+}
+
+/// Returns the status of a job, and its result once it completed
+///
+/// Jobs are only visible to the user they are charged to.
+#[get("/api/v1/jobs/{id}")]
+pub async fn get_job(
+    data: web::Data<AppState>,
+    caller: Caller,
+    path: web::Path<String>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let id = data.db.convert_to_object_id(path.into_inner())?;
+
+    let job = data
+        .jobs
+        .get(id)
+        .filter(|job| caller.user_id().is_none_or(|user_id| user_id == job.userId))
+        .ok_or_else(|| CustomAPIError::NotFound("Job not found!".to_string()))?;
+
+    Ok(HttpResponse::Ok().json(job))
+}
+
+/// Answers every unknown route
+pub async fn not_found() -> Result<HttpResponse, CustomAPIError> {
+    Err(CustomAPIError::NotFound("Route not found!".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use actix_web::{http::StatusCode, test, App};
+
+    #[actix_web::test]
+    async fn health_check_reports_unreachable_database() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_utils::unreachable_state().await))
+                .service(health_check),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/health").to_request();
+        let res = test::call_service(&app, req).await;
+
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["database"], "0");
+    }
 }
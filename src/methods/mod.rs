@@ -1,17 +1,148 @@
+pub mod delete;
 pub mod get;
+pub mod patch;
 pub mod post;
 
+use base64::{engine::general_purpose::STANDARD, Engine};
 use ring::rand::{SecureRandom, SystemRandom};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-pub fn generate_api_key() -> String {
+use mongodb::bson::{self, oid::ObjectId};
+
+use crate::{
+    error::CustomAPIError,
+    middleware::auth::Caller,
+    models::{User, UserRole},
+    store::{check_quota, Charge, Store},
+};
+
+pub fn generate_api_key() -> Result<String, CustomAPIError> {
     let rng = SystemRandom::new();
     let mut api_key = [0u8; 32];
-    rng.fill(&mut api_key).unwrap();
-    base64::encode(api_key)
+    rng.fill(&mut api_key)
+        .map_err(|_| CustomAPIError::InternalError)?;
+    Ok(STANDARD.encode(api_key))
 }
 
 #[derive(Deserialize)]
 pub struct RequestBody<T> {
     pub data: T,
-}
\ No newline at end of file
+}
+
+/// The profile of a user as returned by the API, quotas and the deletion flag are left out
+#[derive(Serialize)]
+pub struct UserProfile {
+    pub _id: ObjectId,
+    pub name: Option<String>,
+    pub username: Option<String>,
+    pub bio: Option<String>,
+    pub email: String,
+    pub email_verified: Option<bson::DateTime>,
+    pub image: String,
+    pub roles: Vec<UserRole>,
+    pub telemetry: bool,
+}
+
+impl From<User> for UserProfile {
+    fn from(user: User) -> Self {
+        Self {
+            _id: user._id,
+            name: user.name,
+            username: user.username,
+            bio: user.bio,
+            email: user.email,
+            email_verified: user.email_verified,
+            image: user.image,
+            roles: user.roles,
+            telemetry: user.telemetry,
+        }
+    }
+}
+
+/// Returns the id of the user making the request, internal systems have no profile
+pub fn profile_owner(caller: &Caller) -> Result<ObjectId, CustomAPIError> {
+    caller.user_id().ok_or_else(|| {
+        CustomAPIError::Forbidden("Internal keys do not belong to a user!".to_string())
+    })
+}
+
+/// Returns a user that was not deleted
+pub async fn get_active_user(db: &dyn Store, user_id: ObjectId) -> Result<User, CustomAPIError> {
+    db.get_user(user_id)
+        .await?
+        .filter(|user| !user.tomestoned)
+        .ok_or_else(|| CustomAPIError::NotFound("User not found!".to_string()))
+}
+
+/// Checks if the caller is allowed to triage reports.
+///
+/// Internal systems are always allowed, users need the MODERATOR or ADMIN role.
+pub async fn is_moderator(db: &dyn Store, caller: &Caller) -> Result<bool, CustomAPIError> {
+    let user_id = match caller.user_id() {
+        Some(user_id) => user_id,
+        None => return Ok(true),
+    };
+
+    match db.get_user(user_id).await? {
+        Some(user) => Ok(user.has_any_role(&[UserRole::MODERATOR, UserRole::ADMIN])),
+        None => Ok(false),
+    }
+}
+
+/// Checks if the caller may administrate the API.
+///
+/// Internal systems are always allowed, users need the ADMIN role.
+pub async fn is_admin(db: &dyn Store, caller: &Caller) -> Result<bool, CustomAPIError> {
+    let user_id = match caller.user_id() {
+        Some(user_id) => user_id,
+        None => return Ok(true),
+    };
+
+    match db.get_user(user_id).await? {
+        Some(user) => Ok(user.has_any_role(&[UserRole::ADMIN])),
+        None => Ok(false),
+    }
+}
+
+/// Checks if the caller can see a report, returning whether they are a moderator.
+///
+/// Reports of other users are answered with NotFound so their existence is not leaked.
+pub async fn ensure_report_access(
+    db: &dyn Store,
+    caller: &Caller,
+    owner_id: ObjectId,
+) -> Result<bool, CustomAPIError> {
+    let moderator = is_moderator(db, caller).await?;
+
+    if !moderator && caller.user_id() != Some(owner_id) {
+        return Err(CustomAPIError::NotFound("Report not found!".to_string()));
+    }
+
+    Ok(moderator)
+}
+
+/// Checks that the user has at least one credit left and that one more request fits in the
+/// quotas of the user and the token, without charging anything.
+///
+/// This only avoids work that can not be paid for. Concurrent requests can all pass it, the hard
+/// limit is enforced by `process_credit_usage`, and output is only returned once it succeeded.
+pub async fn ensure_credits(db: &dyn Store, charge: Charge) -> Result<(), CustomAPIError> {
+    match db.get_credits(charge.user_id).await? {
+        Some(credits) if credits.current_amount.unwrap_or(0) > 0 => {}
+        _ => return Err(CustomAPIError::InsufficientCredits),
+    }
+
+    let now = chrono::Utc::now();
+
+    if let Some(user) = db.get_user(charge.user_id).await? {
+        check_quota("user", &user.quota_usage.current(now), &user.quota)?;
+    }
+
+    if let Some(token_id) = charge.token_id {
+        if let Some(token) = db.get_api_token_by_id(token_id).await? {
+            check_quota("token", &token.quota_usage.current(now), &token.quota)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,460 @@
+use crate::{
+    audit::Audit,
+    error::CustomAPIError,
+    methods::{ensure_report_access, get_active_user, profile_owner, RequestBody, UserProfile},
+    middleware::auth::Caller,
+    models::{AuditAction, AuditTarget, Quota, ReportEvent, ReportEventKind, ReportStatus},
+    store::{ProfileChanges, ReportChanges},
+    AppState,
+};
+use actix_web::{patch, web, HttpResponse};
+use mongodb::bson::oid::ObjectId;
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Deserialize, Clone)]
+pub struct UpdateReportBody {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Requires the MODERATOR or ADMIN role
+    pub status: Option<ReportStatus>,
+    /// Requires the MODERATOR or ADMIN role. Only user reports have an assignee.
+    pub assigned_to_id: Option<String>,
+    /// Recorded in the report timeline together with the status change
+    pub reason: Option<String>,
+}
+
+/// Creates the timeline entry for a status change, if the update has one
+fn status_change_event(
+    data: &AppState,
+    caller: &Caller,
+    body: &UpdateReportBody,
+    from: ReportStatus,
+) -> Result<Option<ReportEvent>, CustomAPIError> {
+    let to = match body.status {
+        Some(to) => to,
+        None => return Ok(None),
+    };
+
+    Ok(Some(ReportEvent {
+        kind: ReportEventKind::StatusChange { from, to },
+        actorId: caller.user_id(),
+        text: body
+            .reason
+            .clone()
+            .unwrap_or_else(|| format!("Status changed from {:?} to {:?}", from, to)),
+        created_at: data.db.get_current_time()?,
+    }))
+}
+
+/// Validates the requested changes.
+///
+/// Returns an error if the caller may not make the change.
+fn build_report_changes(
+    body: &UpdateReportBody,
+    current_status: ReportStatus,
+    is_moderator: bool,
+    assigned_to_id: Option<ObjectId>,
+) -> Result<ReportChanges, CustomAPIError> {
+    let mut changes = ReportChanges::default();
+
+    if (body.status.is_some() || body.assigned_to_id.is_some()) && !is_moderator {
+        return Err(CustomAPIError::Forbidden(
+            "Only moderators can change the status or assignee of a report!".to_string(),
+        ));
+    }
+
+    if let Some(title) = &body.title {
+        if title.trim().is_empty() {
+            return Err(CustomAPIError::validation(
+                "title",
+                "The title can not be empty!",
+            ));
+        }
+        changes.title = Some(title.clone());
+    }
+
+    if let Some(description) = &body.description {
+        if description.trim().is_empty() {
+            return Err(CustomAPIError::validation(
+                "description",
+                "The description can not be empty!",
+            ));
+        }
+        changes.description = Some(description.clone());
+    }
+
+    if let Some(status) = body.status {
+        if !current_status.can_transition_to(status) {
+            return Err(CustomAPIError::Conflict(format!(
+                "Can not change the report status from {:?} to {:?}!",
+                current_status, status
+            )));
+        }
+        changes.status = Some(status);
+        changes.from_status = Some(current_status);
+    }
+
+    changes.assigned_to_id = assigned_to_id;
+
+    if changes.is_empty() {
+        return Err(CustomAPIError::BadClientData(
+            "Nothing to update!".to_string(),
+        ));
+    }
+
+    Ok(changes)
+}
+
+/// Records a report status change in the audit log
+async fn audit_status_change(
+    data: &AppState,
+    audit: &Audit,
+    kind: &str,
+    id: ObjectId,
+    from: ReportStatus,
+    to: ReportStatus,
+) {
+    audit
+        .record(
+            data.db.as_ref(),
+            AuditAction::ReportStatusChanged,
+            AuditTarget::new(kind, id),
+            Some(json!({ "status": from })),
+            Some(json!({ "status": to })),
+        )
+        .await;
+}
+
+/// Updates a system report
+///
+/// # Example Request Body
+/// ```json
+/// {
+///   "data": {
+///     "status": "RESOLVED"
+///   }
+/// }
+/// ```
+#[patch("/api/v1/reports/system/{id}")]
+pub async fn update_system_report(
+    data: web::Data<AppState>,
+    caller: Caller,
+    audit: Audit,
+    path: web::Path<String>,
+    body: web::Json<RequestBody<UpdateReportBody>>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let id = data.db.convert_to_object_id(path.into_inner())?;
+
+    if body.data.assigned_to_id.is_some() {
+        return Err(CustomAPIError::validation(
+            "assigned_to_id",
+            "System reports do not have an assignee!",
+        ));
+    }
+
+    let report = data
+        .db
+        .get_system_report(id)
+        .await?
+        .ok_or_else(|| CustomAPIError::NotFound("Report not found!".to_string()))?;
+
+    let moderator = ensure_report_access(data.db.as_ref(), &caller, report.userId).await?;
+    let changes = build_report_changes(&body.data, report.status, moderator, None)?;
+    let event = status_change_event(&data, &caller, &body.data, report.status)?;
+    let previous_status = report.status;
+
+    let report = data
+        .db
+        .update_system_report(id, changes, event)
+        .await?
+        .ok_or_else(|| CustomAPIError::NotFound("Report not found!".to_string()))?;
+
+    if report.status != previous_status {
+        let kind = "system_report";
+        audit_status_change(&data, &audit, kind, id, previous_status, report.status).await;
+    }
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Updates a user report
+#[patch("/api/v1/reports/user/{id}")]
+pub async fn update_user_report(
+    data: web::Data<AppState>,
+    caller: Caller,
+    audit: Audit,
+    path: web::Path<String>,
+    body: web::Json<RequestBody<UpdateReportBody>>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let id = data.db.convert_to_object_id(path.into_inner())?;
+
+    let assigned_to_id =
+        match body.data.assigned_to_id.clone() {
+            Some(assignee) => Some(data.db.convert_to_object_id(assignee).map_err(|_| {
+                CustomAPIError::validation("assigned_to_id", "Invalid assignee id!")
+            })?),
+            None => None,
+        };
+
+    let report = data
+        .db
+        .get_user_report(id)
+        .await?
+        .ok_or_else(|| CustomAPIError::NotFound("Report not found!".to_string()))?;
+
+    let moderator = ensure_report_access(data.db.as_ref(), &caller, report.assignedToId).await?;
+    let changes = build_report_changes(&body.data, report.status, moderator, assigned_to_id)?;
+    let event = status_change_event(&data, &caller, &body.data, report.status)?;
+    let previous_status = report.status;
+
+    let report = data
+        .db
+        .update_user_report(id, changes, event)
+        .await?
+        .ok_or_else(|| CustomAPIError::NotFound("Report not found!".to_string()))?;
+
+    if report.status != previous_status {
+        let kind = "user_report";
+        audit_status_change(&data, &audit, kind, id, previous_status, report.status).await;
+    }
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[derive(Deserialize, Clone)]
+pub struct UpdateQuotaBody {
+    /// The user whose quota is replaced
+    pub id: String,
+    /// Replaces the quota of this api token of the user instead
+    pub token_id: Option<String>,
+    /// Left out for no limit
+    pub requests_per_day: Option<i32>,
+    /// Left out for no limit
+    pub credits_per_month: Option<i32>,
+}
+
+/// Replaces the quota of a user or one of their api tokens. Only for internal requests.
+///
+/// Every charged request counts as one request and one credit against the quotas of the user and
+/// the token it was made with.
+///
+/// # Example Request Body
+/// ```json
+/// {
+///   "data": {
+///     "id": "6454d5c4b1b9c0b5f3d6f3b3",
+///     "requests_per_day": 1000,
+///     "credits_per_month": 20000
+///   }
+/// }
+/// ```
+#[patch("/api/v1/quotas")]
+pub async fn update_quota(
+    data: web::Data<AppState>,
+    caller: Caller,
+    audit: Audit,
+    body: web::Json<RequestBody<UpdateQuotaBody>>,
+) -> Result<HttpResponse, CustomAPIError> {
+    if caller.user_id().is_some() {
+        return Err(CustomAPIError::Forbidden(
+            "Quotas can only be changed by internal systems!".to_string(),
+        ));
+    }
+
+    let body = body.into_inner().data;
+
+    for (field, limit) in [
+        ("requests_per_day", body.requests_per_day),
+        ("credits_per_month", body.credits_per_month),
+    ] {
+        if limit.is_some_and(|limit| limit < 0) {
+            return Err(CustomAPIError::validation(
+                field,
+                "A quota can not be negative!",
+            ));
+        }
+    }
+
+    let user_id = data.db.convert_to_object_id(body.id)?;
+    let quota = Quota {
+        requests_per_day: body.requests_per_day,
+        credits_per_month: body.credits_per_month,
+    };
+
+    let not_found = || CustomAPIError::NotFound("User or token not found!".to_string());
+
+    let (target, before) = match body.token_id {
+        Some(token_id) => {
+            let token_id = data
+                .db
+                .convert_to_object_id(token_id)
+                .map_err(|_| CustomAPIError::validation("token_id", "Invalid token id!"))?;
+
+            // The token has to belong to the user, so a typo can not change someone else's quota.
+            let token = data
+                .db
+                .get_api_token_by_id(token_id)
+                .await?
+                .filter(|token| token.userId == user_id)
+                .ok_or_else(not_found)?;
+
+            if !data.db.set_token_quota(token_id, quota.clone()).await? {
+                return Err(not_found());
+            }
+
+            (AuditTarget::new("token", token_id), token.quota)
+        }
+        None => {
+            let user = data.db.get_user(user_id).await?.ok_or_else(not_found)?;
+
+            if !data.db.set_user_quota(user_id, quota.clone()).await? {
+                return Err(not_found());
+            }
+
+            (AuditTarget::new("user", user_id), user.quota)
+        }
+    };
+
+    audit
+        .record(
+            data.db.as_ref(),
+            AuditAction::QuotaChanged,
+            target,
+            Some(json!(before)),
+            Some(json!(quota)),
+        )
+        .await;
+
+    Ok(HttpResponse::Ok().json(quota))
+}
+
+#[derive(Deserialize, Clone)]
+pub struct UpdateProfileBody {
+    /// An empty string clears the name, the same goes for the username and bio
+    pub name: Option<String>,
+    /// 3 to 32 letters, digits, `_` or `-`, unique across users
+    pub username: Option<String>,
+    pub bio: Option<String>,
+    /// An http(s) url, or empty for no image
+    pub image: Option<String>,
+    /// Whether detailed usage statistics may be collected
+    pub telemetry: Option<bool>,
+}
+
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_USERNAME_LENGTH: usize = 32;
+const MAX_BIO_LENGTH: usize = 500;
+const MAX_IMAGE_LENGTH: usize = 2048;
+
+/// Trims a text field, empty values clear the field
+fn optional_text(
+    field: &str,
+    value: &Option<String>,
+    max_length: usize,
+) -> Result<Option<Option<String>>, CustomAPIError> {
+    let value = match value {
+        Some(value) => value.trim(),
+        None => return Ok(None),
+    };
+
+    if value.chars().count() > max_length {
+        return Err(CustomAPIError::validation(
+            field,
+            format!("The {} can be at most {} characters long!", field, max_length),
+        ));
+    }
+
+    Ok(Some(Some(value.to_string()).filter(|value| !value.is_empty())))
+}
+
+impl UpdateProfileBody {
+    /// Validates the fields, the username is checked against other users separately
+    fn changes(&self) -> Result<ProfileChanges, CustomAPIError> {
+        let username = optional_text("username", &self.username, MAX_USERNAME_LENGTH)?;
+
+        if let Some(Some(username)) = &username {
+            let valid_chars = username
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+            if username.len() < 3 || !valid_chars {
+                return Err(CustomAPIError::validation(
+                    "username",
+                    "The username needs 3 to 32 letters, digits, '_' or '-'!",
+                ));
+            }
+        }
+
+        let image = optional_text("image", &self.image, MAX_IMAGE_LENGTH)?;
+
+        if let Some(Some(image)) = &image {
+            if !image.starts_with("https://") && !image.starts_with("http://") {
+                return Err(CustomAPIError::validation(
+                    "image",
+                    "The image has to be an http(s) url!",
+                ));
+            }
+        }
+
+        let changes = ProfileChanges {
+            name: optional_text("name", &self.name, MAX_NAME_LENGTH)?,
+            username,
+            bio: optional_text("bio", &self.bio, MAX_BIO_LENGTH)?,
+            image: image.map(Option::unwrap_or_default),
+            telemetry: self.telemetry,
+        };
+
+        if changes.is_empty() {
+            return Err(CustomAPIError::BadClientData(
+                "Nothing to update!".to_string(),
+            ));
+        }
+
+        Ok(changes)
+    }
+}
+
+/// Updates the profile of the user making the request
+///
+/// ```json
+/// {
+///   "data": {
+///     "username": "translator",
+///     "telemetry": false
+///   }
+/// }
+/// ```
+#[patch("/api/v1/me")]
+pub async fn update_me(
+    data: web::Data<AppState>,
+    caller: Caller,
+    body: web::Json<RequestBody<UpdateProfileBody>>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let user_id = profile_owner(&caller)?;
+    let changes = body.data.changes()?;
+
+    get_active_user(data.db.as_ref(), user_id).await?;
+
+    if let Some(Some(username)) = &changes.username {
+        let taken = data
+            .db
+            .get_user_by_username(username)
+            .await?
+            .is_some_and(|user| user._id != user_id);
+
+        if taken {
+            return Err(CustomAPIError::Conflict(
+                "The username is already taken!".to_string(),
+            ));
+        }
+    }
+
+    let user = data
+        .db
+        .update_user_profile(user_id, changes)
+        .await?
+        .ok_or_else(|| CustomAPIError::NotFound("User not found!".to_string()))?;
+
+    Ok(HttpResponse::Ok().json(UserProfile::from(user)))
+}
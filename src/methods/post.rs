@@ -1,39 +1,114 @@
 use crate::{
-    db::{CollectionNames},
-    methods::{generate_api_key, RequestBody},
-    models::{Credits, Payment, Tokens},
+    audit::Audit,
+    config::KeyScope,
+    error::{ApiKeyError, CustomAPIError},
+    jwt::{self, Claims},
+    methods::{ensure_credits, ensure_report_access, generate_api_key, is_moderator, RequestBody},
+    middleware::auth::Caller,
+    inference::Language,
+    models::{
+        AuditAction, AuditTarget, Credits, Payment, Quota, QuotaUsage, ReportEvent, ReportEventKind,
+        Tokens,
+    },
+    store::{Charge, ReportChanges},
     AppState,
 };
-use actix_web::{post, web, HttpResponse, Responder};
-use mongodb::bson::{doc, oid::ObjectId};
-// use neura_labs_engine::{
-//     pipelines::translation::generate_translation,
-//     utils::{concatenate_strings, convert_strings_to_strs},
-//     Language,
-// };
+use actix_web::{http::header, post, web, HttpResponse};
+use mongodb::bson::oid::ObjectId;
+use std::time::Duration;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
 #[derive(Deserialize)]
 pub struct GetUserBody {
     pub id: String,
 }
 
-// #[derive(Deserialize, Clone)]
-// pub struct TranslateBody {
-//     /// The language to translate from
-//     source_language: Language,
-//     /// The language to translate to
-//     target_language: Language,
-//     /// The input to translate
-//     input_context: Vec<String>,
-//     concat: Option<bool>,
-//     id: String,
-// }
+#[derive(Deserialize, Clone)]
+pub struct TranslateBody {
+    /// The language to translate from, a code from `/api/v1/languages` or its name
+    source_language: String,
+    /// The language to translate to, a code from `/api/v1/languages` or its name
+    target_language: String,
+    /// The input to translate
+    input_context: Vec<String>,
+    concat: Option<bool>,
+    /// The user to charge. Users are always charged themselves, required for internal requests.
+    id: Option<String>,
+}
+
+impl TranslateBody {
+    /// Checks the body against the supported languages and returns the source and target language
+    ///
+    /// Runs before the caller's credits are looked at, so invalid requests are never charged.
+    fn validate(&self) -> Result<(Language, Language), CustomAPIError> {
+        let language = |field: &str, value: &str| {
+            value.parse::<Language>().map_err(|_| {
+                CustomAPIError::validation(
+                    field,
+                    format!(
+                        "'{}' is not a supported language, see /api/v1/languages",
+                        value
+                    ),
+                )
+            })
+        };
+
+        let source = language("source_language", &self.source_language)?;
+        let target = language("target_language", &self.target_language)?;
+
+        if source == target {
+            return Err(CustomAPIError::validation(
+                "target_language",
+                "The target language has to differ from the source language!",
+            ));
+        }
+
+        if self.input_context.is_empty() {
+            return Err(CustomAPIError::validation(
+                "input_context",
+                "There has to be at least one input to translate!",
+            ));
+        }
+
+        Ok((source, target))
+    }
+}
+
+#[derive(Serialize, Clone)]
+struct TranslateResponse {
+    data: Vec<String>,
+}
+
+/// Returns who a model request is charged to.
+///
+/// Users are always charged themselves and their token counts against its quota, internal
+/// requests name the user in the body.
+fn request_charge(
+    data: &AppState,
+    caller: &Caller,
+    id: Option<String>,
+) -> Result<Charge, CustomAPIError> {
+    let user_id = match (caller.user_id(), id) {
+        (Some(user_id), _) => user_id,
+        (None, Some(id)) => data.db.convert_to_object_id(id)?,
+        (None, None) => return Err(CustomAPIError::validation("id", "A user id is required!")),
+    };
 
-// #[derive(Serialize, Clone)]
-// struct TranslateResponse {
-//     data: Vec<String>,
-// }
+    Ok(Charge {
+        user_id,
+        token_id: caller.token_id(),
+    })
+}
+
+/// Concatenates the translations into a single string if requested
+fn join_output(output: Vec<String>, concat: Option<bool>) -> Vec<String> {
+    if concat.unwrap_or(false) {
+        vec![output.join(" ")]
+    } else {
+        output
+    }
+}
 
 /// Translates a given input from a source language to a target language
 ///
@@ -53,73 +128,336 @@ pub struct GetUserBody {
 ///   }
 /// }
 /// ```
-// #[post("/api/v1/translate")]
-// pub async fn translate(
-//     data: web::Data<AppState>,
-//     body: web::Json<RequestBody<TranslateBody>>,
-// ) -> impl Responder {
-//     let body_data = body.data.clone();
-
-//     let id = data.db.convert_to_object_id(body_data.id).unwrap();
-//     let can_run = data.db.process_credit_usage(id).await.unwrap();
-
-//     if !can_run {
-//         return HttpResponse::BadRequest().body("Insufficient credit amount!");
-//     }
-
-//     let result = actix_web::web::block(move || {
-//         let mut res = Vec::new();
-
-//         let data = generate_translation(
-//             body_data.source_language,
-//             body_data.target_language,
-//             convert_strings_to_strs(&body_data.input_context),
-//         )
-//         .unwrap();
-
-//         if body_data.concat.unwrap_or(false) {
-//             let c = concatenate_strings(&convert_strings_to_strs(&data));
-//             res.push(c)
-//         } else {
-//             res.extend(data);
-//         };
-
-//         Ok::<TranslateResponse, anyhow::Error>(TranslateResponse { data: res })
-//     })
-//     .await
-//     .unwrap();
-
-//     match result {
-//         Ok(res) => {
-//             return HttpResponse::Ok().json(res);
-//         }
-//         Err(e) => {
-//             return HttpResponse::InternalServerError().body(format!("{}", e));
-//         }
-//     };
-// }
-
-// todo - block users from accessing this endpoint
+#[post("/api/v1/translate")]
+pub async fn translate(
+    data: web::Data<AppState>,
+    caller: Caller,
+    body: web::Json<RequestBody<TranslateBody>>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let body_data = body.data.clone();
+    let (source, target) = body_data.validate()?;
+
+    let charge = request_charge(&data, &caller, body_data.id)?;
+
+    // Credits are only charged for finished translations, but there is no point in queueing
+    // work for a user who can not pay for it.
+    ensure_credits(data.db.as_ref(), charge).await?;
+
+    let output = data
+        .inference
+        .translate(source, target, body_data.input_context)
+        .await?;
+
+    if !data.db.process_credit_usage(charge).await? {
+        return Err(CustomAPIError::InsufficientCredits);
+    }
+
+    let output = join_output(output, body.data.concat);
+
+    Ok(HttpResponse::Ok().json(TranslateResponse { data: output }))
+}
+
+/// Formats a Server-Sent Event
+fn sse_event(name: &str, data: &impl Serialize) -> web::Bytes {
+    let data = serde_json::to_string(data).unwrap_or_else(|_| String::from("null"));
+    web::Bytes::from(format!("event: {}\ndata: {}\n\n", name, data))
+}
+
+/// Translates the input one item at a time and streams the results as Server-Sent Events
+///
+/// Takes the same body as `/api/v1/translate`, `concat` is ignored. Like every translation, the
+/// batch costs one credit, charged once the first item is translated. The stream stops at the first
+/// item that fails, and as soon as the client disconnects.
+///
+/// # Events
+/// - `translation`: `{"index": 0, "output": "..."}`
+/// - `progress`: `{"completed": 1, "total": 3}`
+/// - `error`: `{"index": 2, "code": "insufficient_credits", "message": "..."}`
+/// - `summary`: `{"completed": 2, "total": 3, "charged": 1}`, always the last event
+#[post("/api/v1/translate/stream")]
+pub async fn translate_stream(
+    data: web::Data<AppState>,
+    caller: Caller,
+    body: web::Json<RequestBody<TranslateBody>>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let body_data = body.into_inner().data;
+    let (source, target) = body_data.validate()?;
+
+    let charge = request_charge(&data, &caller, body_data.id)?;
+
+    ensure_credits(data.db.as_ref(), charge).await?;
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<web::Bytes>(16);
+
+    actix_web::rt::spawn(async move {
+        let total = body_data.input_context.len();
+        let mut completed = 0;
+        let mut charged = 0;
+
+        for (index, input) in body_data.input_context.into_iter().enumerate() {
+            // The client disconnected, stop before anything else is translated or charged.
+            if tx.is_closed() {
+                return;
+            }
+
+            let output = match data
+                .inference
+                .translate(source, target, vec![input])
+                .await
+            {
+                Ok(output) if charged > 0 => Ok(output),
+                Ok(output) => match data.db.process_credit_usage(charge).await {
+                    Ok(true) => {
+                        charged = 1;
+                        Ok(output)
+                    }
+                    Ok(false) => Err(CustomAPIError::InsufficientCredits),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            }
+            .map(|output| output.into_iter().next().unwrap_or_default());
+
+            let output = match output {
+                Ok(output) => output,
+                Err(e) => {
+                    let error = serde_json::json!({
+                        "index": index,
+                        "code": e.code(),
+                        "message": e.to_string(),
+                    });
+                    let _ = tx.send(sse_event("error", &error)).await;
+                    break;
+                }
+            };
+
+            completed += 1;
+
+            let translation = serde_json::json!({ "index": index, "output": output });
+            let progress = serde_json::json!({ "completed": completed, "total": total });
+
+            if tx.send(sse_event("translation", &translation)).await.is_err()
+                || tx.send(sse_event("progress", &progress)).await.is_err()
+            {
+                return;
+            }
+        }
+
+        let summary = serde_json::json!({
+            "completed": completed,
+            "total": total,
+            "charged": charged,
+        });
+        let _ = tx.send(sse_event("summary", &summary)).await;
+    });
+
+    let events = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv()
+            .await
+            .map(|event| (Ok::<_, actix_web::Error>(event), rx))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(events))
+}
+
+/// Queues a translation and answers right away with the job, poll `/api/v1/jobs/{id}` for the result
+///
+/// Takes the same body as `/api/v1/translate`. The credit is charged once the job completed,
+/// results are kept for `jobs.ttl_secs`.
+#[post("/api/v1/jobs")]
+pub async fn create_job(
+    data: web::Data<AppState>,
+    caller: Caller,
+    body: web::Json<RequestBody<TranslateBody>>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let body_data = body.into_inner().data;
+    let (source, target) = body_data.validate()?;
+
+    let charge = request_charge(&data, &caller, body_data.id)?;
+
+    ensure_credits(data.db.as_ref(), charge).await?;
+
+    let job = data.jobs.create(charge.user_id);
+    let id = job._id;
+
+    let jobs = data.jobs.clone();
+    let timeout = Duration::from_secs(data.config.jobs.timeout_secs);
+    let pending = data.inference.submit(timeout, move |engine| {
+        jobs.start(id);
+        engine.translate(source, target, &body_data.input_context)
+    });
+
+    let pending = match pending {
+        Ok(pending) => pending,
+        Err(e) => {
+            data.jobs.remove(id);
+            return Err(e);
+        }
+    };
+
+    let db = data.db.clone();
+    let jobs = data.jobs.clone();
+    let concat = body_data.concat;
+
+    actix_web::rt::spawn(async move {
+        let charged = match pending.await {
+            Ok(output) => match db.process_credit_usage(charge).await {
+                Ok(true) => Ok(output),
+                Ok(false) => Err(CustomAPIError::InsufficientCredits),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+
+        match charged {
+            Ok(output) => jobs.complete(id, join_output(output, concat)),
+            Err(e) => jobs.fail(id, &e),
+        }
+    });
+
+    Ok(HttpResponse::Accepted()
+        .insert_header((header::LOCATION, format!("/api/v1/jobs/{}", id.to_hex())))
+        .json(job))
+}
+
+/// Creates an api token for a user.
+///
+/// Only internal keys can create tokens, the auth middleware checks that they have the `tokens`
+/// scope.
 #[post("/api/v1/token")]
 pub async fn create_api_token(
     data: web::Data<AppState>,
+    caller: Caller,
+    audit: Audit,
     body: web::Json<RequestBody<String>>,
-) -> impl Responder {
-    let collection = data.db.get_collection::<Tokens>(CollectionNames::Tokens);
+) -> Result<HttpResponse, CustomAPIError> {
+    if !matches!(caller, Caller::Internal { .. }) {
+        return Err(CustomAPIError::Forbidden(
+            "Only internal keys can create api tokens!".to_string(),
+        ));
+    }
 
     let token = Tokens {
         _id: ObjectId::new(),
-        token: generate_api_key(),
-        created_at: data.db.get_current_time().unwrap(),
+        token: generate_api_key()?,
+        created_at: data.db.get_current_time()?,
         updated_at: None,
         tomestoned: false,
-        userId: data.db.convert_to_object_id(body.data.clone()).unwrap(),
+        userId: data.db.convert_to_object_id(body.data.clone())?,
+        expires_at: None,
+        quota: Quota::default(),
+        quota_usage: QuotaUsage::default(),
     };
 
-    match collection.insert_one(token, None).await {
-        Ok(_) => HttpResponse::Ok().body("ok"),
-        Err(e) => HttpResponse::InternalServerError().body(format!("{}", e)),
+    let token_id = token._id;
+    let user_id = token.userId;
+
+    data.db.create_api_token(token).await?;
+
+    audit
+        .record(
+            data.db.as_ref(),
+            AuditAction::TokenCreated,
+            AuditTarget::new("token", token_id),
+            None,
+            Some(json!({ "userId": user_id.to_hex() })),
+        )
+        .await;
+
+    Ok(HttpResponse::Ok().body("ok"))
+}
+
+#[derive(Deserialize, Clone)]
+pub struct AccessTokenBody {
+    /// Narrows down what the access token may be used for, every route if empty
+    #[serde(default)]
+    scopes: Vec<KeyScope>,
+}
+
+#[derive(Serialize)]
+struct AccessTokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    /// Seconds until the access token expires
+    expires_in: i64,
+    scopes: Vec<KeyScope>,
+}
+
+/// Exchanges the api token of the request for a short-lived access token
+#[post("/api/v1/auth/token")]
+pub async fn create_access_token(
+    data: web::Data<AppState>,
+    caller: Caller,
+    body: web::Json<RequestBody<AccessTokenBody>>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let key = data
+        .config
+        .jwt
+        .keys
+        .first()
+        .ok_or_else(|| CustomAPIError::NotFound("Access tokens are not enabled!".to_string()))?;
+
+    // Access tokens can not be exchanged again, so they can not outlive their api token.
+    let (user_id, token_id) = match caller {
+        Caller::User {
+            user_id,
+            token_id: Some(token_id),
+            scopes: None,
+            ..
+        } => (user_id, token_id),
+        _ => {
+            return Err(CustomAPIError::Forbidden(
+                "Only api tokens can be exchanged for access tokens!".to_string(),
+            ))
+        }
+    };
+
+    // The roles and the expiry are read again, the cached ones may be outdated. So may the cached
+    // token itself, it could have been revoked on another instance.
+    let user = data
+        .db
+        .get_user(user_id)
+        .await?
+        .ok_or_else(|| CustomAPIError::NotFound("User not found!".to_string()))?;
+    let token = data
+        .db
+        .get_api_token_by_id(token_id)
+        .await?
+        .ok_or_else(|| CustomAPIError::NotFound("Token not found!".to_string()))?;
+
+    if user.tomestoned || token.tomestoned {
+        return Err(CustomAPIError::InvalidApiKey(ApiKeyError::Revoked));
     }
+
+    let now = chrono::Utc::now().timestamp();
+    let mut exp = now.saturating_add(data.config.jwt.ttl_secs as i64);
+    if let Some(expires_at) = token.expires_at {
+        exp = exp.min(expires_at.timestamp_millis() / 1000);
+    }
+
+    let scopes = match &body.data.scopes {
+        scopes if scopes.is_empty() => vec![KeyScope::All],
+        scopes => scopes.clone(),
+    };
+
+    let claims = Claims {
+        sub: user_id.to_hex(),
+        tid: token_id.to_hex(),
+        roles: user.roles,
+        scopes: scopes.clone(),
+        iat: now,
+        exp,
+    };
+
+    Ok(HttpResponse::Ok().json(AccessTokenResponse {
+        access_token: jwt::sign(key, &claims),
+        token_type: "Bearer",
+        expires_in: exp - now,
+        scopes,
+    }))
 }
 
 #[derive(Deserialize, Clone)]
@@ -129,18 +467,35 @@ pub struct CreatePaymentBody {
     // pub payment_id: String,
 }
 
-// todo - block users from accessing this endpoint (https://github.com/actix/examples/blob/master/middleware/middleware/src/redirect.rs#L50)
+/// Records a payment and adds its amount to the credits of the user.
+///
+/// Only internal keys can record payments, the auth middleware checks that they have the
+/// `billing` scope.
 #[post("/api/v1/payment")]
 pub async fn create_user_payment(
     data: web::Data<AppState>,
+    caller: Caller,
+    audit: Audit,
     body: web::Json<RequestBody<CreatePaymentBody>>,
-) -> impl Responder {
+) -> Result<HttpResponse, CustomAPIError> {
+    if !matches!(caller, Caller::Internal { .. }) {
+        return Err(CustomAPIError::Forbidden(
+            "Only internal keys can record payments!".to_string(),
+        ));
+    }
+
     let body_data = body.data.clone();
-    let uid = data.db.convert_to_object_id(body.data.id.clone()).unwrap();
-    let payment_collection = data.db.get_collection::<Payment>(CollectionNames::Payment);
-    let credits_collection = data.db.get_collection::<Credits>(CollectionNames::Credits);
 
-    let now = data.db.get_current_time().unwrap();
+    if body_data.amount <= 0 {
+        return Err(CustomAPIError::validation(
+            "amount",
+            "The amount has to be greater than 0!",
+        ));
+    }
+
+    let uid = data.db.convert_to_object_id(body_data.id.clone())?;
+
+    let now = data.db.get_current_time()?;
 
     let payment = Payment {
         _id: ObjectId::new(),
@@ -155,32 +510,290 @@ pub async fn create_user_payment(
         credits_purchased: body_data.amount,
     };
 
-    payment_collection.insert_one(payment, None).await.unwrap();
+    let payment_id = payment._id;
+    let balance = |credits: Option<Credits>| {
+        json!({ "current_amount": credits.and_then(|c| c.current_amount).unwrap_or(0) })
+    };
+
+    let before = balance(data.db.get_credits(uid).await?);
+    data.db.create_payment(payment).await?;
+    data.db.add_credits(uid, body_data.amount).await?;
+    let after = balance(data.db.get_credits(uid).await?);
 
-    // check if the user has an existing credit record
-    let filter = doc! {"userId": uid};
+    audit
+        .record(
+            data.db.as_ref(),
+            AuditAction::PaymentCreated,
+            AuditTarget::new("payment", payment_id),
+            None,
+            Some(json!({ "userId": uid.to_hex(), "credits_purchased": body_data.amount })),
+        )
+        .await;
+    audit
+        .record(
+            data.db.as_ref(),
+            AuditAction::CreditsChanged,
+            AuditTarget::new("user", uid),
+            Some(before),
+            Some(after),
+        )
+        .await;
 
-    let credits = credits_collection.find_one(filter, None).await.unwrap();
+    Ok(HttpResponse::Ok().body("ok"))
+}
 
-    if credits.is_some() {
-        let credits = credits.unwrap();
-        let new_credits = credits.current_amount.unwrap_or(0) + body_data.amount;
-        let filter = doc! {"userId": uid};
-        let update = doc! {"$set": {"current_amount": new_credits}};
-        credits_collection
-            .update_one(filter, update, None)
-            .await
-            .unwrap();
-    } else {
-        let credits = Credits {
-            _id: ObjectId::new(),
-            userId: uid,
-            current_amount: Some(body_data.amount),
-            used_amount: Some(0),
-        };
+/// Checks that a required text field is not empty
+fn require_text(field: &str, value: &str) -> Result<(), CustomAPIError> {
+    if value.trim().is_empty() {
+        return Err(CustomAPIError::validation(
+            field,
+            format!("The {} can not be empty!", field),
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize, Clone)]
+pub struct CreateSystemReportBody {
+    pub title: String,
+    pub description: String,
+    /// The user the report is about. Defaults to the caller, required for internal requests. Only
+    /// moderators can file reports for other users.
+    pub user_id: Option<String>,
+}
+
+/// Files a new system report
+///
+/// # Example Request Body
+/// ```json
+/// {
+///   "data": {
+///     "title": "Translation failed",
+///     "description": "The engine returned an empty result"
+///   }
+/// }
+/// ```
+#[post("/api/v1/reports/system")]
+pub async fn create_system_report(
+    data: web::Data<AppState>,
+    caller: Caller,
+    body: web::Json<RequestBody<CreateSystemReportBody>>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let body_data = body.data.clone();
+
+    require_text("title", &body_data.title)?;
+    require_text("description", &body_data.description)?;
+
+    let user_id = match (body_data.user_id, caller.user_id()) {
+        (Some(id), own_id) => {
+            let id = data
+                .db
+                .convert_to_object_id(id)
+                .map_err(|_| CustomAPIError::validation("user_id", "Invalid user id!"))?;
+
+            if own_id != Some(id) && !is_moderator(data.db.as_ref(), &caller).await? {
+                return Err(CustomAPIError::Forbidden(
+                    "Only moderators can file reports for other users!".to_string(),
+                ));
+            }
+            id
+        }
+        (None, Some(id)) => id,
+        (None, None) => {
+            return Err(CustomAPIError::validation(
+                "user_id",
+                "A user id is required!",
+            ))
+        }
+    };
+
+    let report = data
+        .db
+        .create_system_report(body_data.title, user_id, body_data.description)
+        .await?;
+
+    Ok(HttpResponse::Created().json(report))
+}
+
+#[derive(Deserialize, Clone)]
+pub struct CreateUserReportBody {
+    pub title: String,
+    pub description: String,
+    /// The user handling the report. Defaults to the caller until a moderator reassigns it, only
+    /// moderators can assign it to someone else when filing it.
+    pub assigned_to_id: Option<String>,
+}
 
-        credits_collection.insert_one(credits, None).await.unwrap();
+/// Files a new user report
+#[post("/api/v1/reports/user")]
+pub async fn create_user_report(
+    data: web::Data<AppState>,
+    caller: Caller,
+    body: web::Json<RequestBody<CreateUserReportBody>>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let body_data = body.data.clone();
+
+    require_text("title", &body_data.title)?;
+    require_text("description", &body_data.description)?;
+
+    let assigned_to_id = match (body_data.assigned_to_id, caller.user_id()) {
+        (Some(id), own_id) => {
+            let id = data.db.convert_to_object_id(id).map_err(|_| {
+                CustomAPIError::validation("assigned_to_id", "Invalid assignee id!")
+            })?;
+
+            if own_id != Some(id) && !is_moderator(data.db.as_ref(), &caller).await? {
+                return Err(CustomAPIError::Forbidden(
+                    "Only moderators can assign reports!".to_string(),
+                ));
+            }
+            id
+        }
+        (None, Some(id)) => id,
+        (None, None) => {
+            return Err(CustomAPIError::validation(
+                "assigned_to_id",
+                "An assignee id is required!",
+            ))
+        }
+    };
+
+    let report = data
+        .db
+        .create_user_report(body_data.title, body_data.description, assigned_to_id)
+        .await?;
+
+    Ok(HttpResponse::Created().json(report))
+}
+
+#[derive(Deserialize, Clone)]
+pub struct CreateCommentBody {
+    pub text: String,
+}
+
+/// Builds a comment for the report timeline
+fn comment_event(
+    data: &AppState,
+    caller: &Caller,
+    text: String,
+) -> Result<ReportEvent, CustomAPIError> {
+    Ok(ReportEvent {
+        kind: ReportEventKind::Comment,
+        actorId: caller.user_id(),
+        text,
+        created_at: data.db.get_current_time()?,
+    })
+}
+
+/// Adds a comment to the timeline of a system report
+///
+/// Only the user the report belongs to and moderators can comment.
+#[post("/api/v1/reports/system/{id}/comments")]
+pub async fn create_system_report_comment(
+    data: web::Data<AppState>,
+    caller: Caller,
+    path: web::Path<String>,
+    body: web::Json<RequestBody<CreateCommentBody>>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let id = data.db.convert_to_object_id(path.into_inner())?;
+
+    require_text("text", &body.data.text)?;
+
+    let report = data
+        .db
+        .get_system_report(id)
+        .await?
+        .ok_or_else(|| CustomAPIError::NotFound("Report not found!".to_string()))?;
+
+    ensure_report_access(data.db.as_ref(), &caller, report.userId).await?;
+
+    let event = comment_event(&data, &caller, body.data.text.clone())?;
+
+    data.db
+        .update_system_report(id, ReportChanges::default(), Some(event.clone()))
+        .await?
+        .ok_or_else(|| CustomAPIError::NotFound("Report not found!".to_string()))?;
+
+    Ok(HttpResponse::Created().json(event))
+}
+
+/// Adds a comment to the timeline of a user report
+///
+/// Only the assigned user and moderators can comment.
+#[post("/api/v1/reports/user/{id}/comments")]
+pub async fn create_user_report_comment(
+    data: web::Data<AppState>,
+    caller: Caller,
+    path: web::Path<String>,
+    body: web::Json<RequestBody<CreateCommentBody>>,
+) -> Result<HttpResponse, CustomAPIError> {
+    let id = data.db.convert_to_object_id(path.into_inner())?;
+
+    require_text("text", &body.data.text)?;
+
+    let report = data
+        .db
+        .get_user_report(id)
+        .await?
+        .ok_or_else(|| CustomAPIError::NotFound("Report not found!".to_string()))?;
+
+    ensure_report_access(data.db.as_ref(), &caller, report.assignedToId).await?;
+
+    let event = comment_event(&data, &caller, body.data.text.clone())?;
+
+    data.db
+        .update_user_report(id, ReportChanges::default(), Some(event.clone()))
+        .await?
+        .ok_or_else(|| CustomAPIError::NotFound("Report not found!".to_string()))?;
+
+    Ok(HttpResponse::Created().json(event))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use actix_web::{http::StatusCode, test, App, HttpMessage};
+
+    async fn post(uri: &str, body: serde_json::Value) -> (StatusCode, serde_json::Value) {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_utils::unreachable_state().await))
+                .service(create_api_token)
+                .service(create_user_payment),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri(uri)
+            .set_json(body)
+            .to_request();
+        // The auth middleware is not part of this app, so the caller is set here.
+        req.extensions_mut().insert(Caller::Internal {
+            key_name: String::from("test"),
+        });
+        let res = test::call_service(&app, req).await;
+        let status = res.status();
+
+        (status, test::read_body_json(res).await)
     }
 
-    HttpResponse::Ok().body("ok")
+    #[actix_web::test]
+    async fn create_api_token_rejects_malformed_id() {
+        let (status, body) = post("/api/v1/token", json!({ "data": "not-an-object-id" })).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "validation_error");
+        assert_eq!(body["error"]["details"]["field"], "id");
+    }
+
+    #[actix_web::test]
+    async fn create_user_payment_rejects_malformed_id() {
+        let body = json!({ "data": { "id": "12345", "amount": 10 } });
+        let (status, body) = post("/api/v1/payment", body).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "validation_error");
+    }
 }
@@ -0,0 +1,110 @@
+// Prometheus metrics for the API. Everything is registered in the default registry
+// and exposed in the text format on the /metrics route.
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+lazy_static! {
+    pub static ref HTTP_REQUESTS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "http_requests_total",
+        "Number of HTTP requests handled, by route and status",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    pub static ref HTTP_REQUEST_DURATION_SECONDS: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "HTTP request latency in seconds, by route and status",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    pub static ref AUTH_OUTCOMES_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "auth_outcomes_total",
+        "Authentication results from the auth middleware",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref CREDIT_DEDUCTIONS_TOTAL: IntCounter = register_int_counter!(
+        "credit_deductions_total",
+        "Number of credits deducted from user balances"
+    )
+    .unwrap();
+    pub static ref MONGODB_OPERATION_DURATION_SECONDS: HistogramVec = register_histogram_vec!(
+        "mongodb_operation_duration_seconds",
+        "Latency of MongoDB helper operations in seconds",
+        &["operation"]
+    )
+    .unwrap();
+    pub static ref API_TOKEN_CACHE_SIZE: IntGauge = register_int_gauge!(
+        "api_token_cache_size",
+        "Number of API tokens held in the auth cache"
+    )
+    .unwrap();
+    pub static ref INFERENCE_QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "inference_queue_depth",
+        "Number of inference jobs waiting for a worker"
+    )
+    .unwrap();
+    pub static ref INFERENCE_REJECTIONS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "inference_rejections_total",
+        "Inference jobs that were not answered, by reason",
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref RATE_LIMITED_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "rate_limited_total",
+        "Requests rejected by the rate limiter, by the bucket that ran out",
+        &["scope"]
+    )
+    .unwrap();
+}
+
+/// The possible results of authenticating a request.
+pub enum AuthOutcome {
+    SuperKey,
+    CacheHit,
+    DbHit,
+    Session,
+    AccessToken,
+    Rejected,
+}
+
+impl AuthOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AuthOutcome::SuperKey => "super_key",
+            AuthOutcome::CacheHit => "cache_hit",
+            AuthOutcome::DbHit => "db_hit",
+            AuthOutcome::Session => "session",
+            AuthOutcome::AccessToken => "access_token",
+            AuthOutcome::Rejected => "rejected",
+        }
+    }
+}
+
+pub fn record_auth_outcome(outcome: AuthOutcome) {
+    AUTH_OUTCOMES_TOTAL
+        .with_label_values(&[outcome.as_str()])
+        .inc();
+}
+
+/// Starts a timer for a MongoDB operation.
+///
+/// The latency is recorded when the returned timer is dropped.
+pub fn time_db_operation(operation: &str) -> prometheus::HistogramTimer {
+    MONGODB_OPERATION_DURATION_SECONDS
+        .with_label_values(&[operation])
+        .start_timer()
+}
+
+/// Encodes every registered metric in the Prometheus text format
+pub fn gather() -> anyhow::Result<String> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+
+    encoder.encode(&prometheus::gather(), &mut buffer)?;
+
+    Ok(String::from_utf8(buffer)?)
+}
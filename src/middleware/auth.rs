@@ -4,15 +4,29 @@ use std::{
 };
 
 use actix_web::{
-    dev::{self, Service, ServiceRequest, ServiceResponse, Transform},
-    web, Error,
+    dev::{self, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::HeaderMap,
+    web, Error, FromRequest, HttpMessage, HttpRequest,
 };
 use futures_util::future::LocalBoxFuture;
 use lazy_static::lazy_static;
-use std::collections::HashSet;
-use std::sync::Mutex;
+use mongodb::bson::{self, oid::ObjectId};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
-use crate::AppState;
+use crate::{
+    audit::Audit,
+    config::{Config, InternalKey, KeyScope},
+    error::{ApiKeyError, CustomAPIError},
+    jwt::{self, Claims},
+    metrics::{self, AuthOutcome, API_TOKEN_CACHE_SIZE},
+    middleware::request_id,
+    models::{AuditAction, AuditTarget, UserRole},
+    store::Store,
+    AppState,
+};
 
 pub struct RequestHandler;
 
@@ -41,19 +55,306 @@ pub struct LoggingMiddleware<S> {
 }
 
 pub const AUTH_HEADER: &str = "Authorization";
+pub const API_KEY_HEADER: &str = "X-API-Key";
+pub const SESSION_HEADER: &str = "X-Session-Token";
+
+/// The cookies NextAuth keeps the dashboard session in, the secure one is used over https
+const SESSION_COOKIES: [&str; 2] = ["__Secure-next-auth.session-token", "next-auth.session-token"];
+
+/// Reads the API key of a request.
+///
+/// Accepts `Authorization: Bearer <key>`, the bare key in `Authorization` as older clients send it,
+/// and the `X-API-Key` header. `Authorization` wins when both are sent.
+pub fn api_key(headers: &HeaderMap) -> Result<String, ApiKeyError> {
+    let authorization = headers.get(AUTH_HEADER);
+    let (value, from_authorization) = match (authorization, headers.get(API_KEY_HEADER)) {
+        (Some(value), _) => (value, true),
+        (None, Some(value)) => (value, false),
+        (None, None) => return Err(ApiKeyError::Missing),
+    };
+
+    // Header values that are not visible ASCII can not be a valid key.
+    let value = value.to_str().map_err(|_| ApiKeyError::Malformed)?.trim();
+
+    let key = match value.split_once(' ') {
+        Some((scheme, key)) if from_authorization && scheme.eq_ignore_ascii_case("bearer") => {
+            key.trim()
+        }
+        Some(_) => return Err(ApiKeyError::Malformed),
+        None if value.eq_ignore_ascii_case("bearer") => return Err(ApiKeyError::Malformed),
+        None => value,
+    };
+
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return Err(ApiKeyError::Malformed);
+    }
+
+    Ok(key.to_string())
+}
+
+/// Reads the dashboard session token of a request from the `X-Session-Token` header or the
+/// NextAuth session cookie
+pub fn session_token(req: &ServiceRequest) -> Option<String> {
+    if let Some(value) = req.headers().get(SESSION_HEADER) {
+        return value.to_str().ok().map(String::from);
+    }
+
+    SESSION_COOKIES
+        .iter()
+        .find_map(|name| req.cookie(name))
+        .map(|cookie| cookie.value().to_string())
+}
+
+/// The authenticated caller of a request.
+///
+/// The auth middleware stores this in the request extensions, handlers can take it as an argument.
+#[derive(Clone, Debug)]
+pub enum Caller {
+    /// An internal system using the super key or one of the internal keys
+    Internal {
+        /// The name of the key, `super_key` for the super key
+        key_name: String,
+    },
+    /// A user authenticated with one of their api tokens, an access token or a dashboard session
+    User {
+        user_id: ObjectId,
+        /// The api token the request was made with, none for dashboard sessions
+        token_id: Option<ObjectId>,
+        /// The roles the user had when the token was first seen
+        roles: Vec<UserRole>,
+        /// The scopes of an access token. Api tokens and sessions may use every route, they have
+        /// none.
+        scopes: Option<Vec<KeyScope>>,
+    },
+}
+
+impl Caller {
+    /// Returns the id of the user making the request, if any
+    pub fn user_id(&self) -> Option<ObjectId> {
+        match self {
+            Caller::Internal { .. } => None,
+            Caller::User { user_id, .. } => Some(*user_id),
+        }
+    }
+
+    /// Returns the id of the api token used for the request, if any
+    pub fn token_id(&self) -> Option<ObjectId> {
+        match self {
+            Caller::Internal { .. } => None,
+            Caller::User { token_id, .. } => *token_id,
+        }
+    }
+}
+
+impl FromRequest for Caller {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Caller>()
+                .cloned()
+                .ok_or_else(|| CustomAPIError::Unauthorized("Unauthorized Request!".to_string()).into()),
+        )
+    }
+}
+
+/// Finds the internal key the token belongs to.
+///
+/// Every key is compared in constant time and all of them are checked, so the response time does
+/// not tell how much of a key was guessed or which key matched.
+pub(crate) fn find_internal_key(config: &Config, token: &str) -> Option<InternalKey> {
+    let matches = |key: &str| {
+        !key.is_empty()
+            && ring::constant_time::verify_slices_are_equal(key.as_bytes(), token.as_bytes())
+                .is_ok()
+    };
+
+    let mut found = None;
+
+    if matches(&config.super_key) {
+        found = Some(InternalKey::super_key(&config.super_key));
+    }
 
+    for key in &config.internal_keys {
+        if matches(&key.key) {
+            found = Some(key.clone());
+        }
+    }
+
+    found
+}
+
+/// The scope an internal key or access token needs for a route.
+///
+/// Public routes need none, api routes that are not listed need the `all` scope.
+fn required_scope(path: &str) -> Option<KeyScope> {
+    let path = path.trim_end_matches('/');
+    let under = |prefix: &str| {
+        path == prefix
+            || path
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.starts_with('/'))
+    };
+
+    if under("/api/v1/translate") || under("/api/v1/jobs") {
+        Some(KeyScope::Translate)
+    } else if under("/api/v1/token") {
+        Some(KeyScope::Tokens)
+    } else if under("/api/v1/payment") || under("/api/v1/credits") || under("/api/v1/quotas") {
+        Some(KeyScope::Billing)
+    } else if under("/api/v1/reports") {
+        Some(KeyScope::Reports)
+    } else if under("/api/v1/stats") || under("/api/v1/languages") || !under("/api") {
+        None
+    } else {
+        Some(KeyScope::All)
+    }
+}
+
+/// Writes an audit log entry for a request made with an internal key
+async fn audit_internal_key(
+    db: &dyn Store,
+    key: &InternalKey,
+    req: &ServiceRequest,
+    ip: Option<IpAddr>,
+    outcome: &str,
+) {
+    let caller = Caller::Internal {
+        key_name: key.name.clone(),
+    };
+
+    Audit::new(&caller, ip.map(|ip| ip.to_string()), request_id::current())
+        .record(
+            db,
+            AuditAction::InternalKeyUsed,
+            AuditTarget::new("route", format!("{} {}", req.method(), req.path())),
+            None,
+            Some(serde_json::json!({ "outcome": outcome })),
+        )
+        .await;
+}
+
+/// Resolves a dashboard session to its user.
+///
+/// Sessions are not cached, signing out on the dashboard deletes them and has to take effect at
+/// once.
+async fn session_caller(db: &dyn Store, session_token: &str) -> Result<Caller, CustomAPIError> {
+    let unknown = || CustomAPIError::Unauthorized("Unknown session!".to_string());
+
+    let session = db.get_session(session_token).await?.ok_or_else(unknown)?;
+    if session.expires <= bson::DateTime::now() {
+        return Err(CustomAPIError::Unauthorized("The session has expired!".to_string()));
+    }
+
+    match db.get_user(session.userId).await? {
+        Some(user) if !user.tomestoned => Ok(Caller::User {
+            user_id: user._id,
+            token_id: None,
+            roles: user.roles,
+            scopes: None,
+        }),
+        _ => Err(unknown()),
+    }
+}
+
+/// The caller of a request made with a verified access token
+fn access_token_caller(claims: Claims) -> Result<Caller, ApiKeyError> {
+    let id = |id: &str| ObjectId::parse_str(id).map_err(|_| ApiKeyError::Malformed);
+
+    Ok(Caller::User {
+        user_id: id(&claims.sub)?,
+        token_id: Some(id(&claims.tid)?),
+        roles: claims.roles,
+        scopes: Some(claims.scopes),
+    })
+}
+
+/// The user that owns a cached token
 #[derive(Clone)]
-struct ApiToken {
-    exist: bool,
-    value: String,
+struct CachedToken {
+    token_id: ObjectId,
+    user_id: ObjectId,
+    roles: Vec<UserRole>,
+    expires_at: Option<bson::DateTime>,
 }
 
-// Define a type alias for the hash set.
-type ApiTokenCache = HashSet<String>;
+// Define a type alias for the token cache. Each cached token maps to the user that owns it.
+type ApiTokenCache = HashMap<String, CachedToken>;
+
+/// How long access tokens trust a cached answer to whether their user was deleted
+const USER_STATUS_TTL: Duration = Duration::from_secs(60);
+
+/// Whether a user was deleted, as last read from the database
+#[derive(Clone, Copy)]
+struct CachedUserStatus {
+    deleted: bool,
+    checked_at: Instant,
+}
 
 lazy_static! {
-    // Create a mutex-guarded global instance of the hash set.
+    // Create a mutex-guarded global instance of the token cache.
     static ref API_TOKEN_CACHE: Mutex<ApiTokenCache> = Mutex::new(ApiTokenCache::new());
+    static ref USER_STATUS_CACHE: Mutex<HashMap<ObjectId, CachedUserStatus>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Locks the token cache.
+///
+/// The cache only holds plain entries, so it is still usable if a thread panicked while holding it.
+fn token_cache() -> MutexGuard<'static, ApiTokenCache> {
+    API_TOKEN_CACHE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Locks the user status cache, which only holds plain entries like the token cache
+fn user_status_cache() -> MutexGuard<'static, HashMap<ObjectId, CachedUserStatus>> {
+    USER_STATUS_CACHE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Checks if the user was deleted.
+///
+/// Access tokens and cached api tokens are not looked up in the database, so this is what stops
+/// them once their user is deleted. The answer is cached for `USER_STATUS_TTL`, deleting an account
+/// on another instance takes up to that long to reach this one.
+async fn is_deleted_user(db: &dyn Store, user_id: ObjectId) -> Result<bool, CustomAPIError> {
+    let cached = user_status_cache().get(&user_id).copied();
+    if let Some(cached) = cached.filter(|cached| cached.checked_at.elapsed() < USER_STATUS_TTL) {
+        return Ok(cached.deleted);
+    }
+
+    let deleted = match db.get_user(user_id).await? {
+        Some(user) => user.tomestoned,
+        None => true,
+    };
+
+    let mut cache = user_status_cache();
+    cache.retain(|_, cached| cached.checked_at.elapsed() < USER_STATUS_TTL);
+    cache.insert(
+        user_id,
+        CachedUserStatus {
+            deleted,
+            checked_at: Instant::now(),
+        },
+    );
+
+    Ok(deleted)
+}
+
+/// Drops the cached tokens of a user, so revoked tokens are looked up again on their next use.
+/// Their access tokens are checked against the database again as well.
+///
+/// Only clears the cache of this instance.
+pub fn forget_user_tokens(user_id: ObjectId) {
+    let mut cache = token_cache();
+    cache.retain(|_, cached| cached.user_id != user_id);
+    API_TOKEN_CACHE_SIZE.set(cache.len() as i64);
+
+    user_status_cache().remove(&user_id);
 }
 
 impl<S, B> Service<ServiceRequest> for LoggingMiddleware<S>
@@ -72,47 +373,306 @@ where
         let svc = self.service.clone();
 
         // Get the app state
-        let data = req.app_data::<web::Data<AppState>>().unwrap().clone();
-
-        // get the request headers and check if the api key is present
-        let token = match req.headers().get(AUTH_HEADER) {
-            Some(t) => ApiToken {
-                exist: true,
-                value: t.to_str().unwrap().to_string(),
-            },
-            None => ApiToken {
-                exist: false,
-                value: String::from(""),
-            },
-        };
+        let data = req.app_data::<web::Data<AppState>>().cloned();
+
+        // get the request headers and check if the api key is present.
+        let token = api_key(req.headers());
+        let session = session_token(&req);
 
         Box::pin(async move {
-            let res = svc.call(req).await?;
+            let data = match data {
+                Some(data) => data,
+                None => return Err(CustomAPIError::InternalError.into()),
+            };
+
+            let token = match (token, session) {
+                (Ok(token), _) => token,
+                // The web dashboard sends its session instead of an api key.
+                (Err(ApiKeyError::Missing), Some(session)) => {
+                    let caller = match session_caller(data.db.as_ref(), &session).await {
+                        Ok(caller) => caller,
+                        Err(e) => {
+                            if let CustomAPIError::Unauthorized(_) = e {
+                                metrics::record_auth_outcome(AuthOutcome::Rejected);
+                            }
+                            return Err(e.into());
+                        }
+                    };
 
-            if !token.exist {
-                return Err(actix_web::error::ErrorUnauthorized("Unauthorized Request!"));
+                    metrics::record_auth_outcome(AuthOutcome::Session);
+                    req.extensions_mut().insert(caller);
+                    return svc.call(req).await;
+                }
+                (Err(e), _) => {
+                    metrics::record_auth_outcome(AuthOutcome::Rejected);
+                    return Err(CustomAPIError::InvalidApiKey(e).into());
+                }
+            };
+
+            // Access tokens are verified by their signature, only the deletion of their user is
+            // looked up.
+            if !data.config.jwt.keys.is_empty() && jwt::is_jwt(&token) {
+                let now = chrono::Utc::now().timestamp();
+                let caller = match jwt::verify(&data.config.jwt.keys, &token, now)
+                    .and_then(access_token_caller)
+                {
+                    Ok(caller) => caller,
+                    Err(e) => {
+                        metrics::record_auth_outcome(AuthOutcome::Rejected);
+                        return Err(CustomAPIError::InvalidApiKey(e).into());
+                    }
+                };
+
+                if let Some(user_id) = caller.user_id() {
+                    if is_deleted_user(data.db.as_ref(), user_id).await? {
+                        metrics::record_auth_outcome(AuthOutcome::Rejected);
+                        return Err(CustomAPIError::InvalidApiKey(ApiKeyError::Revoked).into());
+                    }
+                }
+
+                if let (Some(scope), Caller::User { scopes: Some(scopes), .. }) =
+                    (required_scope(req.path()), &caller)
+                {
+                    if !scope.granted_by(scopes) {
+                        metrics::record_auth_outcome(AuthOutcome::Rejected);
+                        return Err(CustomAPIError::Forbidden(format!(
+                            "The access token lacks the {:?} scope!",
+                            scope
+                        ))
+                        .into());
+                    }
+                }
+
+                metrics::record_auth_outcome(AuthOutcome::AccessToken);
+                req.extensions_mut().insert(caller);
+                return svc.call(req).await;
             }
 
             // Used for internal api request from other systems.
-            // Any request with the super key will be accepted.
-            if token.value == crate::utils::env().unwrap().super_key {
-                return Ok(res);
+            // Internal keys are only accepted from their allowed addresses and for their scopes.
+            if let Some(key) = find_internal_key(&data.config, &token) {
+                let ip = req.peer_addr().map(|addr| addr.ip());
+                let allowed_ip = match ip {
+                    Some(ip) => key.allows_ip(ip),
+                    None => key.allowed_ips.is_empty(),
+                };
+
+                if !allowed_ip {
+                    audit_internal_key(data.db.as_ref(), &key, &req, ip, "denied_ip").await;
+                    metrics::record_auth_outcome(AuthOutcome::Rejected);
+                    return Err(CustomAPIError::Forbidden(format!(
+                        "The internal key {} can not be used from this address!",
+                        key.name
+                    ))
+                    .into());
+                }
+
+                if let Some(scope) = required_scope(req.path()) {
+                    if !key.has_scope(scope) {
+                        audit_internal_key(data.db.as_ref(), &key, &req, ip, "denied_scope").await;
+                        metrics::record_auth_outcome(AuthOutcome::Rejected);
+                        return Err(CustomAPIError::Forbidden(format!(
+                            "The internal key {} lacks the {:?} scope!",
+                            key.name, scope
+                        ))
+                        .into());
+                    }
+                }
+
+                audit_internal_key(data.db.as_ref(), &key, &req, ip, "granted").await;
+                metrics::record_auth_outcome(AuthOutcome::SuperKey);
+                req.extensions_mut().insert(Caller::Internal { key_name: key.name });
+                return svc.call(req).await;
             }
 
             // Check if the API token is already in the cache.
-            let mut cache = API_TOKEN_CACHE.lock().unwrap();
+            // The lock is released before querying the database so it is never held across an await.
+            let cached = token_cache().get(&token).cloned();
+
+            let cached = match cached {
+                // The token may be cached since before its user was deleted on another instance.
+                Some(cached) if is_deleted_user(data.db.as_ref(), cached.user_id).await? => {
+                    let mut cache = token_cache();
+                    cache.remove(&token);
+                    API_TOKEN_CACHE_SIZE.set(cache.len() as i64);
+
+                    metrics::record_auth_outcome(AuthOutcome::Rejected);
+                    return Err(CustomAPIError::InvalidApiKey(ApiKeyError::Revoked).into());
+                }
+                Some(cached) => {
+                    metrics::record_auth_outcome(AuthOutcome::CacheHit);
+                    cached
+                }
+                None => {
+                    // Token not found in cache, so check the database and add to cache if found.
+                    let api_token = match data.db.get_api_token(&token).await? {
+                        Some(api_token) => api_token,
+                        None => {
+                            metrics::record_auth_outcome(AuthOutcome::Rejected);
+                            return Err(CustomAPIError::InvalidApiKey(ApiKeyError::Unknown).into());
+                        }
+                    };
+
+                    // Revoked tokens are not cached, revoking a token clears it from the cache.
+                    if api_token.tomestoned {
+                        metrics::record_auth_outcome(AuthOutcome::Rejected);
+                        return Err(CustomAPIError::InvalidApiKey(ApiKeyError::Revoked).into());
+                    }
+
+                    // Roles are cached with the token, the rate limits depend on them. Tokens of
+                    // deleted users are rejected even if they were not revoked.
+                    let roles = match data.db.get_user(api_token.userId).await? {
+                        Some(user) if !user.tomestoned => user.roles,
+                        _ => {
+                            metrics::record_auth_outcome(AuthOutcome::Rejected);
+                            return Err(CustomAPIError::InvalidApiKey(ApiKeyError::Revoked).into());
+                        }
+                    };
+                    let cached = CachedToken {
+                        token_id: api_token._id,
+                        user_id: api_token.userId,
+                        roles,
+                        expires_at: api_token.expires_at,
+                    };
 
-            if !cache.contains(&token.value) {
-                // Token not found in cache, so check the database and add to cache if found.
-                let authorized_request = data.db.has_api_key(token.clone().value).await.unwrap();
-                if !authorized_request {
-                    return Err(actix_web::error::ErrorUnauthorized("Unauthorized Request!"));
+                    let mut cache = token_cache();
+                    cache.insert(token.clone(), cached.clone());
+                    API_TOKEN_CACHE_SIZE.set(cache.len() as i64);
+
+                    metrics::record_auth_outcome(AuthOutcome::DbHit);
+                    cached
                 }
-                cache.insert(token.value.clone());
+            };
+
+            // Cached tokens can expire while they sit in the cache, so this is checked every time.
+            if cached
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= bson::DateTime::now())
+            {
+                let mut cache = token_cache();
+                cache.remove(&token);
+                API_TOKEN_CACHE_SIZE.set(cache.len() as i64);
+
+                metrics::record_auth_outcome(AuthOutcome::Rejected);
+                return Err(CustomAPIError::InvalidApiKey(ApiKeyError::Expired).into());
             }
 
-            // everything is fine, return the response
-            Ok(res)
+            // everything is fine, run the request
+            req.extensions_mut().insert(Caller::User {
+                user_id: cached.user_id,
+                token_id: Some(cached.token_id),
+                roles: cached.roles,
+                scopes: None,
+            });
+            svc.call(req).await
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{methods::get::index, middleware::request_id::RequestIdHandler, test_utils};
+    use actix_web::{http::header::HeaderValue, http::StatusCode, test, App};
+
+    async fn call(req: test::TestRequest) -> (StatusCode, Option<String>, serde_json::Value) {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_utils::unreachable_state().await))
+                .wrap(RequestHandler)
+                .wrap(RequestIdHandler)
+                .service(index),
+        )
+        .await;
+
+        // Middleware errors are rendered by the server, so they are turned into responses here.
+        let res = match test::try_call_service(&app, req.to_request()).await {
+            Ok(res) => res.into_parts().1,
+            Err(e) => e.error_response(),
+        };
+        let status = res.status();
+        let challenge = res
+            .headers()
+            .get("www-authenticate")
+            .map(|value| value.to_str().unwrap().to_string());
+        let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+
+        (status, challenge, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn maps_routes_to_scopes() {
+        assert_eq!(required_scope("/api/v1/translate/stream"), Some(KeyScope::Translate));
+        assert_eq!(required_scope("/api/v1/token"), Some(KeyScope::Tokens));
+        assert_eq!(required_scope("/api/v1/reports/user/1"), Some(KeyScope::Reports));
+        assert_eq!(required_scope("/api/v1/tokens"), Some(KeyScope::All));
+        assert_eq!(required_scope("/api/v1/languages"), None);
+        assert_eq!(required_scope("/health"), None);
+    }
+
+    #[actix_web::test]
+    async fn reads_bearer_and_api_key_headers() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(name.parse().unwrap(), HeaderValue::from_static(value));
+            }
+            headers
+        };
+
+        assert_eq!(api_key(&headers(&[(AUTH_HEADER, "Bearer abc")])).unwrap(), "abc");
+        assert_eq!(api_key(&headers(&[(AUTH_HEADER, "bearer  abc ")])).unwrap(), "abc");
+        assert_eq!(api_key(&headers(&[(AUTH_HEADER, "abc")])).unwrap(), "abc");
+        assert_eq!(api_key(&headers(&[(API_KEY_HEADER, "abc")])).unwrap(), "abc");
+        assert_eq!(
+            api_key(&headers(&[(AUTH_HEADER, "Bearer abc"), (API_KEY_HEADER, "def")])).unwrap(),
+            "abc"
+        );
+
+        assert_eq!(api_key(&headers(&[])), Err(ApiKeyError::Missing));
+        for value in ["Basic abc", "Bearer", "Bearer a b", ""] {
+            assert_eq!(api_key(&headers(&[(AUTH_HEADER, value)])), Err(ApiKeyError::Malformed));
+        }
+        assert_eq!(
+            api_key(&headers(&[(API_KEY_HEADER, "Bearer abc")])),
+            Err(ApiKeyError::Malformed)
+        );
+    }
+
+    #[actix_web::test]
+    async fn rejects_missing_header() {
+        let (status, challenge, body) = call(test::TestRequest::get().uri("/")).await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"]["code"], "unauthorized");
+        assert_eq!(body["error"]["details"]["reason"], "missing");
+        assert_eq!(challenge.as_deref(), Some("Bearer realm=\"neura-labs-api\""));
+    }
+
+    #[actix_web::test]
+    async fn rejects_non_ascii_header() {
+        let header = HeaderValue::from_bytes(b"t\xf6ken").unwrap();
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header((AUTH_HEADER, header));
+
+        let (status, challenge, body) = call(req).await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"]["message"], "Malformed Authorization header!");
+        assert_eq!(body["error"]["details"]["reason"], "malformed");
+        assert!(challenge.unwrap().contains("error=\"invalid_request\""));
+    }
+
+    #[actix_web::test]
+    async fn reports_unreachable_database() {
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header((AUTH_HEADER, "unknown-token"));
+
+        let (status, _, body) = call(req).await;
+
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["error"]["code"], "internal_error");
+    }
+}
@@ -0,0 +1,352 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    future::{ready, Ready},
+    panic::AssertUnwindSafe,
+    rc::Rc,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    dev::{self, Service, ServiceRequest, ServiceResponse, Transform},
+    web, Error,
+};
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use lazy_static::lazy_static;
+use ring::digest;
+
+use crate::{error::CustomAPIError, store::Store, AppState};
+
+/// How often an error has to happen before a report is opened for it
+pub const REPORT_THRESHOLD: i64 = 3;
+
+/// How long occurrences are collected in memory before they are written to the report
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Error groups kept in memory, the least recently seen ones are dropped beyond this
+const MAX_ERROR_GROUPS: usize = 1_000;
+
+/// Groups with nothing left to write are dropped once they were not seen for this long
+const GROUP_IDLE_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Opens or updates a `SystemReport` when a route keeps failing with a server error.
+///
+/// Panics in handlers are caught and answered with a 500 so they are reported as well.
+pub struct ErrorReporter;
+
+impl<S: 'static, B> Transform<S, ServiceRequest> for ErrorReporter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ErrorReporterMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ErrorReporterMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct ErrorReporterMiddleware<S> {
+    service: Rc<S>,
+}
+
+/// The occurrences of a single error group
+struct ErrorGroup {
+    /// Occurrences not yet written to the database
+    pending: i64,
+    total: i64,
+    last_flush: Option<Instant>,
+    last_seen: Instant,
+    /// The title and description a new report is filed with
+    title: String,
+    description: String,
+}
+
+impl ErrorGroup {
+    /// Groups that are reported and still have occurrences to write
+    fn has_pending_report(&self) -> bool {
+        self.total >= REPORT_THRESHOLD && self.pending > 0
+    }
+}
+
+/// Occurrences of an error group that are due to be written to its report
+pub struct PendingError {
+    pub fingerprint: String,
+    pub title: String,
+    pub description: String,
+    pub occurrences: i64,
+}
+
+lazy_static! {
+    static ref ERROR_GROUPS: Mutex<HashMap<String, ErrorGroup>> = Mutex::new(HashMap::new());
+}
+
+/// Locks the error groups, they only hold counters and are still usable after a panic
+fn error_groups() -> MutexGuard<'static, HashMap<String, ErrorGroup>> {
+    ERROR_GROUPS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Replaces ids in an error message, so errors that only differ by ids end up in the same group.
+///
+/// Words with a digit, like numbers, object ids and UUIDs, and long hex words are replaced. Dashes
+/// and underscores are part of a word, so a UUID is replaced as a whole.
+fn normalize(message: &str) -> String {
+    let is_id = |word: &str| {
+        word.chars().any(|c| c.is_ascii_digit())
+            || (word.len() >= 8 && word.chars().all(|c| c.is_ascii_hexdigit()))
+    };
+
+    let mut normalized = String::with_capacity(message.len());
+    let mut word = String::new();
+
+    for c in message.chars().chain(std::iter::once(' ')) {
+        if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+            word.push(c);
+            continue;
+        }
+
+        if is_id(&word) {
+            normalized.push('#');
+        } else {
+            normalized.push_str(&word);
+        }
+        word.clear();
+        normalized.push(c);
+    }
+
+    normalized.pop();
+    normalized
+}
+
+/// Builds a stable fingerprint for an error from the SHA-256 of its route and normalized message
+fn fingerprint(method: &str, route: &str, status: u16, message: &str) -> String {
+    let input = format!("{}\n{}\n{}\n{}", method, route, status, normalize(message));
+    let hash = digest::digest(&digest::SHA256, input.as_bytes());
+
+    hash.as_ref()[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("unknown panic")
+    }
+}
+
+/// Counts an error and returns the occurrences that should be written to the report now, if any
+fn track(fingerprint: &str, title: &str, description: &str) -> Option<i64> {
+    let mut groups = error_groups();
+    let now = Instant::now();
+
+    if groups.len() >= MAX_ERROR_GROUPS && !groups.contains_key(fingerprint) {
+        // Groups without occurrences to write go first, then the least recently seen.
+        let evicted = groups
+            .iter()
+            .min_by_key(|(_, group)| (group.has_pending_report(), group.last_seen))
+            .map(|(fingerprint, _)| fingerprint.clone());
+        if let Some(evicted) = evicted {
+            groups.remove(&evicted);
+        }
+    }
+
+    let group = groups.entry(fingerprint.to_string()).or_insert(ErrorGroup {
+        pending: 0,
+        total: 0,
+        last_flush: None,
+        last_seen: now,
+        title: title.to_string(),
+        description: description.to_string(),
+    });
+
+    group.pending += 1;
+    group.total += 1;
+    group.last_seen = now;
+
+    if group.total < REPORT_THRESHOLD {
+        return None;
+    }
+
+    let due = match group.last_flush {
+        Some(last_flush) => last_flush.elapsed() >= FLUSH_INTERVAL,
+        None => true,
+    };
+
+    if !due {
+        return None;
+    }
+
+    group.last_flush = Some(now);
+    Some(std::mem::take(&mut group.pending))
+}
+
+/// Takes the occurrences of every reported group that were not written yet, and drops the groups
+/// that have been idle for a while
+pub fn take_pending() -> Vec<PendingError> {
+    let mut groups = error_groups();
+    let now = Instant::now();
+
+    groups.retain(|_, group| {
+        group.has_pending_report() || now.duration_since(group.last_seen) < GROUP_IDLE_TIMEOUT
+    });
+
+    groups
+        .iter_mut()
+        .filter(|(_, group)| group.has_pending_report())
+        .map(|(fingerprint, group)| {
+            group.last_flush = Some(now);
+
+            PendingError {
+                fingerprint: fingerprint.clone(),
+                title: group.title.clone(),
+                description: group.description.clone(),
+                occurrences: std::mem::take(&mut group.pending),
+            }
+        })
+        .collect()
+}
+
+/// Writes the occurrences that were collected but not written yet
+pub async fn flush_pending(db: &dyn Store) {
+    for error in take_pending() {
+        let PendingError {
+            fingerprint,
+            title,
+            description,
+            occurrences,
+        } = error;
+
+        if let Err(e) = db
+            .record_server_error(fingerprint, title, description, occurrences)
+            .await
+        {
+            log::error!("Failed to record server error: {}", e);
+        }
+    }
+}
+
+/// Writes the collected occurrences every `FLUSH_INTERVAL`, so they are not lost when an error
+/// stops happening
+pub fn spawn_flush_task(db: Arc<dyn Store>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(FLUSH_INTERVAL);
+
+        loop {
+            interval.tick().await;
+            flush_pending(db.as_ref()).await;
+        }
+    });
+}
+
+impl<S, B> Service<ServiceRequest> for ErrorReporterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let svc = self.service.clone();
+
+        let data = req.app_data::<web::Data<AppState>>().cloned();
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| String::from("unmatched"));
+        let method = req.method().to_string();
+
+        Box::pin(async move {
+            // The panic message is only used for the report, clients get a generic internal error.
+            let (res, panic) = match AssertUnwindSafe(svc.call(req)).catch_unwind().await {
+                Ok(res) => (res, None),
+                Err(panic) => {
+                    let message = format!("panic: {}", panic_message(panic.as_ref()));
+                    log::error!("{} {} {}", method, route, message);
+                    (Err(CustomAPIError::InternalError.into()), Some(message))
+                }
+            };
+
+            let (status, message) = match &res {
+                Ok(res) => (
+                    res.status(),
+                    res.response()
+                        .error()
+                        .map(|e| e.to_string())
+                        .unwrap_or_default(),
+                ),
+                Err(e) => (e.as_response_error().status_code(), e.to_string()),
+            };
+            let message = panic.unwrap_or(message);
+
+            if !status.is_server_error() {
+                return res;
+            }
+
+            let fingerprint = fingerprint(&method, &route, status.as_u16(), &message);
+            let title = format!("{} {} {}", status.as_u16(), method, route);
+            let description = if message.is_empty() {
+                format!("{} returned {}", route, status)
+            } else {
+                message
+            };
+
+            let occurrences = track(&fingerprint, &title, &description);
+
+            if let (Some(occurrences), Some(data)) = (occurrences, data) {
+                actix_web::rt::spawn(async move {
+                    if let Err(e) = data
+                        .db
+                        .record_server_error(fingerprint, title, description, occurrences)
+                        .await
+                    {
+                        log::error!("Failed to record server error: {}", e);
+                    }
+                });
+            }
+
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_errors_that_only_differ_by_ids() {
+        assert_eq!(
+            normalize("user 6458f3c2a7d1e2b4c9f01a3e not found after 3 tries"),
+            "user # not found after # tries"
+        );
+        assert_eq!(
+            normalize("job 550e8400-e29b-41d4-a716-446655440000 deadbeefcafe failed"),
+            "job # # failed"
+        );
+
+        let a = fingerprint("GET", "/api/v1/jobs/{id}", 500, "job 550e8400-e29b-41d4 failed");
+        let b = fingerprint("GET", "/api/v1/jobs/{id}", 500, "job 7f3a9c21-aaaa-11ee failed");
+        assert_eq!(a, b);
+        assert_eq!(a.len(), 16);
+        assert_ne!(a, fingerprint("GET", "/api/v1/jobs/{id}", 503, "job 550e8400 failed"));
+    }
+}
@@ -0,0 +1,82 @@
+use std::{
+    future::{ready, Ready},
+    rc::Rc,
+    time::Instant,
+};
+
+use actix_web::{
+    dev::{self, Service, ServiceRequest, ServiceResponse, Transform},
+    Error,
+};
+use futures_util::future::LocalBoxFuture;
+
+use crate::metrics::{HTTP_REQUESTS_TOTAL, HTTP_REQUEST_DURATION_SECONDS};
+
+/// Records request counts and latency for every route.
+///
+/// This should be the outermost middleware so rejected requests are counted as well.
+pub struct RequestMetrics;
+
+impl<S: 'static, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestMetricsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let svc = self.service.clone();
+
+        // Use the route pattern instead of the raw path to keep the label cardinality bounded.
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| String::from("unmatched"));
+        let method = req.method().to_string();
+        let start = Instant::now();
+
+        Box::pin(async move {
+            let res = svc.call(req).await;
+
+            let status = match &res {
+                Ok(res) => res.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            let labels = [method.as_str(), route.as_str(), status.as_str()];
+
+            HTTP_REQUESTS_TOTAL.with_label_values(&labels).inc();
+            HTTP_REQUEST_DURATION_SECONDS
+                .with_label_values(&labels)
+                .observe(start.elapsed().as_secs_f64());
+
+            res
+        })
+    }
+}
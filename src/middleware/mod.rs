@@ -1 +1,69 @@
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod error_reports;
+pub mod metrics;
+pub mod rate_limit;
+pub mod request_id;
+
+use std::net::IpAddr;
+
+use actix_web::HttpRequest;
+
+use crate::config::Config;
+
+/// The address of the client that made a request.
+///
+/// This is the peer address, unless the peer is one of the trusted proxies. Then it is the last
+/// address in `X-Forwarded-For` that was not added by a trusted proxy, the entries before it were
+/// sent by the client and could be anything.
+pub fn client_ip(req: &HttpRequest, config: &Config) -> Option<IpAddr> {
+    let mut ip = req.peer_addr()?.ip();
+
+    let forwarded: Vec<&str> = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+
+    for entry in forwarded.iter().rev() {
+        if !config.is_trusted_proxy(ip) {
+            break;
+        }
+
+        match entry.trim().parse() {
+            Ok(forwarded_ip) => ip = forwarded_ip,
+            Err(_) => break,
+        }
+    }
+
+    Some(ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn ip(forwarded_for: &str, peer: &str) -> Option<IpAddr> {
+        let config = Config {
+            trusted_proxies: vec![String::from("10.0.0.0/8")],
+            ..Config::default()
+        };
+        let req = TestRequest::default()
+            .peer_addr(format!("{}:4000", peer).parse().unwrap())
+            .insert_header(("X-Forwarded-For", forwarded_for))
+            .to_http_request();
+
+        client_ip(&req, &config)
+    }
+
+    #[test]
+    fn reads_forwarded_addresses_from_trusted_proxies_only() {
+        let client = Some("203.0.113.7".parse().unwrap());
+
+        assert_eq!(ip("1.1.1.1", "203.0.113.7"), client);
+        assert_eq!(ip("1.1.1.1, 203.0.113.7", "10.0.0.1"), client);
+        assert_eq!(ip("1.1.1.1, 203.0.113.7, 10.0.0.2", "10.0.0.1"), client);
+        assert_eq!(ip("not an ip", "10.0.0.1"), Some("10.0.0.1".parse().unwrap()));
+    }
+}
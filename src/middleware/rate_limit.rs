@@ -0,0 +1,379 @@
+use std::{
+    collections::HashMap,
+    future::{ready, Ready},
+    rc::Rc,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    dev::{self, Service, ServiceRequest, ServiceResponse, Transform},
+    error::InternalError,
+    http::header::{HeaderMap, HeaderName, HeaderValue},
+    web, Error, HttpMessage, ResponseError,
+};
+use async_trait::async_trait;
+use futures_util::future::LocalBoxFuture;
+
+use crate::{
+    config::{RateLimit, RateLimitConfig},
+    error::CustomAPIError,
+    metrics::RATE_LIMITED_TOTAL,
+    middleware::{
+        auth::{self, Caller},
+        client_ip,
+    },
+    models::UserRole,
+    AppState,
+};
+
+/// Buckets are only pruned once there are this many, pruning drops the full buckets
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+/// Pruning walks every bucket, so it runs at most this often
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The state of a bucket after a request tried to take a token from it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    /// The size of the bucket
+    pub limit: u32,
+    /// Requests left right now
+    pub remaining: u32,
+    /// Seconds until the bucket is full again
+    pub reset_secs: u64,
+    /// Seconds until the next request is allowed, 0 if this one was
+    pub retry_after: u64,
+}
+
+/// Keeps the token buckets.
+///
+/// The in-memory backend limits each instance on its own, a shared backend can implement this to
+/// enforce the limits across instances.
+#[async_trait]
+pub trait RateLimitBackend: Send + Sync + std::fmt::Debug {
+    /// Takes a token from the bucket with the key, creating a full bucket if there is none
+    async fn acquire(&self, key: &str, limit: RateLimit) -> Result<RateLimitDecision, CustomAPIError>;
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    /// The limit the bucket was last used with, limits differ between roles
+    limit: RateLimit,
+}
+
+impl Bucket {
+    /// Whether the bucket refilled completely by now, then it is no different from a new one
+    fn is_full(&self, now: Instant) -> bool {
+        let per_second = f64::from(self.limit.per_minute) / 60.0;
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+
+        self.tokens + elapsed * per_second >= f64::from(self.limit.burst)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    pruned_at: Option<Instant>,
+}
+
+/// Keeps the buckets in the memory of this instance
+#[derive(Debug, Default)]
+pub struct MemoryRateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl MemoryRateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn acquire_at(&self, key: &str, limit: RateLimit, now: Instant) -> RateLimitDecision {
+        let burst = f64::from(limit.burst);
+        let per_second = f64::from(limit.per_minute) / 60.0;
+
+        let mut state = self.buckets();
+
+        let prune_due = state
+            .pruned_at
+            .is_none_or(|pruned_at| now.saturating_duration_since(pruned_at) >= PRUNE_INTERVAL);
+        if state.buckets.len() >= MAX_IDLE_BUCKETS && prune_due {
+            state.buckets.retain(|_, bucket| !bucket.is_full(now));
+            state.pruned_at = Some(now);
+        }
+
+        let bucket = state.buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: burst,
+            updated_at: now,
+            limit,
+        });
+        bucket.limit = limit;
+
+        let elapsed = now.saturating_duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(burst);
+        bucket.updated_at = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        let retry_after = if allowed {
+            0
+        } else {
+            (((1.0 - bucket.tokens) / per_second).ceil() as u64).max(1)
+        };
+
+        RateLimitDecision {
+            allowed,
+            limit: limit.burst,
+            remaining: bucket.tokens.floor() as u32,
+            reset_secs: ((burst - bucket.tokens) / per_second).ceil() as u64,
+            retry_after,
+        }
+    }
+
+    /// Locks the buckets. They only hold plain numbers, so they are still usable after a panic.
+    fn buckets(&self) -> MutexGuard<'_, Buckets> {
+        self.buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[async_trait]
+impl RateLimitBackend for MemoryRateLimiter {
+    async fn acquire(&self, key: &str, limit: RateLimit) -> Result<RateLimitDecision, CustomAPIError> {
+        Ok(self.acquire_at(key, limit, Instant::now()))
+    }
+}
+
+/// The limit for the tokens of a user, the most generous limit of their roles wins
+fn token_limit(config: &RateLimitConfig, roles: &[UserRole]) -> RateLimit {
+    roles
+        .iter()
+        .filter_map(|role| config.roles.get(role))
+        .max_by_key(|limit| (limit.per_minute, limit.burst))
+        .copied()
+        .unwrap_or(config.token)
+}
+
+/// Sets the `RateLimit-*` headers, unless a bucket with fewer requests left already did
+fn insert_headers(headers: &mut HeaderMap, decision: &RateLimitDecision) {
+    let remaining = headers
+        .get("ratelimit-remaining")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u32>().ok());
+    if remaining.is_some_and(|remaining| remaining <= decision.remaining) {
+        return;
+    }
+
+    for (name, value) in [
+        ("ratelimit-limit", u64::from(decision.limit)),
+        ("ratelimit-remaining", u64::from(decision.remaining)),
+        ("ratelimit-reset", decision.reset_secs),
+    ] {
+        headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+    }
+}
+
+/// Limits the request rate with token buckets.
+///
+/// Answers `429` with a `Retry-After` header once a bucket is empty, every response carries the
+/// `RateLimit-*` headers of the tightest bucket. Requests with internal keys are not limited.
+#[derive(Clone, Copy)]
+pub enum RateLimiter {
+    /// Limits each client IP. Runs outside the auth middleware, so requests with missing or wrong
+    /// keys are limited too and guessing keys is slowed down.
+    PerIp,
+    /// Limits each API token, or each user for dashboard sessions. Has to run inside the auth
+    /// middleware to know the caller.
+    PerToken,
+}
+
+impl<S: 'static, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            limiter: *self,
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    limiter: RateLimiter,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let svc = self.service.clone();
+        let limiter = self.limiter;
+
+        let data = req.app_data::<web::Data<AppState>>().cloned();
+        let caller = req.extensions().get::<Caller>().cloned();
+
+        Box::pin(async move {
+            let data = match data {
+                Some(data) => data,
+                None => return Err(CustomAPIError::InternalError.into()),
+            };
+            let config = &data.config.rate_limit;
+
+            if !config.enabled {
+                return svc.call(req).await;
+            }
+
+            let (scope, key, limit) = match limiter {
+                RateLimiter::PerIp => {
+                    // Only a key that matches exactly skips the limit, guesses do not.
+                    let internal = auth::api_key(req.headers())
+                        .is_ok_and(|key| auth::find_internal_key(&data.config, &key).is_some());
+                    if internal {
+                        return svc.call(req).await;
+                    }
+
+                    let ip = client_ip(req.request(), &data.config)
+                        .map_or_else(|| String::from("unknown"), |ip| ip.to_string());
+                    ("ip", format!("ip:{}", ip), config.ip)
+                }
+                // Api tokens and the access tokens issued for them share a bucket, dashboard
+                // sessions get one per user.
+                RateLimiter::PerToken => match caller {
+                    Some(Caller::User {
+                        token_id, user_id, roles, ..
+                    }) => {
+                        let key = match token_id {
+                            Some(token_id) => format!("token:{}", token_id.to_hex()),
+                            None => format!("user:{}", user_id.to_hex()),
+                        };
+                        ("token", key, token_limit(config, &roles))
+                    }
+                    Some(Caller::Internal { .. }) | None => return svc.call(req).await,
+                },
+            };
+
+            let decision = data.rate_limiter.acquire(&key, limit).await?;
+
+            if !decision.allowed {
+                RATE_LIMITED_TOTAL.with_label_values(&[scope]).inc();
+
+                let e = CustomAPIError::RateLimited {
+                    retry_after: decision.retry_after,
+                };
+                let mut res = e.error_response();
+                insert_headers(res.headers_mut(), &decision);
+
+                return Err(InternalError::from_response(e, res).into());
+            }
+
+            let mut res = svc.call(req).await?;
+            insert_headers(res.headers_mut(), &decision);
+
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refills_buckets_over_time() {
+        let limiter = MemoryRateLimiter::new();
+        let limit = RateLimit {
+            burst: 2,
+            per_minute: 60,
+        };
+        let start = Instant::now();
+
+        assert_eq!(limiter.acquire_at("key", limit, start).remaining, 1);
+        assert_eq!(limiter.acquire_at("key", limit, start).remaining, 0);
+
+        let denied = limiter.acquire_at("key", limit, start);
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, 1);
+        assert_eq!(denied.reset_secs, 2);
+
+        assert!(limiter.acquire_at("other", limit, start).allowed);
+
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.acquire_at("key", limit, later).allowed);
+        assert!(!limiter.acquire_at("key", limit, later).allowed);
+    }
+
+    #[test]
+    fn prunes_buckets_by_their_own_limit() {
+        let limiter = MemoryRateLimiter::new();
+        let slow = RateLimit {
+            burst: 2,
+            per_minute: 1,
+        };
+        let fast = RateLimit {
+            burst: 2,
+            per_minute: 6000,
+        };
+        let start = Instant::now();
+
+        limiter.acquire_at("slow", slow, start);
+        limiter.acquire_at("slow", slow, start);
+        for i in 1..MAX_IDLE_BUCKETS {
+            limiter.acquire_at(&format!("fast-{}", i), fast, start);
+        }
+
+        // The full fast buckets are dropped, the slow one is still refilling and is kept.
+        let later = start + PRUNE_INTERVAL;
+        limiter.acquire_at("new", fast, later);
+        assert_eq!(limiter.buckets().buckets.len(), 2);
+        assert_eq!(limiter.acquire_at("slow", slow, later).remaining, 0);
+
+        // Until the interval passed again no bucket is dropped.
+        for i in 1..MAX_IDLE_BUCKETS {
+            limiter.acquire_at(&format!("fast-{}", i), fast, later);
+        }
+        limiter.acquire_at("newer", fast, later + Duration::from_secs(1));
+        assert_eq!(limiter.buckets().buckets.len(), MAX_IDLE_BUCKETS + 2);
+    }
+
+    #[test]
+    fn picks_the_most_generous_role_limit() {
+        let admin = RateLimit {
+            burst: 10,
+            per_minute: 1000,
+        };
+        let config = RateLimitConfig {
+            roles: HashMap::from([(UserRole::ADMIN, admin)]),
+            ..RateLimitConfig::default()
+        };
+
+        assert_eq!(token_limit(&config, &[UserRole::USER]), config.token);
+        assert_eq!(token_limit(&config, &[UserRole::USER, UserRole::ADMIN]), admin);
+    }
+}
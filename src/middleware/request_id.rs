@@ -0,0 +1,122 @@
+use std::{
+    future::{ready, Ready},
+    rc::Rc,
+};
+
+use actix_web::{
+    body::BoxBody,
+    dev::{self, Service, ServiceRequest, ServiceResponse, Transform},
+    error::InternalError,
+    http::header::{HeaderName, HeaderValue},
+    Error, HttpMessage,
+};
+use futures_util::future::LocalBoxFuture;
+use mongodb::bson::oid::ObjectId;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The id of the request currently being handled, if any
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// The id of a request, stored in the request extensions
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+/// Assigns every request an id and echoes it in the `X-Request-Id` response header.
+///
+/// A valid id sent by the client is reused so requests can be traced across systems.
+/// Errors returned by inner services are rendered here so their body carries the id,
+/// which means this has to wrap every middleware that can fail.
+pub struct RequestIdHandler;
+
+impl<S: 'static, B> Transform<S, ServiceRequest> for RequestIdHandler
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: actix_web::body::MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestIdMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: Rc<S>,
+}
+
+/// Client ids are only accepted if they are short and made of safe characters
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: actix_web::body::MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let svc = self.service.clone();
+
+        let id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| is_valid_request_id(value))
+            .map(String::from)
+            .unwrap_or_else(|| ObjectId::new().to_hex());
+
+        req.extensions_mut().insert(RequestId(id.clone()));
+
+        Box::pin(REQUEST_ID.scope(id.clone(), async move {
+            let header = HeaderValue::from_str(&id).ok();
+
+            match svc.call(req).await {
+                Ok(mut res) => {
+                    if let Some(value) = header {
+                        res.headers_mut()
+                            .insert(HeaderName::from_static("x-request-id"), value);
+                    }
+
+                    Ok(res.map_into_boxed_body())
+                }
+                Err(e) => {
+                    // The router needs sole ownership of the request, so it can not be kept around
+                    // to build the error response. The error is rendered here instead and passed on
+                    // as a ready-made response.
+                    let mut res = e.error_response();
+                    if let Some(value) = header {
+                        res.headers_mut()
+                            .insert(HeaderName::from_static("x-request-id"), value);
+                    }
+
+                    Err(InternalError::from_response(e, res).into())
+                }
+            }
+        }))
+    }
+}
@@ -1,5 +1,8 @@
 // Database models for the application. This is a port from the web/prisma schema file in the
 // Client side codebase.
+//
+// Field names mirror the prisma schema so the documents stay compatible with the web client.
+#![allow(non_snake_case)]
 
 use mongodb::bson::{oid::ObjectId, self};
 use serde::{Deserialize, Serialize};
@@ -16,9 +19,23 @@ pub struct User {
     pub roles: Vec<UserRole>,
     pub telemetry: bool,
     pub tomestoned: bool,
+    /// When the account was deleted, it is purged once the retention period is over
+    #[serde(default)]
+    pub deleted_at: Option<bson::DateTime>,
+    #[serde(default)]
+    pub quota: Quota,
+    #[serde(default)]
+    pub quota_usage: QuotaUsage,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+impl User {
+    /// Checks if the user has at least one of the given roles
+    pub fn has_any_role(&self, roles: &[UserRole]) -> bool {
+        self.roles.iter().any(|role| roles.contains(role))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Hash, Serialize)]
 pub enum UserRole {
     USER,
     CONTRIBUTOR,
@@ -35,8 +52,81 @@ pub struct Tokens {
     pub created_at: bson::DateTime,
     pub updated_at: Option<bson::DateTime>,
     pub tomestoned: bool,
-    #[allow(non_snake_case)]
     pub userId: ObjectId,
+    /// The token is rejected from this time on, tokens without it do not expire
+    #[serde(default)]
+    pub expires_at: Option<bson::DateTime>,
+    #[serde(default)]
+    pub quota: Quota,
+    #[serde(default)]
+    pub quota_usage: QuotaUsage,
+}
+
+/// Hard caps on usage, enforced on top of the credit balance. Unset limits are unlimited.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Quota {
+    pub requests_per_day: Option<i32>,
+    pub credits_per_month: Option<i32>,
+}
+
+/// Usage counted against a quota. The counters start over every day and month (UTC).
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct QuotaUsage {
+    /// The day `requests` were counted on, e.g. `2023-05-14`
+    pub day: String,
+    pub requests: i32,
+    /// The month `credits` were counted in, e.g. `2023-05`
+    pub month: String,
+    pub credits: i32,
+}
+
+impl QuotaUsage {
+    /// The usage of the current day and month, counters of past periods start over
+    pub fn current(&self, now: chrono::DateTime<chrono::Utc>) -> QuotaUsage {
+        let day = now.format("%Y-%m-%d").to_string();
+        let month = now.format("%Y-%m").to_string();
+
+        QuotaUsage {
+            requests: if self.day == day { self.requests } else { 0 },
+            credits: if self.month == month { self.credits } else { 0 },
+            day,
+            month,
+        }
+    }
+
+    /// Names the quota that one more charged request would exceed, if any
+    pub fn exceeded(&self, quota: &Quota) -> Option<&'static str> {
+        if quota
+            .requests_per_day
+            .is_some_and(|limit| self.requests >= limit)
+        {
+            return Some("requests_per_day");
+        }
+
+        if quota
+            .credits_per_month
+            .is_some_and(|limit| self.credits >= limit)
+        {
+            return Some("credits_per_month");
+        }
+
+        None
+    }
+
+    /// Counts a charged request, which costs a single credit
+    pub fn record(&mut self) {
+        self.requests += 1;
+        self.credits += 1;
+    }
+}
+
+/// A dashboard login, created by NextAuth in the web client
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Session {
+    pub _id: ObjectId,
+    pub sessionToken: String,
+    pub userId: ObjectId,
+    pub expires: bson::DateTime,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -45,11 +135,10 @@ pub struct Statistics {
     pub created_at: bson::DateTime,
     pub updated_at: Option<bson::DateTime>,
     pub usage: Option<Usage>,
-    #[allow(non_snake_case)]
     pub userId: ObjectId,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Usage {
     pub api_calls: Option<i32>,
     pub api_calls_monday: Option<i32>,
@@ -63,12 +152,86 @@ pub struct Usage {
     pub api_calls_fail: Option<i32>,
 }
 
+impl Usage {
+    /// The usage of a single charged call.
+    ///
+    /// Only the count is kept unless `detailed`, which adds the weekday and the outcome.
+    pub fn call(now: chrono::DateTime<chrono::Utc>, detailed: bool) -> Usage {
+        let mut usage = Usage {
+            api_calls: Some(1),
+            ..Usage::default()
+        };
+
+        if detailed {
+            usage.api_calls_success = Some(1);
+
+            let weekday = match chrono::Datelike::weekday(&now) {
+                chrono::Weekday::Mon => &mut usage.api_calls_monday,
+                chrono::Weekday::Tue => &mut usage.api_calls_tuesday,
+                chrono::Weekday::Wed => &mut usage.api_calls_wednesday,
+                chrono::Weekday::Thu => &mut usage.api_calls_thursday,
+                chrono::Weekday::Fri => &mut usage.api_calls_friday,
+                chrono::Weekday::Sat => &mut usage.api_calls_saturday,
+                chrono::Weekday::Sun => &mut usage.api_calls_sunday,
+            };
+            *weekday = Some(1);
+        }
+
+        usage
+    }
+
+    /// Adds the new usage on top of this one.
+    ///
+    /// Counters that are not set yet take the new value.
+    pub fn merge(&mut self, new_usage: &Usage) {
+        self.api_calls = self
+            .api_calls
+            .map(|x| x + new_usage.api_calls.unwrap_or_default())
+            .or(new_usage.api_calls);
+        self.api_calls_monday = self
+            .api_calls_monday
+            .map(|x| x + new_usage.api_calls_monday.unwrap_or_default())
+            .or(new_usage.api_calls_monday);
+        self.api_calls_tuesday = self
+            .api_calls_tuesday
+            .map(|x| x + new_usage.api_calls_tuesday.unwrap_or_default())
+            .or(new_usage.api_calls_tuesday);
+        self.api_calls_wednesday = self
+            .api_calls_wednesday
+            .map(|x| x + new_usage.api_calls_wednesday.unwrap_or_default())
+            .or(new_usage.api_calls_wednesday);
+        self.api_calls_thursday = self
+            .api_calls_thursday
+            .map(|x| x + new_usage.api_calls_thursday.unwrap_or_default())
+            .or(new_usage.api_calls_thursday);
+        self.api_calls_friday = self
+            .api_calls_friday
+            .map(|x| x + new_usage.api_calls_friday.unwrap_or_default())
+            .or(new_usage.api_calls_friday);
+        self.api_calls_saturday = self
+            .api_calls_saturday
+            .map(|x| x + new_usage.api_calls_saturday.unwrap_or_default())
+            .or(new_usage.api_calls_saturday);
+        self.api_calls_sunday = self
+            .api_calls_sunday
+            .map(|x| x + new_usage.api_calls_sunday.unwrap_or_default())
+            .or(new_usage.api_calls_sunday);
+        self.api_calls_success = self
+            .api_calls_success
+            .map(|x| x + new_usage.api_calls_success.unwrap_or_default())
+            .or(new_usage.api_calls_success);
+        self.api_calls_fail = self
+            .api_calls_fail
+            .map(|x| x + new_usage.api_calls_fail.unwrap_or_default())
+            .or(new_usage.api_calls_fail);
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Credits {
     pub _id: ObjectId,
     pub used_amount: Option<i32>,
     pub current_amount: Option<i32>,
-    #[allow(non_snake_case)]
     pub userId: ObjectId,
 }
 
@@ -83,7 +246,6 @@ pub struct Payment {
     pub subscription_cancelled_date: Option<bson::DateTime>,
     pub subscription_cancelled_reason: Option<String>,
     pub credits_purchased: i32,
-    #[allow(non_snake_case)]
     pub userId: ObjectId,
 }
 
@@ -94,8 +256,16 @@ pub struct SystemReport {
     pub description: String,
     pub status: ReportStatus,
     pub created_at: bson::DateTime,
-    #[allow(non_snake_case)]
     pub userId: ObjectId,
+    /// Status changes and comments, oldest first
+    #[serde(default)]
+    pub timeline: Vec<ReportEvent>,
+    /// Groups the reports opened automatically from server errors
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    /// How often the grouped server error happened
+    #[serde(default)]
+    pub occurrences: Option<i64>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -105,13 +275,97 @@ pub struct UserReport {
     pub description: String,
     pub status: ReportStatus,
     pub created_at: bson::DateTime,
-    #[allow(non_snake_case)]
     pub assignedToId: ObjectId,
+    /// Status changes and comments, oldest first
+    #[serde(default)]
+    pub timeline: Vec<ReportEvent>,
 }
 
+/// A single entry in the timeline of a report
 #[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReportEvent {
+    pub kind: ReportEventKind,
+    /// The user responsible for the event, none for internal systems
+    pub actorId: Option<ObjectId>,
+    pub text: String,
+    pub created_at: bson::DateTime,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum ReportEventKind {
+    StatusChange { from: ReportStatus, to: ReportStatus },
+    Comment,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
 pub enum ReportStatus {
     InProgress,
     RESOLVED,
     CLOSED,
-}
\ No newline at end of file
+}
+
+impl ReportStatus {
+    /// Reports can only move out of InProgress, resolved and closed reports are final.
+    pub fn can_transition_to(&self, next: ReportStatus) -> bool {
+        matches!(
+            (self, next),
+            (ReportStatus::InProgress, ReportStatus::RESOLVED)
+                | (ReportStatus::InProgress, ReportStatus::CLOSED)
+        )
+    }
+}
+
+/// A privileged action, kept in the `audit_logs` collection
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AuditLog {
+    pub _id: ObjectId,
+    pub created_at: bson::DateTime,
+    pub actor: AuditActor,
+    pub action: AuditAction,
+    pub target: AuditTarget,
+    /// The changed values before the action, if it changed anything
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub ip: Option<String>,
+    pub request_id: Option<String>,
+}
+
+/// Who performed an audited action
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AuditActor {
+    /// The user, none for internal systems
+    pub userId: Option<ObjectId>,
+    /// The name of the internal key, none for users
+    pub key_name: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    InternalKeyUsed,
+    TokenCreated,
+    PaymentCreated,
+    CreditsChanged,
+    QuotaChanged,
+    ReportStatusChanged,
+    AccountDeleted,
+}
+
+/// What an audited action was performed on
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AuditTarget {
+    /// e.g. `token`, `user`, `payment`, `system_report` or `route`
+    pub kind: String,
+    /// The id of the document, or the method and path for routes
+    pub id: Option<String>,
+}
+
+impl AuditTarget {
+    pub fn new(kind: &str, id: impl ToString) -> Self {
+        Self {
+            kind: kind.to_string(),
+            id: Some(id.to_string()),
+        }
+    }
+}
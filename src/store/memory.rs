@@ -0,0 +1,773 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use mongodb::bson::{self, oid::ObjectId};
+
+use crate::{
+    db::{ANONYMOUS_USER_ID, SYSTEM_USER_ID},
+    error::CustomAPIError,
+    metrics::CREDIT_DEDUCTIONS_TOTAL,
+    models::{
+        AuditLog, Credits, Payment, Quota, ReportEvent, ReportStatus, Session, Statistics,
+        SystemReport, Tokens, Usage, User, UserReport,
+    },
+    store::{
+        check_quota, report_status_conflict, AccountDeletion, AuditFilter, Charge, ProfileChanges,
+        ReportChanges, ReportFilter, Store, ACCOUNT_DELETED_REASON,
+    },
+};
+
+/// Keeps everything in memory, nothing survives a restart.
+///
+/// Used by the tests and to run the API without a MongoDB server.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    data: Mutex<MemoryData>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryData {
+    users: HashMap<ObjectId, User>,
+    tokens: Vec<Tokens>,
+    sessions: Vec<Session>,
+    credits: HashMap<ObjectId, Credits>,
+    payments: Vec<Payment>,
+    statistics: HashMap<ObjectId, Statistics>,
+    system_reports: Vec<SystemReport>,
+    user_reports: Vec<UserReport>,
+    audit_logs: Vec<AuditLog>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a user. Users are created by the web client, so the API has no handler for this.
+    pub fn insert_user(&self, user: User) {
+        self.data().users.insert(user._id, user);
+    }
+
+    /// Adds an api token as is, e.g. one that is revoked or expired
+    pub fn insert_api_token(&self, token: Tokens) {
+        self.data().tokens.push(token);
+    }
+
+    /// Revokes an api token without touching any cache, like another instance would
+    pub fn revoke_api_token(&self, token_id: ObjectId) {
+        if let Some(token) = self.data().tokens.iter_mut().find(|t| t._id == token_id) {
+            token.tomestoned = true;
+        }
+    }
+
+    /// Adds a dashboard session, they are created by the web client
+    pub fn insert_session(&self, session: Session) {
+        self.data().sessions.push(session);
+    }
+
+    /// Returns the api tokens of a user, tokens are never returned by the API itself
+    pub fn api_tokens(&self, user_id: ObjectId) -> Vec<Tokens> {
+        self.data()
+            .tokens
+            .iter()
+            .filter(|t| t.userId == user_id)
+            .cloned()
+            .collect()
+    }
+
+    /// Locks the data. It is only ever changed in place, so it is still usable after a panic.
+    fn data(&self) -> MutexGuard<'_, MemoryData> {
+        self.data
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Cancels a subscription because its account was deleted
+fn cancel_payment(payment: &mut Payment, cancelled_at: bson::DateTime) {
+    payment.active = false;
+    payment.subscription_cancelled = true;
+    payment.subscription_cancelled_date = Some(cancelled_at);
+    payment.subscription_cancelled_reason = Some(String::from(ACCOUNT_DELETED_REASON));
+}
+
+/// Applies the changes shared by both report types
+fn apply_report_changes(
+    changes: &ReportChanges,
+    title: &mut String,
+    description: &mut String,
+    status: &mut ReportStatus,
+) {
+    if let Some(new_title) = &changes.title {
+        *title = new_title.clone();
+    }
+    if let Some(new_description) = &changes.description {
+        *description = new_description.clone();
+    }
+    if let Some(new_status) = changes.status {
+        *status = new_status;
+    }
+}
+
+/// Sorts reports newest first, reports created in the same millisecond keep the newest insert first
+fn newest_first<T: Clone>(reports: &[T], created_at: impl Fn(&T) -> i64) -> Vec<T> {
+    let mut reports: Vec<T> = reports.iter().rev().cloned().collect();
+    reports.sort_by_key(|report| std::cmp::Reverse(created_at(report)));
+    reports
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn ping(&self) -> Result<(), CustomAPIError> {
+        Ok(())
+    }
+
+    async fn get_api_token(&self, token: &str) -> Result<Option<Tokens>, CustomAPIError> {
+        Ok(self.data().tokens.iter().find(|t| t.token == token).cloned())
+    }
+
+    async fn get_session(&self, session_token: &str) -> Result<Option<Session>, CustomAPIError> {
+        Ok(self
+            .data()
+            .sessions
+            .iter()
+            .find(|s| s.sessionToken == session_token)
+            .cloned())
+    }
+
+    async fn get_api_token_by_id(&self, id: ObjectId) -> Result<Option<Tokens>, CustomAPIError> {
+        Ok(self.data().tokens.iter().find(|t| t._id == id).cloned())
+    }
+
+    async fn create_api_token(&self, token: Tokens) -> Result<(), CustomAPIError> {
+        self.data().tokens.push(token);
+        Ok(())
+    }
+
+    async fn get_user(&self, user_id: ObjectId) -> Result<Option<User>, CustomAPIError> {
+        Ok(self.data().users.get(&user_id).cloned())
+    }
+
+    async fn get_credits(&self, user_id: ObjectId) -> Result<Option<Credits>, CustomAPIError> {
+        Ok(self.data().credits.get(&user_id).cloned())
+    }
+
+    async fn add_credits(&self, user_id: ObjectId, amount: i32) -> Result<(), CustomAPIError> {
+        let mut data = self.data();
+        let credits = data.credits.entry(user_id).or_insert_with(|| Credits {
+            _id: ObjectId::new(),
+            userId: user_id,
+            current_amount: Some(0),
+            used_amount: Some(0),
+        });
+
+        credits.current_amount = Some(credits.current_amount.unwrap_or(0) + amount);
+
+        Ok(())
+    }
+
+    async fn process_credit_usage(&self, charge: Charge) -> Result<bool, CustomAPIError> {
+        let user_id = charge.user_id;
+        let now = chrono::Utc::now();
+
+        let telemetry = {
+            let mut data = self.data();
+            let data = &mut *data;
+
+            let credits = match data.credits.get_mut(&user_id) {
+                Some(credits) => credits,
+                None => return Ok(false),
+            };
+
+            if credits.current_amount.unwrap_or(0) <= 0 {
+                return Ok(false);
+            }
+
+            let user = data.users.get_mut(&user_id);
+            let token = charge
+                .token_id
+                .and_then(|token_id| data.tokens.iter_mut().find(|t| t._id == token_id));
+
+            // Both quotas are checked before anything is counted.
+            let user_usage = user.as_ref().map(|user| user.quota_usage.current(now));
+            if let (Some(user), Some(usage)) = (&user, &user_usage) {
+                check_quota("user", usage, &user.quota)?;
+            }
+
+            let token_usage = token.as_ref().map(|token| token.quota_usage.current(now));
+            if let (Some(token), Some(usage)) = (&token, &token_usage) {
+                check_quota("token", usage, &token.quota)?;
+            }
+
+            credits.current_amount = credits.current_amount.map(|amount| amount - 1);
+            credits.used_amount = credits.used_amount.map(|amount| amount + 1);
+
+            if let (Some(token), Some(mut usage)) = (token, token_usage) {
+                usage.record();
+                token.quota_usage = usage;
+            }
+
+            match (user, user_usage) {
+                (Some(user), Some(mut usage)) => {
+                    usage.record();
+                    user.quota_usage = usage;
+                    user.telemetry
+                }
+                _ => true,
+            }
+        };
+
+        CREDIT_DEDUCTIONS_TOTAL.inc();
+
+        // Users without telemetry keep only their credits and quota usage, which billing needs.
+        // Their calls are counted in the anonymous report.
+        if telemetry {
+            self.create_statistics_report(user_id, Some(Usage::call(now, true)))
+                .await?;
+        } else {
+            self.create_statistics_report(ANONYMOUS_USER_ID, Some(Usage::call(now, false)))
+                .await?;
+        }
+
+        Ok(true)
+    }
+
+    async fn get_user_by_username(&self, username: &str) -> Result<Option<User>, CustomAPIError> {
+        Ok(self
+            .data()
+            .users
+            .values()
+            .find(|u| u.username.as_deref() == Some(username))
+            .cloned())
+    }
+
+    async fn update_user_profile(
+        &self,
+        user_id: ObjectId,
+        changes: ProfileChanges,
+    ) -> Result<Option<User>, CustomAPIError> {
+        let mut data = self.data();
+
+        let user = match data.users.get_mut(&user_id) {
+            Some(user) if !user.tomestoned => user,
+            _ => return Ok(None),
+        };
+
+        if let Some(name) = changes.name {
+            user.name = name;
+        }
+        if let Some(username) = changes.username {
+            user.username = username;
+        }
+        if let Some(bio) = changes.bio {
+            user.bio = bio;
+        }
+        if let Some(image) = changes.image {
+            user.image = image;
+        }
+        if let Some(telemetry) = changes.telemetry {
+            user.telemetry = telemetry;
+        }
+
+        Ok(Some(user.clone()))
+    }
+
+    async fn set_user_quota(&self, user_id: ObjectId, quota: Quota) -> Result<bool, CustomAPIError> {
+        match self.data().users.get_mut(&user_id) {
+            Some(user) => {
+                user.quota = quota;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn set_token_quota(&self, token_id: ObjectId, quota: Quota) -> Result<bool, CustomAPIError> {
+        match self.data().tokens.iter_mut().find(|t| t._id == token_id) {
+            Some(token) => {
+                token.quota = quota;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn create_audit_log(&self, entry: AuditLog) -> Result<(), CustomAPIError> {
+        self.data().audit_logs.push(entry);
+        Ok(())
+    }
+
+    async fn get_audit_logs(&self, filter: AuditFilter) -> Result<Vec<AuditLog>, CustomAPIError> {
+        let data = self.data();
+
+        let entries: Vec<AuditLog> = data
+            .audit_logs
+            .iter()
+            .filter(|entry| filter.actor_id.is_none_or(|id| entry.actor.userId == Some(id)))
+            .filter(|entry| {
+                filter
+                    .key_name
+                    .as_ref()
+                    .is_none_or(|name| entry.actor.key_name.as_ref() == Some(name))
+            })
+            .filter(|entry| filter.action.is_none_or(|action| entry.action == action))
+            .filter(|entry| {
+                filter
+                    .target_kind
+                    .as_ref()
+                    .is_none_or(|kind| &entry.target.kind == kind)
+            })
+            .filter(|entry| {
+                filter
+                    .target_id
+                    .as_ref()
+                    .is_none_or(|id| entry.target.id.as_ref() == Some(id))
+            })
+            .filter(|entry| filter.from.is_none_or(|from| entry.created_at >= from))
+            .filter(|entry| filter.to.is_none_or(|to| entry.created_at < to))
+            .cloned()
+            .collect();
+
+        let mut entries = newest_first(&entries, |entry| entry.created_at.timestamp_millis());
+        entries.truncate(usize::try_from(filter.limit).unwrap_or(0));
+
+        Ok(entries)
+    }
+
+    async fn delete_user(
+        &self,
+        user_id: ObjectId,
+        deleted_at: bson::DateTime,
+    ) -> Result<Option<AccountDeletion>, CustomAPIError> {
+        let mut data = self.data();
+
+        match data.users.get_mut(&user_id) {
+            Some(user) => {
+                user.tomestoned = true;
+                user.deleted_at.get_or_insert(deleted_at);
+            }
+            None => return Ok(None),
+        }
+
+        let mut deletion = AccountDeletion::default();
+
+        for token in data.tokens.iter_mut() {
+            if token.userId == user_id && !token.tomestoned {
+                token.tomestoned = true;
+                token.updated_at = Some(deleted_at);
+                deletion.tokens_revoked += 1;
+            }
+        }
+
+        for payment in data.payments.iter_mut() {
+            if payment.userId == user_id && payment.active {
+                cancel_payment(payment, deleted_at);
+                deletion.payments_cancelled += 1;
+            }
+        }
+
+        if let Some(mut report) = data.statistics.remove(&user_id) {
+            report.userId = ObjectId::new();
+            data.statistics.insert(report.userId, report);
+            deletion.statistics_anonymised += 1;
+        }
+
+        data.sessions.retain(|s| s.userId != user_id);
+
+        Ok(Some(deletion))
+    }
+
+    async fn purge_deleted_users(&self, deleted_before: bson::DateTime) -> Result<u64, CustomAPIError> {
+        let mut data = self.data();
+
+        let purged: Vec<ObjectId> = data
+            .users
+            .values()
+            .filter(|u| u.tomestoned && u.deleted_at.is_some_and(|at| at < deleted_before))
+            .map(|u| u._id)
+            .collect();
+
+        for user_id in &purged {
+            data.users.remove(user_id);
+            data.credits.remove(user_id);
+        }
+        data.tokens.retain(|t| !purged.contains(&t.userId));
+        data.payments.retain(|p| !purged.contains(&p.userId));
+        data.sessions.retain(|s| !purged.contains(&s.userId));
+
+        Ok(purged.len() as u64)
+    }
+
+    async fn create_payment(&self, payment: Payment) -> Result<(), CustomAPIError> {
+        self.data().payments.push(payment);
+        Ok(())
+    }
+
+    async fn create_statistics_report(
+        &self,
+        user_id: ObjectId,
+        usage: Option<Usage>,
+    ) -> Result<(), CustomAPIError> {
+        let now = self.get_current_time()?;
+        let mut data = self.data();
+
+        match data.statistics.get_mut(&user_id) {
+            Some(report) => {
+                if let Some(new_usage) = usage {
+                    report
+                        .usage
+                        .get_or_insert_with(Usage::default)
+                        .merge(&new_usage);
+                }
+                report.updated_at = Some(now);
+            }
+            None => {
+                data.statistics.insert(
+                    user_id,
+                    Statistics {
+                        _id: ObjectId::new(),
+                        created_at: now,
+                        updated_at: None,
+                        usage,
+                        userId: user_id,
+                    },
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn get_total_api_calls(&self) -> Result<i64, CustomAPIError> {
+        Ok(self
+            .data()
+            .statistics
+            .values()
+            .filter_map(|s| s.usage.as_ref()?.api_calls)
+            .map(i64::from)
+            .sum())
+    }
+
+    async fn create_system_report(
+        &self,
+        title: String,
+        user_id: ObjectId,
+        description: String,
+    ) -> Result<SystemReport, CustomAPIError> {
+        let report = SystemReport {
+            _id: ObjectId::new(),
+            title,
+            description,
+            status: ReportStatus::InProgress,
+            created_at: self.get_current_time()?,
+            userId: user_id,
+            timeline: Vec::new(),
+            fingerprint: None,
+            occurrences: None,
+        };
+
+        self.data().system_reports.push(report.clone());
+
+        Ok(report)
+    }
+
+    async fn get_system_reports(
+        &self,
+        filter: ReportFilter,
+    ) -> Result<Vec<SystemReport>, CustomAPIError> {
+        let reports = newest_first(&self.data().system_reports, |r| {
+            r.created_at.timestamp_millis()
+        });
+
+        Ok(reports
+            .into_iter()
+            .filter(|r| filter.status.is_none_or(|status| r.status == status))
+            .filter(|r| filter.owner_id.is_none_or(|owner| r.userId == owner))
+            .collect())
+    }
+
+    async fn get_system_report(&self, id: ObjectId) -> Result<Option<SystemReport>, CustomAPIError> {
+        Ok(self
+            .data()
+            .system_reports
+            .iter()
+            .find(|r| r._id == id)
+            .cloned())
+    }
+
+    async fn update_system_report(
+        &self,
+        id: ObjectId,
+        changes: ReportChanges,
+        event: Option<ReportEvent>,
+    ) -> Result<Option<SystemReport>, CustomAPIError> {
+        let mut data = self.data();
+
+        let report = match data.system_reports.iter_mut().find(|r| r._id == id) {
+            Some(report) => report,
+            None => return Ok(None),
+        };
+
+        if changes.from_status.is_some_and(|status| status != report.status) {
+            return Err(report_status_conflict());
+        }
+
+        apply_report_changes(
+            &changes,
+            &mut report.title,
+            &mut report.description,
+            &mut report.status,
+        );
+        report.timeline.extend(event);
+
+        Ok(Some(report.clone()))
+    }
+
+    async fn record_server_error(
+        &self,
+        fingerprint: String,
+        title: String,
+        description: String,
+        occurrences: i64,
+    ) -> Result<(), CustomAPIError> {
+        let now = self.get_current_time()?;
+        let mut data = self.data();
+
+        let open = data.system_reports.iter_mut().find(|r| {
+            r.fingerprint.as_deref() == Some(fingerprint.as_str())
+                && r.status == ReportStatus::InProgress
+        });
+
+        match open {
+            Some(report) => {
+                report.occurrences = Some(report.occurrences.unwrap_or(0) + occurrences);
+            }
+            None => data.system_reports.push(SystemReport {
+                _id: ObjectId::new(),
+                title,
+                description,
+                status: ReportStatus::InProgress,
+                created_at: now,
+                userId: SYSTEM_USER_ID,
+                timeline: Vec::new(),
+                fingerprint: Some(fingerprint),
+                occurrences: Some(occurrences),
+            }),
+        }
+
+        Ok(())
+    }
+
+    async fn create_user_report(
+        &self,
+        title: String,
+        description: String,
+        assigned_to_id: ObjectId,
+    ) -> Result<UserReport, CustomAPIError> {
+        let report = UserReport {
+            _id: ObjectId::new(),
+            title,
+            description,
+            status: ReportStatus::InProgress,
+            created_at: self.get_current_time()?,
+            assignedToId: assigned_to_id,
+            timeline: Vec::new(),
+        };
+
+        self.data().user_reports.push(report.clone());
+
+        Ok(report)
+    }
+
+    async fn get_user_reports(&self, filter: ReportFilter) -> Result<Vec<UserReport>, CustomAPIError> {
+        let reports = newest_first(&self.data().user_reports, |r| {
+            r.created_at.timestamp_millis()
+        });
+
+        Ok(reports
+            .into_iter()
+            .filter(|r| filter.status.is_none_or(|status| r.status == status))
+            .filter(|r| filter.owner_id.is_none_or(|owner| r.assignedToId == owner))
+            .collect())
+    }
+
+    async fn get_user_report(&self, id: ObjectId) -> Result<Option<UserReport>, CustomAPIError> {
+        Ok(self
+            .data()
+            .user_reports
+            .iter()
+            .find(|r| r._id == id)
+            .cloned())
+    }
+
+    async fn update_user_report(
+        &self,
+        id: ObjectId,
+        changes: ReportChanges,
+        event: Option<ReportEvent>,
+    ) -> Result<Option<UserReport>, CustomAPIError> {
+        let mut data = self.data();
+
+        let report = match data.user_reports.iter_mut().find(|r| r._id == id) {
+            Some(report) => report,
+            None => return Ok(None),
+        };
+
+        if changes.from_status.is_some_and(|status| status != report.status) {
+            return Err(report_status_conflict());
+        }
+
+        apply_report_changes(
+            &changes,
+            &mut report.title,
+            &mut report.description,
+            &mut report.status,
+        );
+        if let Some(assigned_to_id) = changes.assigned_to_id {
+            report.assignedToId = assigned_to_id;
+        }
+        report.timeline.extend(event);
+
+        Ok(Some(report.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn charges_credits_until_exhausted() {
+        let store = MemoryStore::new();
+        let user_id = ObjectId::new();
+        let charge = Charge {
+            user_id,
+            token_id: None,
+        };
+
+        assert!(!store.process_credit_usage(charge).await.unwrap());
+
+        store.add_credits(user_id, 2).await.unwrap();
+
+        assert!(store.process_credit_usage(charge).await.unwrap());
+        assert!(store.process_credit_usage(charge).await.unwrap());
+        assert!(!store.process_credit_usage(charge).await.unwrap());
+
+        let credits = store.get_credits(user_id).await.unwrap().unwrap();
+        assert_eq!(credits.current_amount, Some(0));
+        assert_eq!(credits.used_amount, Some(2));
+
+        let stats = store.data().statistics[&user_id].clone();
+        assert_eq!(stats.usage.unwrap().api_calls, Some(2));
+    }
+
+    #[actix_web::test]
+    async fn keeps_only_anonymous_usage_without_telemetry() {
+        let store = MemoryStore::new();
+        let user_id = ObjectId::new();
+        store.insert_user(User {
+            _id: user_id,
+            name: None,
+            username: None,
+            bio: None,
+            email: String::from("private@example.com"),
+            email_verified: None,
+            image: String::new(),
+            roles: Vec::new(),
+            telemetry: false,
+            tomestoned: false,
+            deleted_at: None,
+            quota: Quota::default(),
+            quota_usage: Default::default(),
+        });
+        store.add_credits(user_id, 2).await.unwrap();
+
+        let charge = Charge {
+            user_id,
+            token_id: None,
+        };
+        assert!(store.process_credit_usage(charge).await.unwrap());
+
+        assert!(!store.data().statistics.contains_key(&user_id));
+        let anonymous = store.data().statistics[&ANONYMOUS_USER_ID].clone();
+        assert_eq!(anonymous.usage.unwrap().api_calls_success, None);
+        assert_eq!(store.get_credits(user_id).await.unwrap().unwrap().used_amount, Some(1));
+        assert_eq!(store.get_user(user_id).await.unwrap().unwrap().quota_usage.requests, 1);
+        assert_eq!(store.get_total_api_calls().await.unwrap(), 1);
+    }
+
+    #[actix_web::test]
+    async fn groups_server_errors_by_fingerprint() {
+        let store = MemoryStore::new();
+
+        for _ in 0..2 {
+            store
+                .record_server_error("abc".into(), "500".into(), "boom".into(), 3)
+                .await
+                .unwrap();
+        }
+
+        let reports = store.get_system_reports(ReportFilter::default()).await.unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].occurrences, Some(6));
+    }
+
+    #[actix_web::test]
+    async fn filters_reports_by_owner_and_status() {
+        let store = MemoryStore::new();
+        let owner = ObjectId::new();
+
+        let report = store
+            .create_user_report("a".into(), "b".into(), owner)
+            .await
+            .unwrap();
+        store
+            .create_user_report("c".into(), "d".into(), ObjectId::new())
+            .await
+            .unwrap();
+
+        let changes = ReportChanges {
+            status: Some(ReportStatus::RESOLVED),
+            ..ReportChanges::default()
+        };
+        store
+            .update_user_report(report._id, changes, None)
+            .await
+            .unwrap();
+
+        let filter = ReportFilter {
+            status: Some(ReportStatus::RESOLVED),
+            owner_id: Some(owner),
+        };
+        let reports = store.get_user_reports(filter).await.unwrap();
+
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0]._id, report._id);
+    }
+
+    #[actix_web::test]
+    async fn rejects_transitions_from_a_stale_status() {
+        let store = MemoryStore::new();
+        let report = store
+            .create_system_report("a".into(), ObjectId::new(), "b".into())
+            .await
+            .unwrap();
+
+        for (status, accepted) in [(ReportStatus::RESOLVED, true), (ReportStatus::CLOSED, false)] {
+            let changes = ReportChanges {
+                status: Some(status),
+                from_status: Some(ReportStatus::InProgress),
+                ..ReportChanges::default()
+            };
+            let result = store.update_system_report(report._id, changes, None).await;
+
+            assert_eq!(result.is_ok(), accepted);
+        }
+
+        let report = store.get_system_report(report._id).await.unwrap().unwrap();
+        assert_eq!(report.status, ReportStatus::RESOLVED);
+    }
+}
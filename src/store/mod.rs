@@ -0,0 +1,285 @@
+// The storage layer of the API. Handlers only talk to the `Store` trait so the backend can be
+// swapped, MongoDB is used in production and the in-memory store for tests and local development.
+
+pub mod memory;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use mongodb::bson::{self, oid::ObjectId};
+use serde::Serialize;
+
+use crate::{
+    error::CustomAPIError,
+    models::{
+        AuditAction, AuditLog, Credits, Payment, Quota, QuotaUsage, ReportEvent, ReportStatus,
+        Session, SystemReport, Tokens, Usage, User, UserReport,
+    },
+};
+
+pub use memory::MemoryStore;
+
+/// Narrows down a report listing
+#[derive(Clone, Debug, Default)]
+pub struct ReportFilter {
+    pub status: Option<ReportStatus>,
+    /// Only returns reports that belong to this user, the reporter of system reports and the
+    /// assignee of user reports.
+    pub owner_id: Option<ObjectId>,
+}
+
+/// The fields of a report to overwrite, unset fields are left as they are
+#[derive(Clone, Debug, Default)]
+pub struct ReportChanges {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub status: Option<ReportStatus>,
+    /// Only user reports have an assignee
+    pub assigned_to_id: Option<ObjectId>,
+    /// The status the transition was checked against. The update fails with a Conflict if the
+    /// report no longer has it, so concurrent transitions can not both apply.
+    pub from_status: Option<ReportStatus>,
+}
+
+impl ReportChanges {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.description.is_none()
+            && self.status.is_none()
+            && self.assigned_to_id.is_none()
+    }
+}
+
+/// The profile fields of a user to overwrite, unset fields are left as they are
+#[derive(Clone, Debug, Default)]
+pub struct ProfileChanges {
+    /// `Some(None)` clears the name, the same goes for the username and bio
+    pub name: Option<Option<String>>,
+    pub username: Option<Option<String>>,
+    pub bio: Option<Option<String>>,
+    pub image: Option<String>,
+    pub telemetry: Option<bool>,
+}
+
+impl ProfileChanges {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.username.is_none()
+            && self.bio.is_none()
+            && self.image.is_none()
+            && self.telemetry.is_none()
+    }
+}
+
+/// The cancellation reason of the subscriptions of deleted accounts
+pub const ACCOUNT_DELETED_REASON: &str = "account deleted";
+
+/// What deleting an account changed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct AccountDeletion {
+    pub tokens_revoked: u64,
+    pub payments_cancelled: u64,
+    pub statistics_anonymised: u64,
+}
+
+/// Narrows down an audit log listing
+#[derive(Clone, Debug, Default)]
+pub struct AuditFilter {
+    pub actor_id: Option<ObjectId>,
+    pub key_name: Option<String>,
+    pub action: Option<AuditAction>,
+    pub target_kind: Option<String>,
+    pub target_id: Option<String>,
+    /// Only entries created at or after this time
+    pub from: Option<bson::DateTime>,
+    /// Only entries created before this time
+    pub to: Option<bson::DateTime>,
+    /// The maximum number of entries returned
+    pub limit: i64,
+}
+
+/// Who a request is charged to
+#[derive(Clone, Copy, Debug)]
+pub struct Charge {
+    pub user_id: ObjectId,
+    /// The api token the request was made with, internal requests have none
+    pub token_id: Option<ObjectId>,
+}
+
+/// Fails if one more charged request does not fit in the quota
+pub(crate) fn check_quota(
+    scope: &'static str,
+    usage: &QuotaUsage,
+    quota: &Quota,
+) -> Result<(), CustomAPIError> {
+    match usage.exceeded(quota) {
+        Some(quota) => Err(CustomAPIError::QuotaExceeded { scope, quota }),
+        None => Ok(()),
+    }
+}
+
+/// The error for a report whose status changed since the transition was checked
+pub(crate) fn report_status_conflict() -> CustomAPIError {
+    CustomAPIError::Conflict("The report status was changed by someone else!".to_string())
+}
+
+#[async_trait]
+pub trait Store: Send + Sync + std::fmt::Debug {
+    /// Checks if the backend is reachable
+    async fn ping(&self) -> Result<(), CustomAPIError>;
+
+    /// Returns the stored token document for a given api token
+    async fn get_api_token(&self, token: &str) -> Result<Option<Tokens>, CustomAPIError>;
+
+    /// Returns the stored token document by its id
+    async fn get_api_token_by_id(&self, id: ObjectId) -> Result<Option<Tokens>, CustomAPIError>;
+
+    /// Stores a new api token
+    async fn create_api_token(&self, token: Tokens) -> Result<(), CustomAPIError>;
+
+    /// Finds a dashboard session by its token
+    async fn get_session(&self, session_token: &str) -> Result<Option<Session>, CustomAPIError>;
+
+    /// Returns a user by their id
+    async fn get_user(&self, user_id: ObjectId) -> Result<Option<User>, CustomAPIError>;
+
+    /// Returns the user with the username, if any
+    async fn get_user_by_username(&self, username: &str) -> Result<Option<User>, CustomAPIError>;
+
+    /// Applies the changes to the profile of a user and returns the updated user.
+    ///
+    /// Returns None if the user does not exist or was deleted.
+    async fn update_user_profile(
+        &self,
+        user_id: ObjectId,
+        changes: ProfileChanges,
+    ) -> Result<Option<User>, CustomAPIError>;
+
+    /// Returns the credit balance of a user
+    async fn get_credits(&self, user_id: ObjectId) -> Result<Option<Credits>, CustomAPIError>;
+
+    /// Adds purchased credits to the balance of a user, creating the balance if needed
+    async fn add_credits(&self, user_id: ObjectId, amount: i32) -> Result<(), CustomAPIError>;
+
+    /// Charges a single credit for a successful request and counts it against the quotas of the
+    /// user and the token.
+    ///
+    /// Returns false if the user has no credits left, and a `QuotaExceeded` error if the request
+    /// does not fit in a quota. Nothing is charged in either case.
+    async fn process_credit_usage(&self, charge: Charge) -> Result<bool, CustomAPIError>;
+
+    /// Replaces the quota of a user, returns false if there is no such user
+    async fn set_user_quota(&self, user_id: ObjectId, quota: Quota) -> Result<bool, CustomAPIError>;
+
+    /// Replaces the quota of an api token, returns false if there is no such token
+    async fn set_token_quota(&self, token_id: ObjectId, quota: Quota) -> Result<bool, CustomAPIError>;
+
+    /// Appends an entry to the audit log
+    async fn create_audit_log(&self, entry: AuditLog) -> Result<(), CustomAPIError>;
+
+    /// Returns the audit log entries matching the filter, newest first
+    async fn get_audit_logs(&self, filter: AuditFilter) -> Result<Vec<AuditLog>, CustomAPIError>;
+
+    /// Deletes an account: tombstones the user and their tokens, cancels their active payments,
+    /// moves their statistics to an anonymous id and signs them out of the dashboard.
+    ///
+    /// Deleting a user again runs the steps again, so a deletion that failed halfway can be
+    /// retried. Returns None if the user does not exist.
+    async fn delete_user(
+        &self,
+        user_id: ObjectId,
+        deleted_at: bson::DateTime,
+    ) -> Result<Option<AccountDeletion>, CustomAPIError>;
+
+    /// Removes the users deleted before the time with their tokens, credits, payments and
+    /// sessions. Returns how many users were purged.
+    async fn purge_deleted_users(&self, deleted_before: bson::DateTime) -> Result<u64, CustomAPIError>;
+
+    /// Stores a new payment
+    async fn create_payment(&self, payment: Payment) -> Result<(), CustomAPIError>;
+
+    /// Adds the usage to the statistics report of a user, creating the report if needed
+    async fn create_statistics_report(
+        &self,
+        user_id: ObjectId,
+        usage: Option<Usage>,
+    ) -> Result<(), CustomAPIError>;
+
+    /// Counts the calls of every user, including the anonymous report
+    async fn get_total_api_calls(&self) -> Result<i64, CustomAPIError>;
+
+    /// Files a new system report
+    async fn create_system_report(
+        &self,
+        title: String,
+        user_id: ObjectId,
+        description: String,
+    ) -> Result<SystemReport, CustomAPIError>;
+
+    /// Returns all system reports matching the filter, newest first
+    async fn get_system_reports(
+        &self,
+        filter: ReportFilter,
+    ) -> Result<Vec<SystemReport>, CustomAPIError>;
+
+    /// Returns a single system report
+    async fn get_system_report(&self, id: ObjectId) -> Result<Option<SystemReport>, CustomAPIError>;
+
+    /// Applies the changes to a system report and returns the updated report
+    ///
+    /// The event, if any, is appended to the report timeline.
+    async fn update_system_report(
+        &self,
+        id: ObjectId,
+        changes: ReportChanges,
+        event: Option<ReportEvent>,
+    ) -> Result<Option<SystemReport>, CustomAPIError>;
+
+    /// Records occurrences of a grouped server error.
+    ///
+    /// The open report with the same fingerprint is updated, a new one is filed if there is none.
+    async fn record_server_error(
+        &self,
+        fingerprint: String,
+        title: String,
+        description: String,
+        occurrences: i64,
+    ) -> Result<(), CustomAPIError>;
+
+    /// Files a new user report
+    async fn create_user_report(
+        &self,
+        title: String,
+        description: String,
+        assigned_to_id: ObjectId,
+    ) -> Result<UserReport, CustomAPIError>;
+
+    /// Returns all user reports matching the filter, newest first
+    async fn get_user_reports(&self, filter: ReportFilter) -> Result<Vec<UserReport>, CustomAPIError>;
+
+    /// Returns a single user report
+    async fn get_user_report(&self, id: ObjectId) -> Result<Option<UserReport>, CustomAPIError>;
+
+    /// Applies the changes to a user report and returns the updated report
+    ///
+    /// The event, if any, is appended to the report timeline.
+    async fn update_user_report(
+        &self,
+        id: ObjectId,
+        changes: ReportChanges,
+        event: Option<ReportEvent>,
+    ) -> Result<Option<UserReport>, CustomAPIError>;
+
+    /// Converts a string to an mongodb ObjectId
+    fn convert_to_object_id(&self, id: String) -> Result<ObjectId, CustomAPIError> {
+        match ObjectId::parse_str(&id) {
+            Ok(object_id) => Ok(object_id),
+            Err(_) => Err(CustomAPIError::validation("id", format!("Invalid id {:?}!", id))),
+        }
+    }
+
+    /// Gets the current DateTime in UTC
+    /// This function converts the DateTime into MongoDB compatible format for storing in the database
+    fn get_current_time(&self) -> Result<bson::DateTime, CustomAPIError> {
+        Ok(bson::DateTime::from_chrono(Utc::now()))
+    }
+}
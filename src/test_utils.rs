@@ -0,0 +1,31 @@
+// Shared helpers for the unit tests.
+
+use std::sync::Arc;
+
+use crate::{
+    config::Config,
+    db::MongoDB,
+    inference::MockEngine,
+    AppState,
+};
+
+pub const SUPER_KEY: &str = "test-super-key";
+
+/// A MongoDB server that can never be reached, queries fail after a short timeout
+pub const UNREACHABLE_URI: &str =
+    "mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=200&connectTimeoutMS=200";
+
+/// Creates an app state with a database that can never be reached.
+///
+/// The MongoDB client connects lazily, so this only fails once a query is made.
+pub async fn unreachable_state() -> AppState {
+    let config = Config {
+        super_key: String::from(SUPER_KEY),
+        ..Config::default()
+    };
+    let db = MongoDB::new(&String::from(UNREACHABLE_URI), &config.database)
+        .await
+        .unwrap();
+
+    AppState::new(config, Arc::new(db), Arc::new(MockEngine))
+}
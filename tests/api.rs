@@ -0,0 +1,971 @@
+mod common;
+
+use std::sync::Arc;
+
+use actix_web::{http::StatusCode, test};
+use mongodb::bson::{self, oid::ObjectId};
+use serde_json::json;
+
+use neura_labs_api::{
+    accounts,
+    config::{InternalKey, JwtKey, KeyScope, RateLimit},
+    inference::{InferenceEngine, Language},
+    middleware::{
+        auth::{API_KEY_HEADER, SESSION_HEADER},
+        error_reports::{self, REPORT_THRESHOLD},
+    },
+    models::{Quota, QuotaUsage, Session, Tokens, UserRole},
+    store::{MemoryStore, ReportFilter, Store},
+};
+
+use common::{request, send, send_json, SUPER_KEY};
+
+#[actix_web::test]
+async fn rejects_requests_without_a_key() {
+    let app = common::app(Arc::new(MemoryStore::new())).await;
+
+    let (status, body) = send_json(&app, request("GET", "/", None)).await;
+
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"]["code"], "unauthorized");
+    assert!(body["error"]["request_id"].is_string());
+}
+
+#[actix_web::test]
+async fn rejects_unknown_keys() {
+    let app = common::app(Arc::new(MemoryStore::new())).await;
+
+    let (status, _) = send(&app, request("GET", "/", Some("not-a-token"))).await;
+
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn accepts_the_super_key() {
+    let app = common::app(Arc::new(MemoryStore::new())).await;
+
+    let (status, body) = send(&app, request("GET", "/", Some(SUPER_KEY))).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.starts_with("Hello from Neura Labs API"));
+}
+
+#[actix_web::test]
+async fn answers_unknown_routes_with_not_found() {
+    let app = common::app(Arc::new(MemoryStore::new())).await;
+
+    let (status, body) = send_json(&app, request("GET", "/nope", Some(SUPER_KEY))).await;
+
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"]["code"], "not_found");
+}
+
+#[actix_web::test]
+async fn created_tokens_authenticate_their_user() {
+    let store = Arc::new(MemoryStore::new());
+    let app = common::app(store.clone()).await;
+    let user_id = common::user(&store, vec![UserRole::USER]);
+
+    let req = request("POST", "/api/v1/token", Some(SUPER_KEY))
+        .set_json(json!({ "data": user_id.to_hex() }));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let tokens = store.api_tokens(user_id);
+    assert_eq!(tokens.len(), 1);
+
+    let (status, _) = send(&app, request("GET", "/", Some(&tokens[0].token))).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn payments_add_credits() {
+    let store = Arc::new(MemoryStore::new());
+    let app = common::app(store.clone()).await;
+    let user_id = ObjectId::new();
+
+    for amount in [10, 5] {
+        let req = request("POST", "/api/v1/payment", Some(SUPER_KEY))
+            .set_json(json!({ "data": { "id": user_id.to_hex(), "amount": amount } }));
+        let (status, _) = send(&app, req).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let credits = store.get_credits(user_id).await.unwrap().unwrap();
+    assert_eq!(credits.current_amount, Some(15));
+    assert_eq!(credits.used_amount, Some(0));
+}
+
+#[actix_web::test]
+async fn rejects_payments_without_an_amount() {
+    let store = Arc::new(MemoryStore::new());
+    let app = common::app(store.clone()).await;
+    let user_id = ObjectId::new();
+
+    let req = request("POST", "/api/v1/payment", Some(SUPER_KEY))
+        .set_json(json!({ "data": { "id": user_id.to_hex(), "amount": 0 } }));
+    let (status, body) = send_json(&app, req).await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["details"]["field"], "amount");
+    assert!(store.get_credits(user_id).await.unwrap().is_none());
+}
+
+fn translation(inputs: &[&str]) -> serde_json::Value {
+    json!({
+        "data": {
+            "source_language": "English",
+            "target_language": "Spanish",
+            "input_context": inputs,
+        }
+    })
+}
+
+#[actix_web::test]
+async fn translates_with_the_engine() {
+    let store = Arc::new(MemoryStore::new());
+    let app = common::app(store.clone()).await;
+    let (_, token) = common::paying_user(&app, &store, 1).await;
+
+    let req = request("POST", "/api/v1/translate", Some(&token))
+        .set_json(translation(&["Hello", "World"]));
+    let (status, body) = send_json(&app, req).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["data"], json!(["[Spanish] Hello", "[Spanish] World"]));
+}
+
+/// An engine that fails every translation
+#[derive(Debug)]
+struct FailingEngine;
+
+impl InferenceEngine for FailingEngine {
+    fn translate(&self, _: Language, _: Language, _: &[String]) -> anyhow::Result<Vec<String>> {
+        Err(anyhow::anyhow!("model 7f3a9c21 failed on input 42"))
+    }
+}
+
+#[actix_web::test]
+async fn reports_repeated_server_errors() {
+    let store = Arc::new(MemoryStore::new());
+    let engine = Arc::new(FailingEngine);
+    let app = common::app_with_engine(common::config(), store.clone(), engine).await;
+    let (_, token) = common::paying_user(&app, &store, 10).await;
+
+    let open_report = || async {
+        let reports = store.get_system_reports(ReportFilter::default()).await.unwrap();
+        reports
+            .into_iter()
+            .find(|report| report.title == "500 POST /api/v1/translate")
+    };
+
+    for _ in 0..REPORT_THRESHOLD {
+        let req = request("POST", "/api/v1/translate", Some(&token)).set_json(translation(&["Hi"]));
+        let (status, _) = send(&app, req).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    // The report is written in the background once the threshold is reached.
+    let mut report = None;
+    for _ in 0..100 {
+        report = open_report().await;
+        if report.is_some() {
+            break;
+        }
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert_eq!(report.unwrap().occurrences, Some(REPORT_THRESHOLD));
+
+    // Later occurrences are collected until the next flush, even if the error stops.
+    let req = request("POST", "/api/v1/translate", Some(&token)).set_json(translation(&["Hi"]));
+    send(&app, req).await;
+    error_reports::flush_pending(store.as_ref()).await;
+
+    assert_eq!(open_report().await.unwrap().occurrences, Some(REPORT_THRESHOLD + 1));
+}
+
+#[actix_web::test]
+async fn purchased_credits_run_out() {
+    let store = Arc::new(MemoryStore::new());
+    let app = common::app(store.clone()).await;
+    let (user_id, token) = common::paying_user(&app, &store, 2).await;
+
+    for _ in 0..2 {
+        let req =
+            request("POST", "/api/v1/translate", Some(&token)).set_json(translation(&["Hello"]));
+        let (status, _) = send(&app, req).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let req = request("POST", "/api/v1/translate", Some(&token)).set_json(translation(&["Hello"]));
+    let (status, body) = send_json(&app, req).await;
+    assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+    assert_eq!(body["error"]["code"], "insufficient_credits");
+
+    let credits = store.get_credits(user_id).await.unwrap().unwrap();
+    assert_eq!(credits.current_amount, Some(0));
+    assert_eq!(credits.used_amount, Some(2));
+}
+
+#[actix_web::test]
+async fn internal_translations_need_a_user() {
+    let app = common::app(Arc::new(MemoryStore::new())).await;
+
+    let req =
+        request("POST", "/api/v1/translate", Some(SUPER_KEY)).set_json(translation(&["Hello"]));
+    let (status, body) = send_json(&app, req).await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["details"]["field"], "id");
+}
+
+#[actix_web::test]
+async fn users_only_see_their_own_reports() {
+    let store = Arc::new(MemoryStore::new());
+    let app = common::app(store.clone()).await;
+    let owner = common::user(&store, vec![UserRole::USER]);
+    let other = common::user(&store, vec![UserRole::USER]);
+
+    for user_id in [owner, other] {
+        let req = request("POST", "/api/v1/token", Some(SUPER_KEY))
+            .set_json(json!({ "data": user_id.to_hex() }));
+        send(&app, req).await;
+    }
+    let owner_token = store.api_tokens(owner)[0].token.clone();
+    let other_token = store.api_tokens(other)[0].token.clone();
+
+    let req = request("POST", "/api/v1/reports/system", Some(&owner_token))
+        .set_json(json!({ "data": { "title": "Broken", "description": "It broke" } }));
+    let (status, report) = send_json(&app, req).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let uri = format!("/api/v1/reports/system/{}", report["_id"]["$oid"].as_str().unwrap());
+
+    let (status, _) = send(&app, request("GET", &uri, Some(&owner_token))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = send(&app, request("GET", &uri, Some(&other_token))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (_, reports) =
+        send_json(&app, request("GET", "/api/v1/reports/system", Some(&other_token))).await;
+    assert_eq!(reports, json!([]));
+
+    // Only moderators can file reports for others or assign them.
+    let req = request("POST", "/api/v1/reports/system", Some(&other_token)).set_json(json!({
+        "data": { "title": "Forged", "description": "Not mine", "user_id": owner.to_hex() }
+    }));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let req = request("POST", "/api/v1/reports/user", Some(&other_token)).set_json(json!({
+        "data": { "title": "Spam", "description": "Hidden", "assigned_to_id": owner.to_hex() }
+    }));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let req = request("POST", "/api/v1/reports/system", Some(SUPER_KEY)).set_json(json!({
+        "data": { "title": "Broken", "description": "Filed for them", "user_id": owner.to_hex() }
+    }));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::CREATED);
+}
+
+#[actix_web::test]
+async fn jobs_are_charged_once_they_complete() {
+    let store = Arc::new(MemoryStore::new());
+    let app = common::app(store.clone()).await;
+    let (user_id, token) = common::paying_user(&app, &store, 1).await;
+
+    let req = request("POST", "/api/v1/jobs", Some(&token)).set_json(translation(&["Hello"]));
+    let res = actix_web::test::call_service(&app, req.to_request()).await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+
+    let uri = res.headers().get("location").unwrap().to_str().unwrap().to_string();
+    let job: serde_json::Value = actix_web::test::read_body_json(res).await;
+    assert_eq!(job["status"], "queued");
+
+    let job = common::finished_job(&app, &uri, &token).await;
+    assert_eq!(job["status"], "completed");
+    assert_eq!(job["result"], json!(["[Spanish] Hello"]));
+
+    let credits = store.get_credits(user_id).await.unwrap().unwrap();
+    assert_eq!(credits.current_amount, Some(0));
+
+    let (_, other_token) = common::paying_user(&app, &store, 1).await;
+    let (status, _) = send(&app, request("GET", &uri, Some(&other_token))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn jobs_need_credits() {
+    let store = Arc::new(MemoryStore::new());
+    let app = common::app(store.clone()).await;
+    let (_, token) = common::paying_user(&app, &store, 0).await;
+
+    let req = request("POST", "/api/v1/jobs", Some(&token)).set_json(translation(&["Hello"]));
+    let (status, _) = send(&app, req).await;
+
+    assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+}
+
+/// Splits a Server-Sent Events body into `(event, data)` pairs
+fn sse_events(body: &str) -> Vec<(String, serde_json::Value)> {
+    body.split("\n\n")
+        .filter(|event| !event.is_empty())
+        .map(|event| {
+            let mut lines = event.lines();
+            let name = lines.next().unwrap().trim_start_matches("event: ");
+            let data = lines.next().unwrap().trim_start_matches("data: ");
+            (name.to_string(), serde_json::from_str(data).unwrap())
+        })
+        .collect()
+}
+
+#[actix_web::test]
+async fn streams_translations_for_one_credit() {
+    let store = Arc::new(MemoryStore::new());
+    let app = common::app(store.clone()).await;
+    let (user_id, token) = common::paying_user(&app, &store, 1).await;
+
+    let req = request("POST", "/api/v1/translate/stream", Some(&token))
+        .set_json(translation(&["One", "Two", "Three"]));
+    let (status, body) = send(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let events = sse_events(&body);
+    let names: Vec<&str> = events.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(
+        names,
+        ["translation", "progress", "translation", "progress", "translation", "progress", "summary"]
+    );
+
+    assert_eq!(events[2].1, json!({ "index": 1, "output": "[Spanish] Two" }));
+    assert_eq!(events[5].1, json!({ "completed": 3, "total": 3 }));
+    assert_eq!(events[6].1, json!({ "completed": 3, "total": 3, "charged": 1 }));
+
+    let credits = store.get_credits(user_id).await.unwrap().unwrap();
+    assert_eq!(credits.used_amount, Some(1));
+
+    // The batch was paid for, another one is refused before anything is streamed.
+    let req = request("POST", "/api/v1/translate/stream", Some(&token))
+        .set_json(translation(&["One"]));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+}
+
+#[actix_web::test]
+async fn serves_metrics_to_internal_keys_only() {
+    let store = Arc::new(MemoryStore::new());
+    let app = common::app(store.clone()).await;
+    let (_, token) = common::paying_user(&app, &store, 1).await;
+
+    let (status, _) = send(&app, request("GET", "/metrics", Some(&token))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, body) = send(&app, request("GET", "/metrics", Some(SUPER_KEY))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("# TYPE"));
+}
+
+#[actix_web::test]
+async fn lists_the_supported_languages() {
+    let app = common::app(Arc::new(MemoryStore::new())).await;
+
+    let (status, body) = send_json(&app, request("GET", "/api/v1/languages", Some(SUPER_KEY))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let spanish = body["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|language| language["code"] == "es")
+        .unwrap();
+    assert_eq!(spanish["name"], "Spanish");
+    assert!(spanish["targets"].as_array().unwrap().contains(&json!("en")));
+    assert!(!spanish["targets"].as_array().unwrap().contains(&json!("es")));
+}
+
+#[actix_web::test]
+async fn rejects_unsupported_languages_without_charging() {
+    let store = Arc::new(MemoryStore::new());
+    let app = common::app(store.clone()).await;
+    let (user_id, token) = common::paying_user(&app, &store, 1).await;
+
+    let req = request("POST", "/api/v1/translate", Some(&token)).set_json(json!({
+        "data": { "source_language": "en", "target_language": "tlh", "input_context": ["Hello"] }
+    }));
+    let (status, body) = send_json(&app, req).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["details"]["field"], "target_language");
+
+    let req = request("POST", "/api/v1/translate", Some(&token)).set_json(json!({
+        "data": { "source_language": "en", "target_language": "es", "input_context": ["Hello"] }
+    }));
+    let (status, body) = send_json(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["data"], json!(["[Spanish] Hello"]));
+
+    let credits = store.get_credits(user_id).await.unwrap().unwrap();
+    assert_eq!(credits.used_amount, Some(1));
+}
+
+#[actix_web::test]
+async fn limits_the_request_rate_per_token() {
+    let store = Arc::new(MemoryStore::new());
+    let mut config = common::config();
+    config.rate_limit.token = RateLimit {
+        burst: 2,
+        per_minute: 1,
+    };
+    let app = common::app_with(config, store.clone()).await;
+    let (_, token) = common::paying_user(&app, &store, 0).await;
+
+    for remaining in ["1", "0"] {
+        let req = request("GET", "/", Some(&token)).to_request();
+        let res = actix_web::test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("ratelimit-limit").unwrap(), "2");
+        assert_eq!(res.headers().get("ratelimit-remaining").unwrap(), remaining);
+    }
+
+    let req = request("GET", "/", Some(&token)).to_request();
+    let res = match actix_web::test::try_call_service(&app, req).await {
+        Ok(_) => panic!("the request was not limited"),
+        Err(e) => e.error_response(),
+    };
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.headers().get("retry-after").unwrap(), "60");
+    assert_eq!(res.headers().get("ratelimit-remaining").unwrap(), "0");
+
+    // Other tokens and the super key have their own limits.
+    let (_, other_token) = common::paying_user(&app, &store, 0).await;
+    let (status, _) = send(&app, request("GET", "/", Some(&other_token))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = send(&app, request("GET", "/", Some(SUPER_KEY))).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn limits_key_guesses_per_ip() {
+    let mut config = common::config();
+    config.rate_limit.ip = RateLimit {
+        burst: 2,
+        per_minute: 1,
+    };
+    let app = common::app_with(config, Arc::new(MemoryStore::new())).await;
+    let guess = |forwarded_for: &str| {
+        request("GET", "/", Some("guessed-key"))
+            .peer_addr("203.0.113.7:4000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", forwarded_for))
+    };
+
+    for forwarded_for in ["1.1.1.1", "2.2.2.2"] {
+        let (status, _) = send(&app, guess(forwarded_for)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    // The forwarded address is ignored unless the peer is a trusted proxy.
+    let (status, _) = send(&app, guess("3.3.3.3")).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+    // Internal keys are not limited.
+    let req = request("GET", "/", Some(SUPER_KEY)).peer_addr("203.0.113.7:4000".parse().unwrap());
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn quotas_cap_usage_despite_credits() {
+    let store = Arc::new(MemoryStore::new());
+    let app = common::app(store.clone()).await;
+    let (user_id, token) = common::paying_user(&app, &store, 10).await;
+    let token_id = store.api_tokens(user_id)[0]._id;
+
+    let req = request("PATCH", "/api/v1/quotas", Some(SUPER_KEY)).set_json(json!({
+        "data": { "id": user_id.to_hex(), "requests_per_day": 2 }
+    }));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let req = request("PATCH", "/api/v1/quotas", Some(SUPER_KEY)).set_json(json!({
+        "data": { "id": user_id.to_hex(), "token_id": token_id.to_hex(), "credits_per_month": 1 }
+    }));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let req = request("POST", "/api/v1/translate", Some(&token)).set_json(translation(&["Hello"]));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let req = request("POST", "/api/v1/translate", Some(&token)).set_json(translation(&["Hello"]));
+    let (status, body) = send_json(&app, req).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(body["error"]["code"], "quota_exceeded");
+    assert_eq!(body["error"]["details"], json!({ "scope": "token", "quota": "credits_per_month" }));
+
+    let (status, usage) = send_json(&app, request("GET", "/api/v1/credits", Some(&token))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(usage["current_amount"], 9);
+    assert_eq!(
+        usage["quotas"]["user"]["requests_per_day"],
+        json!({ "limit": 2, "used": 1 })
+    );
+    assert_eq!(
+        usage["quotas"]["token"]["credits_per_month"],
+        json!({ "limit": 1, "used": 1 })
+    );
+
+    // Users can not lift their own quotas.
+    let req = request("PATCH", "/api/v1/quotas", Some(&token))
+        .set_json(json!({ "data": { "id": user_id.to_hex() } }));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn internal_keys_are_limited_to_their_scopes_and_addresses() {
+    let billing_key = "billing-0123456789abcdef0123456789";
+    let mut config = common::config();
+    config.internal_keys = vec![InternalKey {
+        name: String::from("billing"),
+        key: String::from(billing_key),
+        scopes: vec![KeyScope::Billing],
+        allowed_ips: vec![String::from("10.0.0.0/8")],
+    }];
+    let app = common::app_with(config, Arc::new(MemoryStore::new())).await;
+    let payment = json!({ "data": { "id": ObjectId::new().to_hex(), "amount": 5 } });
+
+    let req = request("POST", "/api/v1/payment", Some(billing_key))
+        .peer_addr("10.1.2.3:4000".parse().unwrap())
+        .set_json(&payment);
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let req = request("POST", "/api/v1/translate", Some(billing_key))
+        .peer_addr("10.1.2.3:4000".parse().unwrap())
+        .set_json(translation(&["Hello"]));
+    let (status, body) = send_json(&app, req).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert!(body["error"]["message"].as_str().unwrap().contains("scope"));
+
+    let req = request("POST", "/api/v1/payment", Some(billing_key))
+        .peer_addr("192.168.0.1:4000".parse().unwrap())
+        .set_json(&payment);
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // The super key keeps working everywhere.
+    let req = request("POST", "/api/v1/payment", Some(SUPER_KEY))
+        .peer_addr("192.168.0.1:4000".parse().unwrap())
+        .set_json(&payment);
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn audits_privileged_actions() {
+    let store = Arc::new(MemoryStore::new());
+    let app = common::app(store.clone()).await;
+    let (user_id, token) = common::paying_user(&app, &store, 5).await;
+
+    let uri = format!("/api/v1/audit-logs?action=credits_changed&target_id={}", user_id.to_hex());
+    let (status, entries) = send_json(&app, request("GET", &uri, Some(SUPER_KEY))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let entries = entries.as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["actor"]["key_name"], "super_key");
+    assert_eq!(entries[0]["before"], json!({ "current_amount": 0 }));
+    assert_eq!(entries[0]["after"], json!({ "current_amount": 5 }));
+    assert!(entries[0]["request_id"].is_string());
+
+    let (_, entries) =
+        send_json(&app, request("GET", "/api/v1/audit-logs?action=token_created", Some(SUPER_KEY)))
+            .await;
+    assert_eq!(entries.as_array().unwrap().len(), 1);
+
+    // Without trusted proxies the address is the peer, whatever the client forwards.
+    let req = request("POST", "/api/v1/payment", Some(SUPER_KEY))
+        .peer_addr("203.0.113.7:4000".parse().unwrap())
+        .insert_header(("x-forwarded-for", "198.51.100.1"))
+        .set_json(json!({ "data": { "id": user_id.to_hex(), "amount": 1 } }));
+    send(&app, req).await;
+
+    let (_, entries) = send_json(&app, request("GET", &uri, Some(SUPER_KEY))).await;
+    assert_eq!(entries[0]["ip"], "203.0.113.7");
+
+    let (status, _) = send(&app, request("GET", "/api/v1/audit-logs", Some(&token))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, body) =
+        send_json(&app, request("GET", "/api/v1/audit-logs?from=yesterday", Some(SUPER_KEY))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["details"]["field"], "from");
+}
+
+#[actix_web::test]
+async fn explains_why_a_key_was_rejected() {
+    let store = Arc::new(MemoryStore::new());
+    let app = common::app(store.clone()).await;
+    let (_, token) = common::paying_user(&app, &store, 5).await;
+
+    let user_id = common::user(&store, vec![UserRole::USER]);
+    let stored = |token: &str, tomestoned, expires_at| Tokens {
+        _id: ObjectId::new(),
+        token: token.to_string(),
+        created_at: bson::DateTime::now(),
+        updated_at: None,
+        tomestoned,
+        userId: user_id,
+        expires_at,
+        quota: Quota::default(),
+        quota_usage: QuotaUsage::default(),
+    };
+    let revoked = format!("revoked-{}", ObjectId::new());
+    let expired = format!("expired-{}", ObjectId::new());
+    store.insert_api_token(stored(&revoked, true, None));
+    store.insert_api_token(stored(&expired, false, Some(bson::DateTime::from_millis(0))));
+
+    let bearer = format!("Bearer {}", token);
+    let (status, _) = send(&app, request("GET", "/", Some(&bearer))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let req = request("GET", "/", None).insert_header((API_KEY_HEADER, token.as_str()));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+
+    for (key, reason) in [
+        (None, "missing"),
+        (Some("Basic abc"), "malformed"),
+        (Some("Bearer unknown-key"), "unknown"),
+        (Some(revoked.as_str()), "revoked"),
+        (Some(expired.as_str()), "expired"),
+    ] {
+        let res = match test::try_call_service(&app, request("GET", "/", key).to_request()).await {
+            Ok(res) => panic!("{} key was accepted with {}", reason, res.status()),
+            Err(e) => e.error_response(),
+        };
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{}", reason);
+
+        let challenge = res.headers().get("www-authenticate").unwrap().to_str().unwrap();
+        assert!(challenge.starts_with("Bearer realm=\"neura-labs-api\""), "{}", challenge);
+
+        let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["details"]["reason"], reason);
+    }
+}
+
+#[actix_web::test]
+async fn dashboard_sessions_resolve_to_their_user() {
+    let store = Arc::new(MemoryStore::new());
+    let app = common::app(store.clone()).await;
+    let (user_id, _) = common::paying_user(&app, &store, 5).await;
+
+    let session = |token: &str, expires: bson::DateTime| Session {
+        _id: ObjectId::new(),
+        sessionToken: token.to_string(),
+        userId: user_id,
+        expires,
+    };
+    let hour = 60 * 60 * 1000;
+    let now = bson::DateTime::now().timestamp_millis();
+    store.insert_session(session("active-session", bson::DateTime::from_millis(now + hour)));
+    store.insert_session(session("expired-session", bson::DateTime::from_millis(now - hour)));
+
+    let cookie = actix_web::cookie::Cookie::new("next-auth.session-token", "active-session");
+    let req = request("GET", "/api/v1/credits", None).cookie(cookie);
+    let (status, body) = send_json(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["current_amount"], 5);
+
+    let req =
+        request("GET", "/api/v1/credits", None).insert_header((SESSION_HEADER, "active-session"));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+
+    for (token, message) in [
+        ("expired-session", "The session has expired!"),
+        ("unknown-session", "Unknown session!"),
+    ] {
+        let req = request("GET", "/api/v1/credits", None).insert_header((SESSION_HEADER, token));
+        let (status, body) = send_json(&app, req).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"]["message"], message);
+    }
+
+    // Only internal keys can hand out credits and tokens, to their own account or any other.
+    let other = ObjectId::new().to_hex();
+    for (uri, data) in [
+        ("/api/v1/payment", json!({ "id": other, "amount": 100 })),
+        ("/api/v1/token", json!(other)),
+    ] {
+        let req = request("POST", uri, None)
+            .insert_header((SESSION_HEADER, "active-session"))
+            .set_json(json!({ "data": data }));
+        let (status, _) = send(&app, req).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    let token = store.api_tokens(user_id)[0].token.clone();
+    let req = request("POST", "/api/v1/payment", Some(&token))
+        .set_json(json!({ "data": { "id": user_id.to_hex(), "amount": 100 } }));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(store.get_credits(user_id).await.unwrap().unwrap().current_amount, Some(5));
+}
+
+#[actix_web::test]
+async fn exchanges_api_tokens_for_access_tokens() {
+    let store = Arc::new(MemoryStore::new());
+    let key = |kid: &str| JwtKey {
+        kid: kid.to_string(),
+        secret: format!("{}-0123456789abcdef0123456789abcdef", kid),
+    };
+    let mut config = common::config();
+    config.jwt.keys = vec![key("old")];
+    let app = common::app_with(config.clone(), store.clone()).await;
+    let (_, token) = common::paying_user(&app, &store, 5).await;
+
+    let req = request("POST", "/api/v1/auth/token", Some(&token))
+        .set_json(json!({ "data": { "scopes": ["translate"] } }));
+    let (status, body) = send_json(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["token_type"], "Bearer");
+    assert_eq!(body["expires_in"], 900);
+    let access_token = format!("Bearer {}", body["access_token"].as_str().unwrap());
+
+    let req = request("POST", "/api/v1/translate", Some(&access_token))
+        .set_json(translation(&["Hello"]));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = send(&app, request("GET", "/api/v1/credits", Some(&access_token))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Rotating adds the new signing key in front of the old one.
+    let mut all = config.clone();
+    all.jwt.keys = vec![key("new"), key("old")];
+    let app = common::app_with(all, store.clone()).await;
+    let req = request("POST", "/api/v1/auth/token", Some(&token)).set_json(json!({ "data": {} }));
+    let (_, body) = send_json(&app, req).await;
+    let renewed = format!("Bearer {}", body["access_token"].as_str().unwrap());
+    assert_eq!(body["scopes"], json!(["all"]));
+
+    // Access tokens can not be exchanged for new ones.
+    let req = request("POST", "/api/v1/auth/token", Some(&renewed)).set_json(json!({ "data": {} }));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Tokens signed with the old key stay valid while it is configured.
+    let req = request("POST", "/api/v1/translate", Some(&access_token))
+        .set_json(translation(&["Hello"]));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+
+    config.jwt.keys = vec![key("new")];
+    let app = common::app_with(config, store).await;
+    let (status, body) = send_json(&app, request("GET", "/", Some(&access_token))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"]["details"]["reason"], "unknown");
+}
+
+#[actix_web::test]
+async fn users_manage_their_profile() {
+    let store = Arc::new(MemoryStore::new());
+    let app = common::app(store.clone()).await;
+    let (user_id, token) = common::paying_user(&app, &store, 0).await;
+    let (_, other_token) = common::paying_user(&app, &store, 0).await;
+
+    let (status, me) = send_json(&app, request("GET", "/api/v1/me", Some(&token))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(me["telemetry"], true);
+    assert!(me.get("quota_usage").is_none());
+
+    let update = json!({ "data": { "username": "translator", "bio": "  ", "telemetry": false } });
+    let req = request("PATCH", "/api/v1/me", Some(&token)).set_json(update);
+    let (status, me) = send_json(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(me["username"], "translator");
+    assert_eq!(me["bio"], serde_json::Value::Null);
+    assert!(!store.get_user(user_id).await.unwrap().unwrap().telemetry);
+
+    let req = request("PATCH", "/api/v1/me", Some(&other_token))
+        .set_json(json!({ "data": { "username": "translator" } }));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    for (field, value) in [("username", "a b"), ("image", "ftp://example.com/a.png")] {
+        let req = request("PATCH", "/api/v1/me", Some(&token))
+            .set_json(json!({ "data": { field: value } }));
+        let (status, body) = send_json(&app, req).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["details"]["field"], field);
+    }
+
+    let uri = format!("/api/v1/users/{}", user_id.to_hex());
+    let (status, _) = send(&app, request("GET", &uri, Some(&other_token))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, user) = send_json(&app, request("GET", &uri, Some(SUPER_KEY))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(user["username"], "translator");
+
+    let deleted = common::user(&store, vec![UserRole::USER]);
+    let mut user = store.get_user(deleted).await.unwrap().unwrap();
+    user.tomestoned = true;
+    store.insert_user(user);
+
+    let uri = format!("/api/v1/users/{}", deleted.to_hex());
+    let (status, _) = send(&app, request("GET", &uri, Some(SUPER_KEY))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn deleting_an_account_revokes_access_and_is_purged_later() {
+    let store = Arc::new(MemoryStore::new());
+    let mut config = common::config();
+    config.jwt.keys = vec![JwtKey {
+        kid: String::from("main"),
+        secret: String::from("main-0123456789abcdef0123456789abcdef"),
+    }];
+    let app = common::app_with(config, store.clone()).await;
+    let (user_id, token) = common::paying_user(&app, &store, 5).await;
+
+    // Puts the token in the auth cache and the usage in the statistics.
+    let req = request("POST", "/api/v1/translate", Some(&token)).set_json(translation(&["Hello"]));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let req = request("POST", "/api/v1/auth/token", Some(&token)).set_json(json!({ "data": {} }));
+    let (_, body) = send_json(&app, req).await;
+    let access_token = format!("Bearer {}", body["access_token"].as_str().unwrap());
+    let (status, _) = send(&app, request("GET", "/api/v1/me", Some(&access_token))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, deletion) = send_json(&app, request("DELETE", "/api/v1/me", Some(&token))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        deletion,
+        json!({ "tokens_revoked": 1, "payments_cancelled": 1, "statistics_anonymised": 1 })
+    );
+
+    for key in [&token, &access_token] {
+        let (status, body) = send_json(&app, request("GET", "/api/v1/me", Some(key))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"]["details"]["reason"], "revoked");
+    }
+
+    // A repeated deletion finds nothing left to change.
+    let uri = format!("/api/v1/users/{}", user_id.to_hex());
+    let (status, deletion) = send_json(&app, request("DELETE", &uri, Some(SUPER_KEY))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        deletion,
+        json!({ "tokens_revoked": 0, "payments_cancelled": 0, "statistics_anonymised": 0 })
+    );
+
+    let (_, entries) = send_json(
+        &app,
+        request("GET", "/api/v1/audit-logs?action=account_deleted", Some(SUPER_KEY)),
+    )
+    .await;
+    assert_eq!(entries[0]["actor"]["key_name"], "super_key");
+    assert_eq!(entries[1]["actor"]["userId"], json!({ "$oid": user_id.to_hex() }));
+
+    let now = bson::DateTime::now();
+    assert_eq!(accounts::purge_deleted_accounts(store.as_ref(), 30, now).await.unwrap(), 0);
+    assert!(store.get_user(user_id).await.unwrap().is_some());
+
+    let later = bson::DateTime::from_millis(now.timestamp_millis() + 31 * 24 * 60 * 60 * 1000);
+    assert_eq!(accounts::purge_deleted_accounts(store.as_ref(), 30, later).await.unwrap(), 1);
+    assert!(store.get_user(user_id).await.unwrap().is_none());
+    assert!(store.api_tokens(user_id).is_empty());
+    assert!(store.get_credits(user_id).await.unwrap().is_none());
+}
+
+#[actix_web::test]
+async fn rejects_api_tokens_of_deleted_users() {
+    let store = Arc::new(MemoryStore::new());
+    let app = common::app(store.clone()).await;
+    let (user_id, token) = common::paying_user(&app, &store, 5).await;
+
+    // Puts the token in the auth cache.
+    let (status, _) = send(&app, request("GET", "/api/v1/me", Some(&token))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Deleted on another instance, the token was neither revoked nor dropped from this cache.
+    let mut user = store.get_user(user_id).await.unwrap().unwrap();
+    user.tomestoned = true;
+    store.insert_user(user);
+
+    for _ in 0..2 {
+        let (status, body) = send_json(&app, request("GET", "/api/v1/me", Some(&token))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"]["details"]["reason"], "revoked");
+    }
+}
+
+#[actix_web::test]
+async fn refuses_to_exchange_revoked_tokens() {
+    let store = Arc::new(MemoryStore::new());
+    let mut config = common::config();
+    config.jwt.keys = vec![JwtKey {
+        kid: String::from("main"),
+        secret: String::from("main-0123456789abcdef0123456789abcdef"),
+    }];
+    let app = common::app_with(config, store.clone()).await;
+    let exchange = |token: &str| {
+        request("POST", "/api/v1/auth/token", Some(token)).set_json(json!({ "data": {} }))
+    };
+
+    // Revoked on another instance while this one still has the token cached.
+    let (user_id, token) = common::paying_user(&app, &store, 5).await;
+    let (status, _) = send(&app, exchange(&token)).await;
+    assert_eq!(status, StatusCode::OK);
+    store.revoke_api_token(store.api_tokens(user_id)[0]._id);
+
+    let (status, body) = send_json(&app, exchange(&token)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"]["details"]["reason"], "revoked");
+
+    // Deleted on another instance while this one still takes the user for active.
+    let (user_id, token) = common::paying_user(&app, &store, 5).await;
+    for _ in 0..2 {
+        let (status, _) = send(&app, exchange(&token)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let mut user = store.get_user(user_id).await.unwrap().unwrap();
+    user.tomestoned = true;
+    store.insert_user(user);
+
+    let (status, body) = send_json(&app, exchange(&token)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"]["details"]["reason"], "revoked");
+}
+
+#[actix_web::test]
+async fn global_stats_count_users_without_telemetry() {
+    let store = Arc::new(MemoryStore::new());
+    let app = common::app(store.clone()).await;
+    let (_, tracked) = common::paying_user(&app, &store, 5).await;
+    let (_, private) = common::paying_user(&app, &store, 5).await;
+
+    let req = request("PATCH", "/api/v1/me", Some(&private))
+        .set_json(json!({ "data": { "telemetry": false } }));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+
+    for token in [&tracked, &private] {
+        let req =
+            request("POST", "/api/v1/translate", Some(token)).set_json(translation(&["Hello"]));
+        let (status, _) = send(&app, req).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let (status, stats) = send_json(&app, request("GET", "/api/v1/stats", Some(&tracked))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(stats["api_calls"], 2);
+}
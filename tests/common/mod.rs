@@ -0,0 +1,164 @@
+// Shared helpers for the HTTP integration tests. Every test runs the full app from `build_app`
+// against a fresh in-memory store.
+
+use std::sync::Arc;
+
+use actix_web::{
+    body::MessageBody,
+    dev::{Service, ServiceResponse},
+    http::StatusCode,
+    test, Error,
+};
+use mongodb::bson::oid::ObjectId;
+use serde_json::{json, Value};
+
+use neura_labs_api::{
+    build_app,
+    config::{Config, StorageBackend},
+    inference::{InferenceEngine, MockEngine},
+    middleware::auth::AUTH_HEADER,
+    models::{Quota, QuotaUsage, User, UserRole},
+    store::MemoryStore,
+    AppState,
+};
+
+pub const SUPER_KEY: &str = "integration-super-key";
+
+pub fn config() -> Config {
+    Config {
+        super_key: String::from(SUPER_KEY),
+        storage: StorageBackend::Memory,
+        ..Config::default()
+    }
+}
+
+/// Starts the app with the given store
+pub async fn app(
+    store: Arc<MemoryStore>,
+) -> impl Service<actix_http::Request, Response = ServiceResponse<impl MessageBody>, Error = Error> {
+    app_with(config(), store).await
+}
+
+/// Starts the app with the given config and store
+pub async fn app_with(
+    config: Config,
+    store: Arc<MemoryStore>,
+) -> impl Service<actix_http::Request, Response = ServiceResponse<impl MessageBody>, Error = Error> {
+    app_with_engine(config, store, Arc::new(MockEngine)).await
+}
+
+/// Starts the app with the given config, store and inference engine
+pub async fn app_with_engine(
+    config: Config,
+    store: Arc<MemoryStore>,
+    engine: Arc<dyn InferenceEngine>,
+) -> impl Service<actix_http::Request, Response = ServiceResponse<impl MessageBody>, Error = Error> {
+    test::init_service(build_app(AppState::new(config, store, engine))).await
+}
+
+/// Adds a user with the given roles to the store
+pub fn user(store: &MemoryStore, roles: Vec<UserRole>) -> ObjectId {
+    let id = ObjectId::new();
+
+    store.insert_user(User {
+        _id: id,
+        name: Some(String::from("Test User")),
+        username: None,
+        bio: None,
+        email: format!("{}@example.com", id),
+        email_verified: None,
+        image: String::new(),
+        roles,
+        telemetry: true,
+        tomestoned: false,
+        deleted_at: None,
+        quota: Quota::default(),
+        quota_usage: QuotaUsage::default(),
+    });
+
+    id
+}
+
+/// Gives the user the credits and returns one of their api tokens
+pub async fn paying_user<S, B>(app: &S, store: &MemoryStore, credits: i32) -> (ObjectId, String)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody + 'static,
+{
+    let user_id = user(store, vec![UserRole::USER]);
+
+    let req = request("POST", "/api/v1/token", Some(SUPER_KEY))
+        .set_json(json!({ "data": user_id.to_hex() }));
+    send(app, req).await;
+
+    let req = request("POST", "/api/v1/payment", Some(SUPER_KEY))
+        .set_json(json!({ "data": { "id": user_id.to_hex(), "amount": credits } }));
+    send(app, req).await;
+
+    (user_id, store.api_tokens(user_id)[0].token.clone())
+}
+
+/// Builds a request authenticated with the given key
+pub fn request(method: &str, uri: &str, key: Option<&str>) -> test::TestRequest {
+    let req = match method {
+        "POST" => test::TestRequest::post(),
+        "PATCH" => test::TestRequest::patch(),
+        "DELETE" => test::TestRequest::delete(),
+        _ => test::TestRequest::get(),
+    }
+    .uri(uri);
+
+    match key {
+        Some(key) => req.insert_header((AUTH_HEADER, key)),
+        None => req,
+    }
+}
+
+/// Sends the request and returns the status and the raw body
+pub async fn send<S, B>(app: &S, req: test::TestRequest) -> (StatusCode, String)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody + 'static,
+{
+    // Errors from middleware are rendered by the server, the test service hands them back as is.
+    let res = match test::try_call_service(app, req.to_request()).await {
+        Ok(res) => res.into_parts().1.map_into_boxed_body(),
+        Err(e) => e.error_response(),
+    };
+    let status = res.status();
+    let body = actix_web::body::to_bytes(res.into_body())
+        .await
+        .unwrap_or_default();
+
+    (status, String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Sends the request and parses the body as JSON
+pub async fn send_json<S, B>(app: &S, req: test::TestRequest) -> (StatusCode, Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody + 'static,
+{
+    let (status, body) = send(app, req).await;
+
+    (status, serde_json::from_str(&body).unwrap_or(Value::Null))
+}
+
+/// Polls the job until it finished
+pub async fn finished_job<S, B>(app: &S, uri: &str, token: &str) -> Value
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody + 'static,
+{
+    for _ in 0..100 {
+        let (status, job) = send_json(app, request("GET", uri, Some(token))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        if job["status"] == "completed" || job["status"] == "failed" {
+            return job;
+        }
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    panic!("the job did not finish");
+}